@@ -0,0 +1,73 @@
+#![cfg_attr(actix_nightly, feature(test))]
+
+extern crate actix_web;
+extern crate bytes;
+#[cfg(actix_nightly)]
+extern crate test;
+
+#[cfg(actix_nightly)]
+mod control {
+    use actix_web::ws::{Frame, OpCode};
+    use test::Bencher;
+
+    #[bench]
+    fn bench_ping_message(b: &mut Bencher) {
+        b.iter(|| Frame::message("heartbeat", OpCode::Ping, true, false));
+    }
+
+    #[bench]
+    fn bench_ping_encode_control(b: &mut Bencher) {
+        b.iter(|| Frame::encode_control(OpCode::Ping, b"heartbeat", false));
+    }
+
+    #[bench]
+    fn bench_masked_ping_message(b: &mut Bencher) {
+        b.iter(|| Frame::message("heartbeat", OpCode::Ping, true, true));
+    }
+
+    #[bench]
+    fn bench_masked_ping_encode_control(b: &mut Bencher) {
+        b.iter(|| Frame::encode_control(OpCode::Ping, b"heartbeat", true));
+    }
+}
+
+#[cfg(actix_nightly)]
+mod decode {
+    use bytes::BytesMut;
+    use actix_web::ws::{Frame, FrameDecoder, OpCode, WsConfig};
+    use test::Bencher;
+
+    fn tiny_frames() -> BytesMut {
+        let mut buf = BytesMut::new();
+        for _ in 0..100 {
+            let frame = Frame::message_with_mask("tick", OpCode::Text, true, [1, 2, 3, 4]);
+            buf.extend(frame.as_ref());
+        }
+        buf
+    }
+
+    #[bench]
+    fn bench_decode_tiny_frames(b: &mut Bencher) {
+        let frames = tiny_frames();
+        let mut decoder = FrameDecoder::new(WsConfig::server());
+        b.iter(|| {
+            let mut buf = frames.clone();
+            while let Some(frame) = decoder.decode(&mut buf).unwrap() {
+                test::black_box(frame);
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_decode_into_tiny_frames(b: &mut Bencher) {
+        let frames = tiny_frames();
+        let mut decoder = FrameDecoder::new(WsConfig::server());
+        let mut scratch = Vec::new();
+        b.iter(|| {
+            let mut buf = frames.clone();
+            while let Some(frame) = decoder.decode_into(&mut buf, &mut scratch).unwrap() {
+                test::black_box(frame.payload);
+            }
+        });
+    }
+}
@@ -1,4 +1,5 @@
-use std::{fmt, mem};
+use std::{fmt, mem, str};
+use std::ops::Range;
 use std::rc::Rc;
 use std::sync::Arc;
 use bytes::{Bytes, BytesMut};
@@ -25,7 +26,7 @@ pub enum Body {
 
 /// Represents various types of binary body.
 /// `Content-Length` header is set to length of the body.
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub enum Binary {
     /// Bytes body
     Bytes(Bytes),
@@ -36,6 +37,8 @@ pub enum Binary {
     /// Shared string body
     #[doc(hidden)]
     ArcSharedString(Arc<String>),
+    /// Shared buffer, cloning does not copy the data
+    ArcSlice(Arc<[u8]>),
 }
 
 impl Body {
@@ -115,6 +118,7 @@ impl Binary {
             Binary::Slice(slice) => slice.len(),
             Binary::SharedString(ref s) => s.len(),
             Binary::ArcSharedString(ref s) => s.len(),
+            Binary::ArcSlice(ref s) => s.len(),
         }
     }
 
@@ -127,6 +131,33 @@ impl Binary {
     pub fn take(&mut self) -> Bytes {
         mem::replace(self, Binary::Slice(b"")).into()
     }
+
+    /// Returns a slice of self for the provided range.
+    ///
+    /// `Bytes` body is not copied, returned binary shares
+    /// the same memory. Shared strings and buffers are copied.
+    ///
+    /// # Panics
+    ///
+    /// Panics if range is out of bounds.
+    pub fn slice(&self, range: Range<usize>) -> Binary {
+        match *self {
+            Binary::Bytes(ref bytes) => Binary::Bytes(bytes.slice(range.start, range.end)),
+            Binary::Slice(slice) => Binary::Slice(&slice[range]),
+            Binary::SharedString(ref s) => Binary::from_slice(&s.as_bytes()[range]),
+            Binary::ArcSharedString(ref s) => Binary::from_slice(&s.as_bytes()[range]),
+            Binary::ArcSlice(ref s) => Binary::from_slice(&s[range]),
+        }
+    }
+
+    /// View binary as UTF-8 string slice, content is validated but not copied
+    pub fn as_str(&self) -> Result<&str, str::Utf8Error> {
+        match *self {
+            Binary::SharedString(ref s) => Ok(s.as_str()),
+            Binary::ArcSharedString(ref s) => Ok(s.as_str()),
+            _ => str::from_utf8(self.as_ref()),
+        }
+    }
 }
 
 impl Clone for Binary {
@@ -136,10 +167,17 @@ impl Clone for Binary {
             Binary::Slice(slice) => Binary::Bytes(Bytes::from(slice)),
             Binary::SharedString(ref s) => Binary::Bytes(Bytes::from(s.as_str())),
             Binary::ArcSharedString(ref s) => Binary::Bytes(Bytes::from(s.as_str())),
+            Binary::ArcSlice(ref s) => Binary::ArcSlice(Arc::clone(s)),
         }
     }
 }
 
+impl PartialEq for Binary {
+    fn eq(&self, other: &Binary) -> bool {
+        self.as_ref() == other.as_ref()
+    }
+}
+
 impl Into<Bytes> for Binary {
     fn into(self) -> Bytes {
         match self {
@@ -147,6 +185,7 @@ impl Into<Bytes> for Binary {
             Binary::Slice(slice) => Bytes::from(slice),
             Binary::SharedString(s) => Bytes::from(s.as_str()),
             Binary::ArcSharedString(s) => Bytes::from(s.as_str()),
+            Binary::ArcSlice(s) => Bytes::from(s.as_ref()),
         }
     }
 }
@@ -217,6 +256,18 @@ impl<'a> From<&'a Arc<String>> for Binary {
     }
 }
 
+impl From<Arc<[u8]>> for Binary {
+    fn from(body: Arc<[u8]>) -> Binary {
+        Binary::ArcSlice(body)
+    }
+}
+
+impl<'a> From<&'a Arc<[u8]>> for Binary {
+    fn from(body: &'a Arc<[u8]>) -> Binary {
+        Binary::ArcSlice(Arc::clone(body))
+    }
+}
+
 impl AsRef<[u8]> for Binary {
     fn as_ref(&self) -> &[u8] {
         match *self {
@@ -224,6 +275,7 @@ impl AsRef<[u8]> for Binary {
             Binary::Slice(slice) => slice,
             Binary::SharedString(ref s) => s.as_bytes(),
             Binary::ArcSharedString(ref s) => s.as_bytes(),
+            Binary::ArcSlice(ref s) => s.as_ref(),
         }
     }
 }
@@ -295,6 +347,34 @@ mod tests {
         assert_eq!(Binary::from(&b).as_ref(), "test".as_bytes());
     }
 
+    #[test]
+    fn test_arc_slice() {
+        let b: Arc<[u8]> = Arc::new(*b"test");
+        assert_eq!(Binary::from(b.clone()).len(), 4);
+        assert_eq!(Binary::from(b.clone()).as_ref(), "test".as_bytes());
+        assert_eq!(Binary::from(&b).len(), 4);
+        assert_eq!(Binary::from(&b).as_ref(), "test".as_bytes());
+
+        // clone shares the buffer
+        let binary = Binary::from(&b);
+        let cloned = binary.clone();
+        assert_eq!(cloned.as_ref().as_ptr(), b.as_ptr());
+        assert_eq!(Arc::strong_count(&b), 3);
+
+        let bytes: Bytes = cloned.into();
+        assert_eq!(bytes, Bytes::from_static(b"test"));
+        assert_eq!(Binary::from(&b).slice(1..3).as_ref(), &b"es"[..]);
+    }
+
+    #[test]
+    fn test_binary_eq() {
+        let b: Arc<[u8]> = Arc::new(*b"test");
+        assert_eq!(Binary::from(b), Binary::from("test"));
+        assert_eq!(Binary::from(Bytes::from("test")), Binary::from(Rc::new("test".to_owned())));
+        assert_eq!(Binary::from(Arc::new("test".to_owned())), Binary::from(Vec::from("test")));
+        assert!(Binary::from("test") != Binary::from("tes"));
+    }
+
     #[test]
     fn test_string() {
         let b = "test".to_owned();
@@ -311,6 +391,52 @@ mod tests {
         assert_eq!(Binary::from(b).as_ref(), "test".as_bytes());
     }
 
+    #[test]
+    fn test_as_str() {
+        assert_eq!(Binary::from(Bytes::from("text")).as_str(), Ok("text"));
+        assert_eq!(Binary::from("sm\u{f6}rg\u{e5}s").as_str(), Ok("sm\u{f6}rg\u{e5}s"));
+        assert_eq!(Binary::from(Rc::new("text".to_owned())).as_str(), Ok("text"));
+        assert_eq!(Binary::from("").as_str(), Ok(""));
+
+        let b = Binary::from(Bytes::from(&b"text payload"[..]));
+        assert_eq!(b.as_str().unwrap().as_ptr(), b.as_ref().as_ptr());
+
+        assert!(Binary::from(&b"\xff\xfe"[..]).as_str().is_err());
+        let err = Binary::from(Vec::from(&b"ab\xc3"[..])).as_str().unwrap_err();
+        assert_eq!(err.valid_up_to(), 2);
+    }
+
+    #[test]
+    fn test_slice() {
+        let bytes = Bytes::from(&b"test payload, long enough to not be inlined"[..]);
+        let b = Binary::from(bytes.clone()).slice(5..12);
+        assert_eq!(b.as_ref(), &b"payload"[..]);
+        assert_eq!(b.as_ref().as_ptr(), bytes[5..].as_ptr());
+
+        let b = Binary::from(b"test payload".as_ref()).slice(5..12);
+        assert_eq!(b, Binary::Slice(b"payload"));
+
+        let b = Binary::from(Vec::from("test payload")).slice(5..12);
+        assert_eq!(b.as_ref(), &b"payload"[..]);
+
+        let b = Binary::from(Rc::new("test payload".to_owned())).slice(5..12);
+        assert_eq!(b.as_ref(), &b"payload"[..]);
+        let b = Binary::from(Arc::new("test payload".to_owned())).slice(0..4);
+        assert_eq!(b.as_ref(), &b"test"[..]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slice_out_of_range() {
+        let _ = Binary::from(Bytes::from("test")).slice(2..5);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_slice_static_out_of_range() {
+        let _ = Binary::from("test").slice(2..5);
+    }
+
     #[test]
     fn test_binary_into() {
         let bytes = Bytes::from_static(b"test");
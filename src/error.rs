@@ -345,21 +345,30 @@ pub enum WsHandshakeError {
     /// Only get method is allowed
     #[fail(display="Method not allowed")]
     GetMethodRequired,
-    /// Upgrade header if not set to websocket
+    /// Upgrade header is not set
     #[fail(display="Websocket upgrade is expected")]
     NoWebsocketUpgrade,
+    /// Upgrade header is set to other protocol than websocket
+    #[fail(display="Upgrade to other protocol than websocket")]
+    BadWebsocketUpgrade,
     /// Connection header is not set to upgrade
     #[fail(display="Connection upgrade is expected")]
     NoConnectionUpgrade,
-    /// Websocket version header is not set
+    /// Websocket version header is not set, responded same as unsupported version
     #[fail(display="Websocket version header is required")]
     NoVersionHeader,
-    /// Unsupported websocket version
+    /// Unsupported websocket version, only version 13 is supported
     #[fail(display="Unsupported version")]
     UnsupportedVersion,
-    /// Websocket key is not set or wrong
+    /// Websocket key is not set
+    #[fail(display="Websocket key is required")]
+    NoWebsocketKey,
+    /// Websocket key is wrong
     #[fail(display="Unknown websocket key")]
     BadWebsocketKey,
+    /// None of offered websocket protocols is supported
+    #[fail(display="Unsupported websocket protocol")]
+    UnsupportedProtocol,
 }
 
 impl ResponseError for WsHandshakeError {
@@ -375,14 +384,22 @@ impl ResponseError for WsHandshakeError {
             }
             WsHandshakeError::NoWebsocketUpgrade =>
                 HTTPBadRequest.with_reason("No WebSocket UPGRADE header found"),
+            WsHandshakeError::BadWebsocketUpgrade =>
+                HTTPBadRequest.with_reason("Upgrade is supported only to websocket"),
             WsHandshakeError::NoConnectionUpgrade =>
                 HTTPBadRequest.with_reason("No CONNECTION upgrade"),
-            WsHandshakeError::NoVersionHeader =>
-                HTTPBadRequest.with_reason("Websocket version header is required"),
-            WsHandshakeError::UnsupportedVersion =>
-                HTTPBadRequest.with_reason("Unsupported version"),
+            WsHandshakeError::NoVersionHeader | WsHandshakeError::UnsupportedVersion => {
+                HttpResponse::build(StatusCode::UPGRADE_REQUIRED)
+                    .header("Sec-WebSocket-Version", "13")
+                    .finish()
+                    .unwrap()
+            }
+            WsHandshakeError::NoWebsocketKey =>
+                HTTPBadRequest.with_reason("Websocket key is required"),
             WsHandshakeError::BadWebsocketKey =>
                 HTTPBadRequest.with_reason("Handshake error"),
+            WsHandshakeError::UnsupportedProtocol =>
+                HTTPBadRequest.with_reason("Unsupported websocket protocol"),
         }
     }
 }
@@ -743,13 +760,20 @@ mod tests {
     fn test_wserror_http_response() {
         let resp: HttpResponse = WsHandshakeError::GetMethodRequired.error_response();
         assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers().get(header::ALLOW).unwrap(), "GET");
         let resp: HttpResponse = WsHandshakeError::NoWebsocketUpgrade.error_response();
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let resp: HttpResponse = WsHandshakeError::BadWebsocketUpgrade.error_response();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
         let resp: HttpResponse = WsHandshakeError::NoConnectionUpgrade.error_response();
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
         let resp: HttpResponse = WsHandshakeError::NoVersionHeader.error_response();
-        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(resp.headers().get("Sec-WebSocket-Version").unwrap(), "13");
         let resp: HttpResponse = WsHandshakeError::UnsupportedVersion.error_response();
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(resp.headers().get("Sec-WebSocket-Version").unwrap(), "13");
+        let resp: HttpResponse = WsHandshakeError::NoWebsocketKey.error_response();
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
         let resp: HttpResponse = WsHandshakeError::BadWebsocketKey.error_response();
         assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
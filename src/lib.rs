@@ -43,6 +43,7 @@
 #![cfg_attr(actix_nightly, feature(
     specialization, // for impl ErrorResponse for std::error::Error
 ))]
+#![cfg_attr(all(test, actix_nightly), feature(test))]
 #![cfg_attr(feature = "cargo-clippy", allow(
     decimal_literal_representation,))]
 
@@ -86,6 +87,9 @@ extern crate trust_dns_resolver;
 
 #[cfg(test)]
 #[macro_use] extern crate serde_derive;
+// `test` is taken by testing helpers module
+#[cfg(all(test, actix_nightly))]
+extern crate test as testing;
 
 #[cfg(feature="tls")]
 extern crate native_tls;
@@ -12,6 +12,7 @@ use bytes::{Bytes, BytesMut};
 use futures::{Future, Poll, Async};
 use tokio_core::reactor::Timeout;
 
+use body::Binary;
 use pipeline::Pipeline;
 use httpcodes::HTTPNotFound;
 use httprequest::HttpRequest;
@@ -27,11 +28,15 @@ use super::{HttpHandler, HttpHandlerTask, IoStream};
 const MAX_BUFFER_SIZE: usize = 131_072;
 const MAX_HEADERS: usize = 96;
 const MAX_PIPELINED_MESSAGES: usize = 16;
+const REQUEST_TIMEOUT: &[u8] =
+    b"HTTP/1.1 408 Request Timeout\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
 
 bitflags! {
     struct Flags: u8 {
         const ERROR = 0b0000_0010;
         const KEEPALIVE = 0b0000_0100;
+        const READ_EOF = 0b0000_1000;
+        const TIMEOUT = 0b0001_0000;
     }
 }
 
@@ -52,6 +57,7 @@ pub(crate) struct Http1<T: IoStream, H: 'static> {
     read_buf: BytesMut,
     tasks: VecDeque<Entry>,
     keepalive_timer: Option<Timeout>,
+    client_timer: Option<Timeout>,
 }
 
 struct Entry {
@@ -66,6 +72,15 @@ impl<T, H> Http1<T, H>
                -> Self
     {
         let bytes = h.get_shared_bytes();
+        let client_timer = if h.client_timeout() > 0 {
+            let mut to = Timeout::new(
+                Duration::from_millis(h.client_timeout()), Arbiter::handle()).unwrap();
+            // register timeout
+            let _ = to.poll();
+            Some(to)
+        } else {
+            None
+        };
         Http1{ flags: Flags::KEEPALIVE,
                settings: h,
                addr: addr,
@@ -73,7 +88,8 @@ impl<T, H> Http1<T, H>
                reader: Reader::new(),
                read_buf: buf,
                tasks: VecDeque::new(),
-               keepalive_timer: None }
+               keepalive_timer: None,
+               client_timer: client_timer }
     }
 
     pub fn settings(&self) -> &WorkerSettings<H> {
@@ -109,6 +125,34 @@ impl<T, H> Http1<T, H>
             }
         }
 
+        // slow request timer, first request headers are not received in time
+        let expired = if let Some(ref mut timer) = self.client_timer {
+            match timer.poll() {
+                Ok(Async::Ready(_)) => true,
+                Ok(Async::NotReady) => false,
+                Err(_) => unreachable!(),
+            }
+        } else {
+            false
+        };
+        if expired {
+            trace!("Slow request timeout, close connection");
+            self.client_timer.take();
+            self.flags.insert(Flags::TIMEOUT);
+            let _ = self.stream.write(Binary::from(REQUEST_TIMEOUT));
+        }
+        // connection is closed once 408 response is flushed
+        if self.flags.contains(Flags::TIMEOUT) {
+            return match self.stream.poll_flush() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                Ok(Async::Ready(_)) => Ok(Async::Ready(())),
+                Err(err) => {
+                    debug!("Error sending request timeout response: {}", err);
+                    Ok(Async::Ready(()))
+                }
+            }
+        }
+
         self.poll_io()
     }
 
@@ -116,7 +160,8 @@ impl<T, H> Http1<T, H>
     pub fn poll_io(&mut self) -> Poll<(), ()> {
         // read incoming data
         let need_read =
-            if !self.flags.contains(Flags::ERROR) && self.tasks.len() < MAX_PIPELINED_MESSAGES
+            if !self.flags.intersects(Flags::ERROR | Flags::READ_EOF) &&
+            self.tasks.len() < MAX_PIPELINED_MESSAGES
         {
             'outer: loop {
                 match self.reader.parse(self.stream.get_mut(),
@@ -125,8 +170,9 @@ impl<T, H> Http1<T, H>
                         // set remote addr
                         req.set_peer_addr(self.addr);
 
-                        // stop keepalive timer
+                        // stop keepalive and slow request timers
                         self.keepalive_timer.take();
+                        self.client_timer.take();
 
                         // start request processing
                         for h in self.settings.handlers().iter_mut() {
@@ -146,6 +192,12 @@ impl<T, H> Http1<T, H>
                         continue
                     },
                     Ok(Async::NotReady) => (),
+                    Err(ReaderError::HalfClosed) => {
+                        // peer is done sending, in-flight tasks still can write
+                        // their responses, i.e. websocket Close reply
+                        trace!("Peer closed write side of upgraded connection");
+                        self.flags.insert(Flags::READ_EOF);
+                    },
                     Err(ReaderError::Disconnect) => {
                         self.flags.insert(Flags::ERROR);
                         self.stream.disconnected();
@@ -270,7 +322,7 @@ impl<T, H> Http1<T, H>
             // start keep-alive timer, this also is slow request timeout
             if self.tasks.is_empty() {
                 // check stream state
-                if self.flags.contains(Flags::ERROR) {
+                if self.flags.intersects(Flags::ERROR | Flags::READ_EOF) {
                     return Ok(Async::Ready(()))
                 }
 
@@ -326,6 +378,7 @@ struct PayloadInfo {
 #[derive(Debug)]
 enum ReaderError {
     Disconnect,
+    HalfClosed,
     Payload,
     Error(ParseError),
 }
@@ -359,18 +412,35 @@ impl Reader {
         }
     }
 
+    /// Peer closed its write side. Payload of upgraded connection
+    /// is read until then, so it is complete and response still can be
+    /// written. Otherwise connection is dropped.
+    fn read_eof(&mut self) -> ReaderError {
+        match self.payload.take() {
+            Some(mut payload) => if payload.decoder.is_eof() {
+                payload.tx.feed_eof();
+                ReaderError::HalfClosed
+            } else {
+                self.payload = Some(payload);
+                ReaderError::Disconnect
+            },
+            None => ReaderError::Disconnect,
+        }
+    }
+
     pub fn parse<T, H>(&mut self, io: &mut T,
                        buf: &mut BytesMut,
                        settings: &WorkerSettings<H>) -> Poll<HttpRequest, ReaderError>
         where T: IoStream
     {
         // read payload
-        let done = {
+        let (done, eof) = {
             if let Some(ref mut payload) = self.payload {
                 if payload.tx.capacity() == 0 {
                     return Ok(Async::NotReady)
                 }
-                match utils::read_from_io(io, buf) {
+                let eof = match utils::read_from_io(io, buf) {
+                    Ok(Async::Ready(0)) if payload.decoder.is_eof() => true,
                     Ok(Async::Ready(0)) => {
                         payload.tx.set_error(PayloadError::Incomplete);
 
@@ -383,9 +453,9 @@ impl Reader {
                         // http channel should not deal with payload errors
                         return Err(ReaderError::Payload)
                     }
-                    _ => (),
-                }
-                loop {
+                    _ => false,
+                };
+                let done = loop {
                     match payload.decoder.decode(buf) {
                         Ok(Async::Ready(Some(bytes))) => {
                             payload.tx.feed_data(bytes)
@@ -401,17 +471,21 @@ impl Reader {
                             return Err(ReaderError::Payload)
                         }
                     }
-                }
+                };
+                (done, eof)
             } else {
-                false
+                (false, false)
             }
         };
         if done { self.payload = None }
+        if eof {
+            return Err(self.read_eof())
+        }
 
         // if buf is empty parse_message will always return NotReady, let's avoid that
         let read = if buf.is_empty() {
             match utils::read_from_io(io, buf) {
-                Ok(Async::Ready(0)) => return Err(ReaderError::Disconnect),
+                Ok(Async::Ready(0)) => return Err(self.read_eof()),
                 Ok(Async::Ready(_)) => (),
                 Ok(Async::NotReady) => return Ok(Async::NotReady),
                 Err(err) => return Err(ReaderError::Error(err.into()))
@@ -442,7 +516,7 @@ impl Reader {
                         match utils::read_from_io(io, buf) {
                             Ok(Async::Ready(0)) => {
                                 debug!("Ignored premature client disconnection");
-                                return Err(ReaderError::Disconnect);
+                                return Err(self.read_eof());
                             },
                             Ok(Async::Ready(_)) => (),
                             Ok(Async::NotReady) => return Ok(Async::NotReady),
@@ -584,6 +658,14 @@ impl Decoder {
     pub fn eof() -> Decoder {
         Decoder { kind: Kind::Eof(false) }
     }
+
+    /// Payload is read until connection is closed
+    pub fn is_eof(&self) -> bool {
+        match self.kind {
+            Kind::Eof(_) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -827,6 +909,7 @@ mod tests {
     struct Buffer {
         buf: Bytes,
         err: Option<io::Error>,
+        eof: bool,
     }
 
     impl Buffer {
@@ -834,6 +917,7 @@ mod tests {
             Buffer {
                 buf: Bytes::from(data),
                 err: None,
+                eof: false,
             }
         }
         fn feed_data(&mut self, data: &'static str) {
@@ -841,6 +925,9 @@ mod tests {
             b.extend(data.as_bytes());
             self.buf = b.take().freeze();
         }
+        fn feed_eof(&mut self) {
+            self.eof = true;
+        }
     }
 
     impl AsyncRead for Buffer {}
@@ -849,6 +936,8 @@ mod tests {
             if self.buf.is_empty() {
                 if self.err.is_some() {
                     Err(self.err.take().unwrap())
+                } else if self.eof {
+                    Ok(0)
                 } else {
                     Err(io::Error::new(io::ErrorKind::WouldBlock, ""))
                 }
@@ -893,7 +982,7 @@ mod tests {
 
     macro_rules! parse_ready {
         ($e:expr) => ({
-            let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+            let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
             match Reader::new().parse($e, &mut BytesMut::new(), &settings) {
                 Ok(Async::Ready(req)) => req,
                 Ok(_) => panic!("Eof during parsing http request"),
@@ -915,7 +1004,7 @@ mod tests {
     macro_rules! expect_parse_err {
         ($e:expr) => ({
             let mut buf = BytesMut::new();
-            let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+            let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
             match Reader::new().parse($e, &mut buf, &settings) {
                 Err(err) => match err {
@@ -933,7 +1022,7 @@ mod tests {
     fn test_parse() {
         let mut buf = Buffer::new("GET /test HTTP/1.1\r\n\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         match reader.parse(&mut buf, &mut readbuf, &settings) {
@@ -950,7 +1039,7 @@ mod tests {
     fn test_parse_partial() {
         let mut buf = Buffer::new("PUT /test HTTP/1");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         match reader.parse(&mut buf, &mut readbuf, &settings) {
@@ -973,7 +1062,7 @@ mod tests {
     fn test_parse_post() {
         let mut buf = Buffer::new("POST /test2 HTTP/1.0\r\n\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         match reader.parse(&mut buf, &mut readbuf, &settings) {
@@ -990,7 +1079,7 @@ mod tests {
     fn test_parse_body() {
         let mut buf = Buffer::new("GET /test HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         match reader.parse(&mut buf, &mut readbuf, &settings) {
@@ -1009,7 +1098,7 @@ mod tests {
         let mut buf = Buffer::new(
             "\r\nGET /test HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         match reader.parse(&mut buf, &mut readbuf, &settings) {
@@ -1027,7 +1116,7 @@ mod tests {
     fn test_parse_partial_eof() {
         let mut buf = Buffer::new("GET /test HTTP/1.1\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         not_ready!{ reader.parse(&mut buf, &mut readbuf, &settings) }
@@ -1047,7 +1136,7 @@ mod tests {
     fn test_headers_split_field() {
         let mut buf = Buffer::new("GET /test HTTP/1.1\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         not_ready!{ reader.parse(&mut buf, &mut readbuf, &settings) }
@@ -1077,7 +1166,7 @@ mod tests {
              Set-Cookie: c1=cookie1\r\n\
              Set-Cookie: c2=cookie2\r\n\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         match reader.parse(&mut buf, &mut readbuf, &settings) {
@@ -1271,6 +1360,37 @@ mod tests {
         assert_eq!(req.payload_mut().readall().unwrap().as_ref(), b"some raw data");
     }
 
+    #[test]
+    fn test_http_request_upgrade_half_closed() {
+        let mut buf = Buffer::new(
+            "GET /test HTTP/1.1\r\n\
+             connection: upgrade\r\n\
+             upgrade: websocket\r\n\r\n");
+        let mut readbuf = BytesMut::new();
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
+
+        let mut reader = Reader::new();
+        let mut req = reader_parse_ready!(reader.parse(&mut buf, &mut readbuf, &settings));
+
+        // peer sends last bytes and shuts down its write side
+        buf.feed_data("some raw data");
+        buf.feed_eof();
+        match reader.parse(&mut buf, &mut readbuf, &settings) {
+            Err(ReaderError::HalfClosed) => (),
+            _ => panic!("Half-closed connection expected"),
+        }
+        assert_eq!(req.payload_mut().readall().unwrap().as_ref(), b"some raw data");
+        assert!(req.payload().eof());
+
+        // connection without payload is dropped
+        let mut buf = Buffer::new("");
+        buf.feed_eof();
+        match Reader::new().parse(&mut buf, &mut readbuf, &settings) {
+            Err(ReaderError::Disconnect) => (),
+            _ => panic!("Disconnect expected"),
+        }
+    }
+
     #[test]
     fn test_http_request_parser_utf8() {
         let mut buf = Buffer::new(
@@ -1312,7 +1432,7 @@ mod tests {
             "GET /test HTTP/1.1\r\n\
              transfer-encoding: chunked\r\n\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         let mut req = reader_parse_ready!(reader.parse(&mut buf, &mut readbuf, &settings));
@@ -1332,7 +1452,7 @@ mod tests {
             "GET /test HTTP/1.1\r\n\
              transfer-encoding: chunked\r\n\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
 
@@ -1360,7 +1480,7 @@ mod tests {
             "GET /test HTTP/1.1\r\n\
              transfer-encoding: chunked\r\n\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         let mut req = reader_parse_ready!(reader.parse(&mut buf, &mut readbuf, &settings));
@@ -1401,7 +1521,7 @@ mod tests {
             "GET /test HTTP/1.1\r\n\
              transfer-encoding: chunked\r\n\r\n");
         let mut readbuf = BytesMut::new();
-        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None);
+        let settings = WorkerSettings::<HttpApplication>::new(Vec::new(), None, 0);
 
         let mut reader = Reader::new();
         let mut req = reader_parse_ready!(reader.parse(&mut buf, &mut readbuf, &settings));
@@ -1415,6 +1535,48 @@ mod tests {
         assert!(req.payload().eof());
     }
 
+    struct SlowWriter {
+        written: Vec<u8>,
+        budget: usize,
+    }
+
+    impl io::Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.budget == 0 {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, ""))
+            }
+            let size = cmp::min(self.budget, buf.len());
+            self.budget -= size;
+            self.written.extend_from_slice(&buf[..size]);
+            Ok(size)
+        }
+        fn flush(&mut self) -> io::Result<()> {Ok(())}
+    }
+    impl AsyncWrite for SlowWriter {
+        fn shutdown(&mut self) -> Poll<(), io::Error> { Ok(Async::Ready(())) }
+    }
+
+    #[test]
+    fn test_request_timeout_partial_write() {
+        use server::h1writer::H1Writer;
+        use server::shared::SharedBytes;
+
+        let stream = SlowWriter{written: Vec::new(), budget: 10};
+        let mut writer = H1Writer::new(stream, SharedBytes::default());
+        writer.write(Binary::from(REQUEST_TIMEOUT)).unwrap();
+
+        // socket accepts only part of the response
+        not_ready!(writer.poll_flush());
+        assert_eq!(writer.get_mut().written.len(), 10);
+
+        writer.get_mut().budget = 1024;
+        match writer.poll_flush() {
+            Ok(Async::Ready(())) => (),
+            _ => panic!("Response should be flushed"),
+        }
+        assert_eq!(&writer.get_mut().written[..], REQUEST_TIMEOUT);
+    }
+
     /*#[test]
     #[should_panic]
     fn test_parse_multiline() {
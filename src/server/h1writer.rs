@@ -63,6 +63,16 @@ impl<T: AsyncWrite> H1Writer<T> {
         self.flags.contains(Flags::KEEPALIVE) && !self.flags.contains(Flags::UPGRADE)
     }
 
+    /// Write buffered data, not ready until the buffer is empty
+    pub fn poll_flush(&mut self) -> Poll<(), io::Error> {
+        self.write_to_stream()?;
+        if self.buffer.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
     fn write_to_stream(&mut self) -> io::Result<WriterState> {
         while !self.buffer.is_empty() {
             match self.stream.write(self.buffer.as_ref()) {
@@ -64,6 +64,7 @@ pub(crate) struct WorkerSettings<H> {
     h: RefCell<Vec<H>>,
     enabled: bool,
     keep_alive: u64,
+    client_timeout: u64,
     bytes: Rc<SharedBytesPool>,
     messages: Rc<helpers::SharedMessagePool>,
     channels: Cell<usize>,
@@ -71,11 +72,14 @@ pub(crate) struct WorkerSettings<H> {
 }
 
 impl<H> WorkerSettings<H> {
-    pub(crate) fn new(h: Vec<H>, keep_alive: Option<u64>) -> WorkerSettings<H> {
+    pub(crate) fn new(h: Vec<H>, keep_alive: Option<u64>, client_timeout: u64)
+                      -> WorkerSettings<H>
+    {
         WorkerSettings {
             h: RefCell::new(h),
             enabled: if let Some(ka) = keep_alive { ka > 0 } else { false },
             keep_alive: keep_alive.unwrap_or(0),
+            client_timeout: client_timeout,
             bytes: Rc::new(SharedBytesPool::new()),
             messages: Rc::new(helpers::SharedMessagePool::new()),
             channels: Cell::new(0),
@@ -103,6 +107,10 @@ impl<H> WorkerSettings<H> {
         self.enabled
     }
 
+    pub fn client_timeout(&self) -> u64 {
+        self.client_timeout
+    }
+
     pub fn get_shared_bytes(&self) -> SharedBytes {
         SharedBytes::new(self.bytes.get_bytes(), Rc::clone(&self.bytes))
     }
@@ -35,6 +35,7 @@ pub struct HttpServer<H> where H: IntoHttpHandler + 'static
     backlog: i32,
     host: Option<String>,
     keep_alive: Option<u64>,
+    client_timeout: u64,
     factory: Arc<Fn() -> Vec<H> + Send + Sync>,
     workers: Vec<Addr<Syn<Worker<H::Handler>>>>,
     sockets: HashMap<net::SocketAddr, net::TcpListener>,
@@ -74,6 +75,7 @@ impl<H> HttpServer<H> where H: IntoHttpHandler + 'static
                     backlog: 2048,
                     host: None,
                     keep_alive: None,
+                    client_timeout: 0,
                     factory: Arc::new(f),
                     workers: Vec::new(),
                     sockets: HashMap::new(),
@@ -126,6 +128,20 @@ impl<H> HttpServer<H> where H: IntoHttpHandler + 'static
         self
     }
 
+    /// Set server client timeout in milliseconds.
+    ///
+    /// Defines a timeout for reading the first request headers of a connection.
+    /// If a client does not transmit the entire set of headers within this time,
+    /// the request is terminated with the 408 (Request Time-out) error. This also
+    /// bounds websocket upgrade, handshake can not be stalled by slow clients.
+    /// Established connections are not affected.
+    ///
+    /// By default client timeout is disabled.
+    pub fn client_timeout(mut self, val: u64) -> Self {
+        self.client_timeout = val;
+        self
+    }
+
     /// Set server host name.
     ///
     /// Host name is used by application router aa a hostname for url generation.
@@ -211,13 +227,14 @@ impl<H> HttpServer<H> where H: IntoHttpHandler + 'static
 
             let h = handler.clone();
             let ka = self.keep_alive;
+            let timeout = self.client_timeout;
             let factory = Arc::clone(&self.factory);
             let addr = Arbiter::start(move |ctx: &mut Context<_>| {
                 let apps: Vec<_> = (*factory)()
                     .into_iter()
                     .map(|h| h.into_handler(s.clone())).collect();
                 ctx.add_message_stream(rx);
-                Worker::new(apps, h, ka)
+                Worker::new(apps, h, ka, timeout)
             });
             workers.push(tx);
             self.workers.push(addr);
@@ -431,7 +448,8 @@ impl<H: IntoHttpHandler> HttpServer<H>
         let settings = ServerSettings::new(Some(addr), &self.host, secure);
         let apps: Vec<_> = (*self.factory)()
             .into_iter().map(|h| h.into_handler(settings.clone())).collect();
-        self.h = Some(Rc::new(WorkerSettings::new(apps, self.keep_alive)));
+        self.h = Some(Rc::new(
+            WorkerSettings::new(apps, self.keep_alive, self.client_timeout)));
 
         // start server
         let signals = self.subscribe_to_signals();
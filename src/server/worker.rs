@@ -54,11 +54,11 @@ pub(crate) struct Worker<H> where H: HttpHandler + 'static {
 
 impl<H: HttpHandler + 'static> Worker<H> {
 
-    pub(crate) fn new(h: Vec<H>, handler: StreamHandlerType, keep_alive: Option<u64>)
-                      -> Worker<H>
+    pub(crate) fn new(h: Vec<H>, handler: StreamHandlerType,
+                      keep_alive: Option<u64>, client_timeout: u64) -> Worker<H>
     {
         Worker {
-            settings: Rc::new(WorkerSettings::new(h, keep_alive)),
+            settings: Rc::new(WorkerSettings::new(h, keep_alive, client_timeout)),
             hnd: Arbiter::handle().clone(),
             handler: handler,
         }
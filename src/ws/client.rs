@@ -11,7 +11,6 @@ use cookie::Cookie;
 use bytes::BytesMut;
 use http::{HttpTryFrom, StatusCode, Error as HttpError};
 use http::header::{self, HeaderName, HeaderValue};
-use sha1::Sha1;
 use futures::{Async, Future, Poll, Stream};
 use futures::future::{Either, err as FutErr};
 use tokio_core::net::TcpStream;
@@ -27,9 +26,10 @@ use client::{ClientRequest, ClientRequestBuilder,
              HttpResponseParser, HttpResponseParserError, HttpClientWriter};
 use client::{Connect, Connection, ClientConnector, ClientConnectorError};
 
-use super::Message;
-use super::frame::Frame;
-use super::proto::{CloseCode, OpCode};
+use super::{Message, WsClose};
+use super::frame::{FrameDecoder, FrameEncoder, FrameReassembler};
+use super::config::WsConfig;
+use super::proto::{CloseCode, OpCode, ProtocolError, hash_key};
 
 pub type WsClientFuture =
     Future<Item=(WsClientReader, WsClientWriter), Error=WsClientError>;
@@ -58,6 +58,8 @@ pub enum WsClientError {
     Connector(ClientConnectorError),
     #[fail(display="{}", _0)]
     Io(io::Error),
+    #[fail(display="{}", _0)]
+    Protocol(ProtocolError),
     #[fail(display="Disconnected")]
     Disconnected,
 }
@@ -68,6 +70,12 @@ impl From<HttpError> for WsClientError {
     }
 }
 
+impl From<ProtocolError> for WsClientError {
+    fn from(err: ProtocolError) -> WsClientError {
+        WsClientError::Protocol(err)
+    }
+}
+
 impl From<UrlParseError> for WsClientError {
     fn from(err: UrlParseError) -> WsClientError {
         WsClientError::Url(err)
@@ -104,6 +112,8 @@ pub struct WsClient {
     origin: Option<HeaderValue>,
     protocols: Option<String>,
     conn: Addr<Unsync<ClientConnector>>,
+    config: WsConfig,
+    auto_pong: bool,
 }
 
 impl WsClient {
@@ -122,6 +132,8 @@ impl WsClient {
             origin: None,
             protocols: None,
             conn: conn,
+            config: WsConfig::client(),
+            auto_pong: false,
         };
         cl.request.uri(uri.as_ref());
         cl
@@ -139,6 +151,30 @@ impl WsClient {
         self
     }
 
+    /// Set max frame size
+    ///
+    /// By default max size is set to 64kb
+    pub fn max_frame_size(&mut self, size: usize) -> &mut Self {
+        self.config.max_frame_size = size;
+        self.config.max_message_size = size;
+        self
+    }
+
+    /// Set protocol settings, role of the settings is ignored
+    /// since client frames are always masked
+    pub fn config(&mut self, config: WsConfig) -> &mut Self {
+        self.config = WsConfig{server: false, ..config};
+        self
+    }
+
+    /// Automatically respond to Ping messages with Pong
+    ///
+    /// Ping messages are still delivered to the reader.
+    pub fn auto_pong(&mut self, auto_pong: bool) -> &mut Self {
+        self.auto_pong = auto_pong;
+        self
+    }
+
     /// Set cookie for handshake request
     pub fn cookie<'c>(&mut self, cookie: Cookie<'c>) -> &mut Self {
         self.request.cookie(cookie);
@@ -199,11 +235,14 @@ impl WsClient {
         }
 
         // get connection and start handshake
+        let config = self.config;
+        let auto_pong = self.auto_pong;
         Ok(Box::new(
             self.conn.call_fut(Connect(request.uri().clone()))
                 .map_err(|_| WsClientError::Disconnected)
-                .and_then(|res| match res {
-                    Ok(stream) => Either::A(WsHandshake::new(stream, request)),
+                .and_then(move |res| match res {
+                    Ok(stream) => Either::A(
+                        WsHandshake::new(stream, request, config, auto_pong)),
                     Err(err) => Either::B(FutErr(err.into())),
                 })
         ))
@@ -217,6 +256,10 @@ struct WsInner {
     parser_buf: BytesMut,
     closed: bool,
     error_sent: bool,
+    close_code: Option<CloseCode>,
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
+    reassembler: FrameReassembler,
 }
 
 struct WsHandshake {
@@ -227,7 +270,9 @@ struct WsHandshake {
 }
 
 impl WsHandshake {
-    fn new(conn: Connection, mut request: ClientRequest) -> WsHandshake {
+    fn new(conn: Connection, mut request: ClientRequest,
+           config: WsConfig, auto_pong: bool) -> WsHandshake
+    {
         // Generate a random key for the `Sec-WebSocket-Key` header.
         // a base64-encoded (see Section 4 of [RFC4648]) value that,
         // when decoded, is 16 bytes in length (RFC 6455)
@@ -238,13 +283,20 @@ impl WsHandshake {
             HeaderName::try_from("SEC-WEBSOCKET-KEY").unwrap(),
             HeaderValue::try_from(key.as_str()).unwrap());
 
+        let mut decoder = FrameDecoder::new(config);
+        decoder.set_auto_pong(auto_pong);
+
         let inner = WsInner {
             conn: conn,
             writer: HttpClientWriter::new(SharedBytes::default()),
             parser: HttpResponseParser::default(),
-            parser_buf: BytesMut::new(),
+            parser_buf: decoder.buffer(),
             closed: false,
             error_sent: false,
+            close_code: None,
+            decoder: decoder,
+            encoder: FrameEncoder::new(&config),
+            reassembler: FrameReassembler::new(config),
         };
 
         WsHandshake {
@@ -303,13 +355,7 @@ impl Future for WsHandshake {
                 let match_key = if let Some(key) = resp.headers().get(
                     HeaderName::try_from("SEC-WEBSOCKET-ACCEPT").unwrap())
                 {
-                    // field is constructed by concatenating /key/
-                    // with the string "258EAFA5-E914-47DA-95CA-C5AB0DC85B11" (RFC 6455)
-                    const WS_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
-                    let mut sha1 = Sha1::new();
-                    sha1.update(self.key.as_ref());
-                    sha1.update(WS_GUID);
-                    key.as_bytes() == base64::encode(&sha1.digest().bytes()).as_bytes()
+                    key.as_bytes() == hash_key(self.key.as_bytes()).as_bytes()
                 } else {
                     false
                 };
@@ -347,6 +393,13 @@ impl WsClientReader {
     fn as_mut(&mut self) -> &mut WsInner {
         unsafe{ &mut *self.inner.get() }
     }
+
+    /// Status code of the server's Close frame, once it is received.
+    ///
+    /// `CloseCode::Empty` if Close frame did not carry status code.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        unsafe{ (*self.inner.get()).close_code }
+    }
 }
 
 impl Stream for WsClientReader {
@@ -371,59 +424,78 @@ impl Stream for WsClientReader {
         let _ = inner.writer.poll_completed(&mut inner.conn, false);
 
         // read
-        match Frame::parse(&mut inner.parser_buf, false) {
-            Ok(Some(frame)) => {
-                // trace!("WsFrame {}", frame);
-                let (_finished, opcode, payload) = frame.unpack();
-
-                match opcode {
-                    OpCode::Continue => unimplemented!(),
-                    OpCode::Bad =>
-                        Ok(Async::Ready(Some(Message::Error))),
-                    OpCode::Close => {
-                        inner.closed = true;
-                        inner.error_sent = true;
-                        Ok(Async::Ready(Some(Message::Closed)))
-                    },
-                    OpCode::Ping =>
-                        Ok(Async::Ready(Some(
-                            Message::Ping(
-                                String::from_utf8_lossy(payload.as_ref()).into())))),
-                    OpCode::Pong =>
-                        Ok(Async::Ready(Some(
-                            Message::Pong(
-                                String::from_utf8_lossy(payload.as_ref()).into())))),
-                    OpCode::Binary =>
-                        Ok(Async::Ready(Some(Message::Binary(payload)))),
-                    OpCode::Text => {
-                        let tmp = Vec::from(payload.as_ref());
-                        match String::from_utf8(tmp) {
-                            Ok(s) =>
-                                Ok(Async::Ready(Some(Message::Text(s)))),
-                            Err(_) =>
-                                Ok(Async::Ready(Some(Message::Error))),
+        loop {
+            match inner.decoder.decode(&mut inner.parser_buf) {
+                Ok(Some(frame)) => {
+                    // trace!("WsFrame {}", frame);
+                    while let Some(pong) = inner.decoder.pong() {
+                        let _ = inner.writer.write(&pong);
+                    }
+                    let (opcode, payload) = match inner.reassembler.push(frame) {
+                        Ok(Some(msg)) => msg,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            // protocol violation, fail the connection
+                            let _ = inner.writer.write(
+                                &inner.encoder.close(err.close_code(), ""));
+                            let _ = inner.writer.poll_completed(&mut inner.conn, false);
+                            inner.closed = true;
+                            inner.error_sent = true;
+                            return Err(err.into())
+                        }
+                    };
+
+                    return match opcode {
+                        OpCode::Continue | OpCode::Bad | OpCode::Reserved(_) =>
+                            Ok(Async::Ready(Some(Message::Error))),
+                        OpCode::Close => {
+                            inner.closed = true;
+                            inner.error_sent = true;
+                            inner.close_code = Some(WsClose::from_payload(payload.as_ref()).code);
+                            Ok(Async::Ready(Some(Message::Closed)))
+                        },
+                        OpCode::Ping =>
+                            Ok(Async::Ready(Some(
+                                Message::Ping(
+                                    String::from_utf8_lossy(payload.as_ref()).into())))),
+                        OpCode::Pong =>
+                            Ok(Async::Ready(Some(
+                                Message::Pong(
+                                    String::from_utf8_lossy(payload.as_ref()).into())))),
+                        OpCode::Binary =>
+                            Ok(Async::Ready(Some(Message::Binary(payload)))),
+                        OpCode::Text => {
+                            let tmp = Vec::from(payload.as_ref());
+                            match String::from_utf8(tmp) {
+                                Ok(s) =>
+                                    Ok(Async::Ready(Some(Message::Text(s)))),
+                                Err(_) =>
+                                    Ok(Async::Ready(Some(Message::Error))),
+                            }
                         }
                     }
                 }
-            }
-            Ok(None) => {
-                if done {
-                    Ok(Async::Ready(None))
-                } else if inner.closed {
-                    if !inner.error_sent {
-                        inner.error_sent = true;
-                        Ok(Async::Ready(Some(Message::Closed)))
-                    } else {
+                Ok(None) => {
+                    return if done {
                         Ok(Async::Ready(None))
+                    } else if inner.closed {
+                        if !inner.error_sent {
+                            inner.error_sent = true;
+                            Ok(Async::Ready(Some(Message::Closed)))
+                        } else {
+                            Ok(Async::Ready(None))
+                        }
+                    } else {
+                        Ok(Async::NotReady)
                     }
-                } else {
-                    Ok(Async::NotReady)
+                },
+                Err(err) => {
+                    let _ = inner.writer.write(&inner.encoder.close(err.close_code(), ""));
+                    let _ = inner.writer.poll_completed(&mut inner.conn, false);
+                    inner.closed = true;
+                    inner.error_sent = true;
+                    return Err(err.into())
                 }
-            },
-            Err(err) => {
-                inner.closed = true;
-                inner.error_sent = true;
-                Err(err.into())
             }
         }
     }
@@ -455,30 +527,35 @@ impl WsClientWriter {
     /// Send text frame
     #[inline]
     pub fn text<T: Into<String>>(&mut self, text: T) {
-        self.write(&Frame::message(text.into(), OpCode::Text, true, true));
+        let frame = self.as_mut().encoder.message(text.into(), OpCode::Text, true);
+        self.write(&frame);
     }
 
     /// Send binary frame
     #[inline]
     pub fn binary<B: Into<Binary>>(&mut self, data: B) {
-        self.write(&Frame::message(data, OpCode::Binary, true, true));
+        let frame = self.as_mut().encoder.message(data, OpCode::Binary, true);
+        self.write(&frame);
     }
 
     /// Send ping frame
     #[inline]
     pub fn ping(&mut self, message: &str) {
-        self.write(&Frame::message(Vec::from(message), OpCode::Ping, true, true));
+        let frame = self.as_mut().encoder.message(Vec::from(message), OpCode::Ping, true);
+        self.write(&frame);
     }
 
     /// Send pong frame
     #[inline]
     pub fn pong(&mut self, message: &str) {
-        self.write(&Frame::message(Vec::from(message), OpCode::Pong, true, true));
+        let frame = self.as_mut().encoder.message(Vec::from(message), OpCode::Pong, true);
+        self.write(&frame);
     }
 
     /// Send close frame
     #[inline]
     pub fn close(&mut self, code: CloseCode, reason: &str) {
-        self.write(&Frame::close(code, reason, true));
+        let frame = self.as_mut().encoder.close(code, reason);
+        self.write(&frame);
     }
 }
@@ -0,0 +1,371 @@
+//! Frame codec core operating on byte slices.
+//!
+//! Functions of this module do not allocate and do not depend on `std::io`
+//! or `bytes`. Errors are reported with plain `CodecError`, `ProtocolError`
+//! and `std::io::Error` conversions live in the `ws` layer.
+use std::fmt;
+use byteorder::{ByteOrder, NetworkEndian};
+
+use ws::mask::apply_mask;
+
+/// First reserved bit, used by `permessage-deflate` extension
+pub const RSV1: u8 = 0x40;
+/// Second reserved bit
+pub const RSV2: u8 = 0x20;
+/// Third reserved bit
+pub const RSV3: u8 = 0x10;
+
+/// Frame codec errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CodecError {
+    /// Server received a frame without masking key
+    UnmaskedClientFrame,
+    /// Client received a masked frame
+    MaskedServerFrame,
+    /// Reserved bit is set without negotiated extension
+    ReservedBitSet,
+    /// Frame payload is larger than max frame size
+    FrameTooLarge,
+    /// 64-bit frame length does not fit into usize
+    LengthOverflow,
+    /// Output buffer can not hold encoded frame
+    BufferTooSmall,
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            CodecError::UnmaskedClientFrame => "Received an unmasked frame from client",
+            CodecError::MaskedServerFrame => "Received a masked frame from server",
+            CodecError::ReservedBitSet => "Received a frame with unexpected reserved bits set",
+            CodecError::FrameTooLarge => "Frame too large",
+            CodecError::LengthOverflow => "Frame length exceeds platform usize",
+            CodecError::BufferTooSmall => "Buffer too small for encoded frame",
+        };
+        f.write_str(msg)
+    }
+}
+
+/// Frame header
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHeader {
+    /// Final fragment of the message
+    pub finished: bool,
+    /// Reserved bit 1
+    pub rsv1: bool,
+    /// Reserved bit 2
+    pub rsv2: bool,
+    /// Reserved bit 3
+    pub rsv3: bool,
+    /// Raw opcode
+    pub opcode: u8,
+    /// Payload length
+    pub length: usize,
+    /// Masking key
+    pub mask: Option<[u8; 4]>,
+}
+
+/// Parse frame header from the start of `buf`.
+///
+/// Returns header and its length, or `None` if `buf` does not hold complete
+/// header yet. Reserved bits missing from `reserved_bits` are rejected,
+/// as well as frames with payload larger than `max_size`.
+pub fn decode_header(buf: &[u8], server: bool, reserved_bits: u8, max_size: usize)
+                     -> Result<Option<(FrameHeader, usize)>, CodecError>
+{
+    let mut idx = 2;
+    let mut size = buf.len();
+
+    if size < 2 {
+        return Ok(None)
+    }
+    size -= 2;
+    let first = buf[0];
+    let second = buf[1];
+    let finished = first & 0x80 != 0;
+
+    // check masking
+    let masked = second & 0x80 != 0;
+    if !masked && server {
+        return Err(CodecError::UnmaskedClientFrame)
+    } else if masked && !server {
+        return Err(CodecError::MaskedServerFrame)
+    }
+
+    // check reserved bits
+    if first & (RSV1 | RSV2 | RSV3) & !reserved_bits != 0 {
+        return Err(CodecError::ReservedBitSet)
+    }
+
+    let len = second & 0x7F;
+    let length = if len == 126 {
+        if size < 2 {
+            return Ok(None)
+        }
+        let len = NetworkEndian::read_uint(&buf[idx..], 2) as usize;
+        size -= 2;
+        idx += 2;
+        len
+    } else if len == 127 {
+        if size < 8 {
+            return Ok(None)
+        }
+        let len = frame_length(NetworkEndian::read_u64(&buf[idx..]))?;
+        size -= 8;
+        idx += 8;
+        len
+    } else {
+        len as usize
+    };
+
+    // check for max allowed size
+    if length > max_size {
+        return Err(CodecError::FrameTooLarge)
+    }
+
+    let mask = if server {
+        if size < 4 {
+            return Ok(None)
+        } else {
+            let mut mask_bytes = [0u8; 4];
+            mask_bytes.copy_from_slice(&buf[idx..idx+4]);
+            idx += 4;
+            Some(mask_bytes)
+        }
+    } else {
+        None
+    };
+
+    Ok(Some((FrameHeader {
+        finished: finished,
+        rsv1: first & RSV1 != 0,
+        rsv2: first & RSV2 != 0,
+        rsv3: first & RSV3 != 0,
+        opcode: first & 0x0F,
+        length: length,
+        mask: mask,
+    }, idx)))
+}
+
+/// Parse complete frame from the start of `buf`, payload is unmasked in place.
+///
+/// Returns header and its length, payload is `buf[idx..idx + header.length]`.
+/// `None` is returned until `buf` holds the whole frame.
+pub fn decode(buf: &mut [u8], server: bool, reserved_bits: u8, max_size: usize)
+              -> Result<Option<(FrameHeader, usize)>, CodecError>
+{
+    let (header, idx) = match decode_masked(buf, server, reserved_bits, max_size)? {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+    if let Some(ref mask) = header.mask {
+        apply_mask(&mut buf[idx..idx + header.length], mask);
+    }
+    Ok(Some((header, idx)))
+}
+
+/// Parse complete frame from the start of `buf`, payload stays masked.
+///
+/// Same as `decode()`, masking key is reported in the header.
+pub fn decode_masked(buf: &[u8], server: bool, reserved_bits: u8, max_size: usize)
+                     -> Result<Option<(FrameHeader, usize)>, CodecError>
+{
+    let (header, idx) = match decode_header(buf, server, reserved_bits, max_size)? {
+        Some(res) => res,
+        None => return Ok(None),
+    };
+    if buf.len() - idx < header.length {
+        return Ok(None)
+    }
+    Ok(Some((header, idx)))
+}
+
+/// Size of encoded frame: 2, 4 or 10 bytes of header
+/// depending on payload length, masking key and payload
+pub fn encoded_len(payload_len: usize, masked: bool) -> usize {
+    let header = if payload_len < 126 {
+        2
+    } else if payload_len <= 65_535 {
+        4
+    } else {
+        10
+    };
+    if masked {
+        header + 4 + payload_len
+    } else {
+        header + payload_len
+    }
+}
+
+/// Encode frame into the start of `buf`, payload is masked
+/// if header carries masking key.
+///
+/// `header.length` is ignored, length of `payload` is encoded.
+/// Returns number of bytes written.
+pub fn encode(buf: &mut [u8], header: &FrameHeader, payload: &[u8])
+              -> Result<usize, CodecError>
+{
+    let payload_len = payload.len();
+    if buf.len() < encoded_len(payload_len, header.mask.is_some()) {
+        return Err(CodecError::BufferTooSmall)
+    }
+
+    let mut one = header.opcode & 0x0F;
+    if header.finished {
+        one |= 0x80;
+    }
+    if header.rsv1 {
+        one |= RSV1;
+    }
+    if header.rsv2 {
+        one |= RSV2;
+    }
+    if header.rsv3 {
+        one |= RSV3;
+    }
+    let two = if header.mask.is_some() { 0x80 } else { 0 };
+
+    buf[0] = one;
+    let mut idx = if payload_len < 126 {
+        buf[1] = two | payload_len as u8;
+        2
+    } else if payload_len <= 65_535 {
+        buf[1] = two | 126;
+        NetworkEndian::write_u16(&mut buf[2..4], payload_len as u16);
+        4
+    } else {
+        buf[1] = two | 127;
+        NetworkEndian::write_u64(&mut buf[2..10], payload_len as u64);
+        10
+    };
+
+    if let Some(ref mask) = header.mask {
+        buf[idx..idx+4].copy_from_slice(mask);
+        idx += 4;
+    }
+    buf[idx..idx+payload_len].copy_from_slice(payload);
+    if let Some(ref mask) = header.mask {
+        // masking key phase starts at the first payload byte
+        apply_mask(&mut buf[idx..idx+payload_len], mask);
+    }
+    Ok(idx + payload_len)
+}
+
+/// Size of frame header, as far as it is known from available bytes
+pub(crate) fn header_len(buf: &[u8]) -> usize {
+    if buf.len() < 2 {
+        return 2
+    }
+    let len = match buf[1] & 0x7F {
+        126 => 4,
+        127 => 10,
+        _ => 2,
+    };
+    if buf[1] & 0x80 != 0 {
+        len + 4
+    } else {
+        len
+    }
+}
+
+/// Convert 64-bit payload length, it does not fit into usize on 32-bit platforms
+fn frame_length(length: u64) -> Result<usize, CodecError> {
+    if length > usize::max_value() as u64 {
+        Err(CodecError::LengthOverflow)
+    } else {
+        Ok(length as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(opcode: u8, mask: Option<[u8; 4]>) -> FrameHeader {
+        FrameHeader{finished: true, rsv1: false, rsv2: false, rsv3: false,
+                    opcode: opcode, length: 0, mask: mask}
+    }
+
+    #[test]
+    fn test_frame_length() {
+        assert_eq!(frame_length(10).unwrap(), 10);
+        assert_eq!(frame_length(usize::max_value() as u64).unwrap(), usize::max_value());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn test_frame_length_overflow() {
+        // 2^32 + 10 would be truncated to 10
+        assert_eq!(frame_length((1u64 << 32) + 10), Err(CodecError::LengthOverflow));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = [0u8; 512];
+        for &len in &[0usize, 1, 125, 126, 300] {
+            let payload = [0x5au8; 300];
+            let payload = &payload[..len];
+
+            // client frame
+            let n = encode(&mut buf, &header(2, Some([1, 2, 3, 4])), payload).unwrap();
+            assert_eq!(n, encoded_len(len, true));
+            let (hdr, idx) = decode(&mut buf[..n], true, 0, 1024).unwrap().unwrap();
+            assert_eq!(hdr.opcode, 2);
+            assert_eq!(hdr.length, len);
+            assert_eq!(hdr.mask, Some([1, 2, 3, 4]));
+            assert_eq!(&buf[idx..idx + hdr.length], payload);
+
+            // server frame
+            let n = encode(&mut buf, &header(1, None), payload).unwrap();
+            assert_eq!(n, encoded_len(len, false));
+            let (hdr, idx) = decode(&mut buf[..n], false, 0, 1024).unwrap().unwrap();
+            assert!(hdr.finished);
+            assert_eq!(hdr.mask, None);
+            assert_eq!(idx + hdr.length, n);
+            assert_eq!(&buf[idx..n], payload);
+        }
+    }
+
+    #[test]
+    fn test_decode_masked() {
+        let mut buf = [0u8; 16];
+        let n = encode(&mut buf, &header(1, Some([1, 2, 3, 4])), b"data").unwrap();
+        assert_eq!(decode_masked(&buf[..n - 1], true, 0, 1024), Ok(None));
+        let (hdr, idx) = decode_masked(&buf[..n], true, 0, 1024).unwrap().unwrap();
+        assert_eq!(hdr.mask, Some([1, 2, 3, 4]));
+        assert_eq!(&buf[idx..n], &[b'd' ^ 1, b'a' ^ 2, b't' ^ 3, b'a' ^ 4][..]);
+    }
+
+    #[test]
+    fn test_decode_partial() {
+        let mut buf = [0u8; 16];
+        let n = encode(&mut buf, &header(1, Some([1, 2, 3, 4])), b"data").unwrap();
+        for i in 0..n {
+            assert_eq!(decode(&mut buf[..i], true, 0, 1024), Ok(None));
+        }
+        assert!(decode(&mut buf[..n], true, 0, 1024).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let mut buf = [0u8; 16];
+        let n = encode(&mut buf, &header(1, None), b"data").unwrap();
+        assert_eq!(decode(&mut buf[..n], true, 0, 1024), Err(CodecError::UnmaskedClientFrame));
+        assert_eq!(decode(&mut buf[..n], false, 0, 3), Err(CodecError::FrameTooLarge));
+
+        let hdr = FrameHeader{rsv2: true, ..header(1, None)};
+        let n = encode(&mut buf, &hdr, b"data").unwrap();
+        assert_eq!(decode(&mut buf[..n], false, 0, 1024), Err(CodecError::ReservedBitSet));
+        assert!(decode(&mut buf[..n], false, RSV2, 1024).unwrap().unwrap().0.rsv2);
+
+        let n = encode(&mut buf, &header(1, Some([1, 2, 3, 4])), b"data").unwrap();
+        assert_eq!(decode(&mut buf[..n], false, 0, 1024), Err(CodecError::MaskedServerFrame));
+    }
+
+    #[test]
+    fn test_encode_buffer_too_small() {
+        let mut buf = [0u8; 5];
+        assert_eq!(encode(&mut buf, &header(1, None), b"data"), Err(CodecError::BufferTooSmall));
+        assert_eq!(encode(&mut buf, &header(1, None), b"dat"), Ok(5));
+    }
+}
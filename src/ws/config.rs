@@ -0,0 +1,145 @@
+//! `WebSocket` protocol settings
+use ws::frame::RSV1;
+
+/// Protocol settings shared by frame parser, reassembler and encoder.
+///
+/// Use `WsConfig::server()` or `WsConfig::client()` to get default
+/// settings for the corresponding side of the connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WsConfig {
+    /// Server side of the connection, expects masked frames
+    /// and sends unmasked ones.
+    pub server: bool,
+    /// Max payload size of a single frame, 64kb by default
+    pub max_frame_size: usize,
+    /// Max size of a message reassembled from fragments, 64kb by default
+    pub max_message_size: usize,
+    /// Reserved bits (`RSV1`, `RSV2`, `RSV3`) enabled by negotiated extensions
+    pub reserved_mask: u8,
+    /// `permessage-deflate` extension is negotiated
+    pub deflate: bool,
+    /// Handling of ping and pong frames with payload longer than 125 bytes
+    pub control_frame_policy: ControlFrameTooLongPolicy,
+    /// Reserved data opcodes (0x3-0x7) accepted as `OpCode::Reserved`,
+    /// bit `1 << opcode` enables the opcode. Empty by default.
+    pub reserved_opcodes: u8,
+    /// Initial capacity of the read buffer, 4kb by default
+    pub read_buffer_capacity: usize,
+    /// Server accepts unmasked frames from client, i.e. behind trusted
+    /// proxy which strips masking. Disabled by default.
+    pub allow_unmasked_client: bool,
+    /// Reject frames with payload length encoded in more bytes
+    /// than necessary. Disabled by default.
+    pub strict_length_encoding: bool,
+    /// Close frame with payload longer than 125 bytes is replaced with
+    /// protocol error Close frame. Otherwise parser fails with
+    /// `ProtocolError::ControlFrameTooLong`. Enabled by default.
+    pub morph_oversized_close: bool,
+    /// Max number of frames a fragmented message can consist of, 1024 by default
+    pub max_fragments_per_message: usize,
+    /// Unmask payload of parsed frames. If disabled, payload stays masked and
+    /// `Frame::mask()` reports masking key, i.e. for pass-through proxies.
+    /// Enabled by default.
+    pub unmask: bool,
+}
+
+/// What to do with ping or pong frame exceeding 125 bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlFrameTooLongPolicy {
+    /// Fail the connection, this is default
+    Fail,
+    /// Keep first 125 bytes of the payload and proceed
+    Truncate,
+}
+
+impl WsConfig {
+
+    /// Default settings of the server side
+    pub fn server() -> WsConfig {
+        WsConfig {
+            server: true,
+            max_frame_size: 65_536,
+            max_message_size: 65_536,
+            reserved_mask: 0,
+            deflate: false,
+            control_frame_policy: ControlFrameTooLongPolicy::Fail,
+            reserved_opcodes: 0,
+            read_buffer_capacity: 4096,
+            allow_unmasked_client: false,
+            strict_length_encoding: false,
+            morph_oversized_close: true,
+            max_fragments_per_message: 1024,
+            unmask: true,
+        }
+    }
+
+    /// Default settings of the client side
+    pub fn client() -> WsConfig {
+        WsConfig {
+            server: false,
+            ..WsConfig::server()
+        }
+    }
+
+    /// Accept frames with reserved data opcode, opcode must be in 0x3-0x7 range
+    pub fn reserved_opcode(mut self, opcode: u8) -> WsConfig {
+        assert!(opcode >= 3 && opcode <= 7, "Not a reserved data opcode: {}", opcode);
+        self.reserved_opcodes |= 1 << opcode;
+        self
+    }
+
+    /// Is reserved opcode accepted
+    pub(crate) fn is_reserved_opcode(&self, opcode: u8) -> bool {
+        opcode >= 3 && opcode <= 7 && self.reserved_opcodes & (1 << opcode) != 0
+    }
+
+    /// Reserved bits allowed in received frames
+    pub(crate) fn reserved_bits(&self) -> u8 {
+        if self.deflate {
+            self.reserved_mask | RSV1
+        } else {
+            self.reserved_mask
+        }
+    }
+}
+
+impl Default for WsConfig {
+    fn default() -> WsConfig {
+        WsConfig::server()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let cfg = WsConfig::default();
+        assert!(cfg.server);
+        assert_eq!(cfg.max_frame_size, 65_536);
+        assert_eq!(cfg.max_message_size, 65_536);
+        assert_eq!(cfg.reserved_bits(), 0);
+        assert_eq!(cfg.control_frame_policy, ControlFrameTooLongPolicy::Fail);
+        assert!(cfg.morph_oversized_close);
+        assert_eq!(cfg.max_fragments_per_message, 1024);
+        assert!(cfg.unmask);
+
+        assert!(!cfg.is_reserved_opcode(3));
+
+        let cfg = WsConfig::server().reserved_opcode(3).reserved_opcode(7);
+        assert!(cfg.is_reserved_opcode(3));
+        assert!(!cfg.is_reserved_opcode(4));
+        assert!(cfg.is_reserved_opcode(7));
+
+        let cfg = WsConfig { deflate: true, ..WsConfig::client() };
+        assert!(!cfg.server);
+        assert_eq!(cfg.reserved_bits(), RSV1);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_reserved_control_opcode() {
+        let _ = WsConfig::server().reserved_opcode(0xB);
+    }
+}
@@ -1,4 +1,9 @@
-use std::mem;
+use std::{io, mem};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use failure::Fail;
 use futures::{Async, Poll};
 use futures::sync::oneshot::Sender;
 use futures::unsync::oneshot;
@@ -14,8 +19,10 @@ use error::{Error, ErrorInternalServerError};
 use httprequest::HttpRequest;
 use context::{Frame as ContextFrame, ActorHttpContext, Drain};
 
-use ws::frame::Frame;
+use ws::frame::FrameEncoder;
+use ws::config::WsConfig;
 use ws::proto::{OpCode, CloseCode};
+use ws::deflate::{PerMessageDeflate, NegotiatedExtensions};
 
 
 /// Http actor execution context
@@ -25,6 +32,57 @@ pub struct WebsocketContext<A, S=()> where A: Actor<Context=WebsocketContext<A,
     stream: Option<SmallVec<[ContextFrame; 4]>>,
     request: HttpRequest<S>,
     disconnected: bool,
+    encoder: FrameEncoder,
+    buffered: usize,
+    high_water_mark: usize,
+    deflate: Option<PerMessageDeflate>,
+    extensions: NegotiatedExtensions,
+    protocol: Option<String>,
+    activity: Rc<Cell<Instant>>,
+    failure: Rc<Cell<Option<CloseCode>>>,
+    close_state: Rc<Cell<CloseState>>,
+    peer_close: Rc<Cell<Option<CloseCode>>>,
+    close_timeout: Duration,
+    close_timer: bool,
+    close_waiters: Vec<oneshot::Sender<()>>,
+}
+
+/// State of the closing handshake
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseState {
+    /// No Close frame passed yet
+    Open,
+    /// Close frame is sent, waiting for the peer's Close frame
+    Sent,
+    /// Close frame is received from the peer, reply is not sent yet
+    Received,
+    /// Both Close frames have passed
+    Closed,
+}
+
+impl CloseState {
+    /// State after Close frame is sent
+    pub(crate) fn sent(self) -> CloseState {
+        match self {
+            CloseState::Open => CloseState::Sent,
+            CloseState::Received | CloseState::Closed => CloseState::Closed,
+            CloseState::Sent => CloseState::Sent,
+        }
+    }
+
+    /// State after Close frame is received from the peer
+    pub(crate) fn received(self) -> CloseState {
+        match self {
+            CloseState::Open => CloseState::Received,
+            CloseState::Sent | CloseState::Closed => CloseState::Closed,
+            CloseState::Received => CloseState::Received,
+        }
+    }
+
+    /// Close frame is already sent, no more frames are allowed
+    fn is_sent(&self) -> bool {
+        *self == CloseState::Sent || *self == CloseState::Closed
+    }
 }
 
 impl<A, S> ActorContext for WebsocketContext<A, S> where A: Actor<Context=Self>
@@ -91,6 +149,19 @@ impl<A, S: 'static> WebsocketContext<A, S> where A: Actor<Context=Self> {
             stream: None,
             request: req,
             disconnected: false,
+            encoder: FrameEncoder::new(&WsConfig::server()),
+            buffered: 0,
+            high_water_mark: 65_536,
+            deflate: None,
+            extensions: NegotiatedExtensions::default(),
+            protocol: None,
+            activity: Rc::new(Cell::new(Instant::now())),
+            failure: Rc::new(Cell::new(None)),
+            close_state: Rc::new(Cell::new(CloseState::Open)),
+            peer_close: Rc::new(Cell::new(None)),
+            close_timeout: Duration::new(5, 0),
+            close_timer: false,
+            close_waiters: Vec::new(),
         }
     }
 
@@ -110,6 +181,7 @@ impl<A, S> WebsocketContext<A, S> where A: Actor<Context=Self> {
             if self.stream.is_none() {
                 self.stream = Some(SmallVec::new());
             }
+            self.buffered += data.len();
             let stream = self.stream.as_mut().unwrap();
             stream.push(ContextFrame::Chunk(Some(data)));
         } else {
@@ -117,6 +189,62 @@ impl<A, S> WebsocketContext<A, S> where A: Actor<Context=Self> {
         }
     }
 
+    /// Take frames buffered for the peer
+    fn take_frames(&mut self) -> Option<SmallVec<[ContextFrame; 4]>> {
+        self.buffered = 0;
+        self.stream.take()
+    }
+
+    /// Size of encoded frames not yet taken by the connection
+    pub fn buffer_size(&self) -> usize {
+        self.buffered
+    }
+
+    /// Bytes which can be buffered before `try_send()` starts to fail
+    /// with `ErrorKind::WouldBlock`
+    pub fn send_capacity(&self) -> usize {
+        self.high_water_mark.saturating_sub(self.buffered)
+    }
+
+    /// Set high-water-mark of the outbound buffer used by `try_send()`.
+    ///
+    /// By default high-water-mark is set to 64kb
+    pub fn set_high_water_mark(&mut self, size: usize) {
+        self.high_water_mark = size;
+    }
+
+    /// Send message frame unless outbound buffer reached high-water-mark.
+    ///
+    /// Returns `ErrorKind::WouldBlock` error if buffer is full, application
+    /// should retry later. Ping and pong payload is limited to 125 bytes,
+    /// Close and Continue frames are rejected with `ErrorKind::InvalidInput`,
+    /// use `WebsocketContext::close()` to close the connection.
+    pub fn try_send(&mut self, code: OpCode, data: Binary) -> io::Result<()> {
+        if self.disconnected {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Connection is closed"))
+        }
+        if self.close_state.get().is_sent() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Close frame is sent"))
+        }
+        if self.buffered >= self.high_water_mark {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "Write buffer is full"))
+        }
+        let frame = match code {
+            OpCode::Text | OpCode::Binary => self.message(data, code),
+            OpCode::Reserved(_) => self.encoder.message(data, code, true),
+            OpCode::Ping | OpCode::Pong => match self.encoder.control(code, data.as_ref()) {
+                Ok(frame) => frame,
+                Err(err) =>
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, err.compat())),
+            },
+            OpCode::Close | OpCode::Continue | OpCode::Bad =>
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput, format!("Can not send {} frame", code))),
+        };
+        self.write(frame);
+        Ok(())
+    }
+
     /// Shared application state
     #[inline]
     pub fn state(&self) -> &S {
@@ -129,34 +257,261 @@ impl<A, S> WebsocketContext<A, S> where A: Actor<Context=Self> {
         &mut self.request
     }
 
+    /// Compress outgoing text and binary messages with
+    /// negotiated `permessage-deflate` context
+    pub fn set_deflate(&mut self, deflate: PerMessageDeflate) {
+        self.deflate = Some(deflate);
+    }
+
+    /// Extensions negotiated during handshake
+    pub fn extensions(&self) -> &NegotiatedExtensions {
+        &self.extensions
+    }
+
+    /// Enable extensions accepted during handshake
+    ///
+    /// Compressor window size can not be limited, messages are sent
+    /// uncompressed if client restricted `server_max_window_bits`.
+    pub(crate) fn set_extensions(&mut self, extensions: NegotiatedExtensions) {
+        if let Some(ref deflate) = extensions.deflate {
+            if deflate.server_max_window_bits == 15 {
+                self.set_deflate(PerMessageDeflate::with_params(deflate, true));
+            }
+        }
+        self.extensions = extensions;
+    }
+
+    /// Subprotocol selected during handshake
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_ref().map(|p| p.as_str())
+    }
+
+    pub(crate) fn set_protocol(&mut self, protocol: Option<String>) {
+        self.protocol = protocol;
+    }
+
+    /// Share time of last received frame with incoming stream
+    pub(crate) fn set_activity(&mut self, activity: Rc<Cell<Instant>>) {
+        self.activity = activity;
+    }
+
+    /// Share protocol violation reported by incoming stream
+    pub(crate) fn set_failure(&mut self, failure: Rc<Cell<Option<CloseCode>>>) {
+        self.failure = failure;
+    }
+
+    /// Fail the connection if incoming stream encountered protocol violation.
+    ///
+    /// Close frame is sent unless actor already closed the connection.
+    fn fail_connection(&mut self) {
+        if let Some(code) = self.failure.get() {
+            self.failure.set(None);
+            self.close(Some((code, "")));
+            self.stop();
+        }
+    }
+
+    /// Share state of the closing handshake with incoming stream
+    pub(crate) fn set_close_state(&mut self, state: Rc<Cell<CloseState>>) {
+        self.close_state = state;
+    }
+
+    /// Share status code of the peer's Close frame with incoming stream
+    pub(crate) fn set_peer_close(&mut self, code: Rc<Cell<Option<CloseCode>>>) {
+        self.peer_close = code;
+    }
+
+    /// State of the closing handshake
+    pub fn close_state(&self) -> CloseState {
+        self.close_state.get()
+    }
+
+    /// Set time to wait for the peer's Close frame after
+    /// sending ours, connection is dropped once it elapses.
+    ///
+    /// By default timeout is set to 5 seconds
+    pub fn set_close_timeout(&mut self, timeout: Duration) {
+        self.close_timeout = timeout;
+    }
+
+    /// Reply to the peer's Close frame unless actor did it already,
+    /// stop once both Close frames have passed.
+    ///
+    /// Reply echoes the peer's status code, Close frame without
+    /// status code is answered with an empty one.
+    ///
+    /// Stopping does not drop queued frames, they are flushed along
+    /// with the reply even if the peer already closed its write side.
+    fn close_handshake(&mut self) {
+        match self.close_state.get() {
+            CloseState::Sent if !self.close_timer => {
+                self.close_timer = true;
+                let timeout = self.close_timeout;
+                self.run_later(timeout, |_, ctx| ctx.close_timeout_elapsed());
+            }
+            CloseState::Open | CloseState::Sent => (),
+            CloseState::Received => {
+                match self.peer_close.get() {
+                    // reserved codes must not be sent over the wire
+                    Some(CloseCode::Reserved(_)) => self.close(Some((CloseCode::Protocol, ""))),
+                    Some(CloseCode::Empty) | None => self.close(None),
+                    Some(code) => self.close(Some((code, ""))),
+                }
+                self.closed();
+                self.stop();
+            }
+            CloseState::Closed => {
+                self.closed();
+                self.stop();
+            }
+        }
+    }
+
+    fn close_timeout_elapsed(&mut self) {
+        if self.close_state.get() == CloseState::Sent {
+            debug!("Peer did not reply to close frame, dropping connection");
+            self.closed();
+            self.stop();
+        }
+    }
+
+    /// Resolve `close_and_wait()` futures
+    fn closed(&mut self) {
+        for tx in self.close_waiters.drain(..) {
+            let _ = tx.send(());
+        }
+    }
+
+    /// Start sending heartbeats.
+    ///
+    /// Ping frame is sent every `interval`, connection gets closed with
+    /// `CloseCode::Away` if no frame is received from the peer within `timeout`.
+    pub fn heartbeat(&mut self, interval: Duration, timeout: Duration) {
+        let hb = Heartbeat{interval: interval, timeout: timeout};
+        self.run_later(interval, move |_, ctx| ctx.beat(hb));
+    }
+
+    fn beat(&mut self, hb: Heartbeat) {
+        if self.beat_at(hb, Instant::now()) {
+            self.run_later(hb.interval, move |_, ctx| ctx.beat(hb));
+        }
+    }
+
+    /// Ping the peer or close silent connection,
+    /// returns `true` if next heartbeat has to be scheduled.
+    fn beat_at(&mut self, hb: Heartbeat, now: Instant) -> bool {
+        if !self.connected() {
+            return false
+        }
+        if hb.expired(self.activity.get(), now) {
+            self.close(Some((CloseCode::Away, "Heartbeat timeout")));
+            self.stop();
+            false
+        } else {
+            self.ping("");
+            true
+        }
+    }
+
     /// Send text frame
     #[inline]
     pub fn text<T: Into<String>>(&mut self, text: T) {
-        self.write(Frame::message(text.into(), OpCode::Text, true, false));
+        if self.close_state.get().is_sent() {
+            warn!("Trying to send text frame after close frame");
+            return
+        }
+        let text: String = text.into();
+        let frame = self.message(text.into(), OpCode::Text);
+        self.write(frame);
     }
 
     /// Send binary frame
     #[inline]
     pub fn binary<B: Into<Binary>>(&mut self, data: B) {
-        self.write(Frame::message(data, OpCode::Binary, true, false));
+        if self.close_state.get().is_sent() {
+            warn!("Trying to send binary frame after close frame");
+            return
+        }
+        let frame = self.message(data.into(), OpCode::Binary);
+        self.write(frame);
+    }
+
+    /// Send already encoded frame, i.e. the same broadcast frame to many peers.
+    ///
+    /// Bytes are written as is, frame must not be masked by server side.
+    /// Nothing is sent after close frame.
+    pub fn write_raw<B: Into<Binary>>(&mut self, frame: B) {
+        if self.close_state.get().is_sent() {
+            warn!("Trying to send raw frame after close frame");
+            return
+        }
+        self.write(frame.into());
+    }
+
+    fn message(&mut self, data: Binary, code: OpCode) -> Binary {
+        if let Some(ref mut deflate) = self.deflate {
+            match self.encoder.compressed_message(data.as_ref(), code, deflate) {
+                Ok(frame) => return frame,
+                Err(err) => warn!("Can not compress websocket message: {}", err),
+            }
+        }
+        self.encoder.message(data, code, true)
     }
 
     /// Send ping frame
+    ///
+    /// Message longer than 125 bytes is not sent, warning is logged.
     #[inline]
     pub fn ping(&mut self, message: &str) {
-        self.write(Frame::message(Vec::from(message), OpCode::Ping, true, false));
+        self.control(OpCode::Ping, message)
     }
 
     /// Send pong frame
+    ///
+    /// Pong can be sent unsolicited as a unidirectional heartbeat, peer
+    /// does not reply to it. Message longer than 125 bytes is not sent,
+    /// warning is logged.
     #[inline]
     pub fn pong(&mut self, message: &str) {
-        self.write(Frame::message(Vec::from(message), OpCode::Pong, true, false));
+        self.control(OpCode::Pong, message)
+    }
+
+    fn control(&mut self, code: OpCode, message: &str) {
+        match self.encoder.control(code, message.as_bytes()) {
+            Ok(frame) => self.write(frame),
+            Err(err) => warn!("Can not send {} frame: {}", code, err),
+        }
     }
 
     /// Send close frame
-    #[inline]
-    pub fn close(&mut self, code: CloseCode, reason: &str) {
-        self.write(Frame::close(code, reason, false));
+    ///
+    /// `None` sends close frame without payload, peer sees it as
+    /// `CloseCode::Empty` (1005). Only first close frame is sent,
+    /// connection is dropped if the peer does not reply within close timeout.
+    pub fn close(&mut self, reason: Option<(CloseCode, &str)>) {
+        let state = self.close_state.get();
+        if state.is_sent() {
+            return
+        }
+        let (code, reason) = reason.unwrap_or((CloseCode::Empty, ""));
+        let frame = self.encoder.close(code, reason);
+        self.close_state.set(state.sent());
+        self.write(frame);
+    }
+
+    /// Send close frame and wait for the peer's Close frame.
+    ///
+    /// Returned future resolves once both Close frames have passed,
+    /// close timeout elapses or the connection is gone.
+    pub fn close_and_wait(&mut self, reason: Option<(CloseCode, &str)>) -> CloseWait<A> {
+        let (tx, rx) = oneshot::channel();
+        self.close(reason);
+        if self.close_state.get() == CloseState::Closed {
+            let _ = tx.send(());
+        } else {
+            self.close_waiters.push(tx);
+        }
+        CloseWait{rx: rx, _a: PhantomData}
     }
 
     /// Returns drain future
@@ -205,9 +560,11 @@ impl<A, S> ActorHttpContext for WebsocketContext<A, S> where A: Actor<Context=Se
         if self.inner.alive() && self.inner.poll(ctx).is_err() {
             return Err(ErrorInternalServerError("error").into())
         }
+        self.fail_connection();
+        self.close_handshake();
 
         // frames
-        if let Some(data) = self.stream.take() {
+        if let Some(data) = self.take_frames() {
             Ok(Async::Ready(Some(data)))
         } else if self.inner.alive() {
             Ok(Async::NotReady)
@@ -217,6 +574,42 @@ impl<A, S> ActorHttpContext for WebsocketContext<A, S> where A: Actor<Context=Se
     }
 }
 
+/// Future returned by `WebsocketContext::close_and_wait()`
+pub struct CloseWait<A> {
+    rx: oneshot::Receiver<()>,
+    _a: PhantomData<A>,
+}
+
+impl<A: Actor> ActorFuture for CloseWait<A> {
+    type Item = ();
+    type Error = ();
+    type Actor = A;
+
+    fn poll(&mut self,
+            _: &mut A,
+            _: &mut <Self::Actor as Actor>::Context) -> Poll<Self::Item, Self::Error>
+    {
+        // dropped sender means context is gone, nothing to wait for
+        match self.rx.poll() {
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            _ => Ok(Async::Ready(())),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl Heartbeat {
+    /// Check if peer has been silent for too long
+    fn expired(&self, last_activity: Instant, now: Instant) -> bool {
+        now > last_activity && now.duration_since(last_activity) >= self.timeout
+    }
+}
+
 impl<A, M, S> ToEnvelope<Syn<A>, M> for WebsocketContext<A, S>
     where A: Actor<Context=WebsocketContext<A, S>> + Handler<M>,
           M: ResponseType + Send + 'static, M::Item: Send, M::Error: Send,
@@ -233,3 +626,312 @@ impl<A, S> From<WebsocketContext<A, S>> for Body
         Body::Actor(Box::new(ctx))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Stalled;
+
+    impl Actor for Stalled {
+        type Context = WebsocketContext<Self>;
+    }
+
+    #[test]
+    fn test_try_send_high_water_mark() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.set_high_water_mark(100);
+
+        // 10 bytes frames, nobody drains the buffer
+        let mut sent = 0;
+        for _ in 0..1000 {
+            match ctx.try_send(OpCode::Binary, Binary::from(&b"01234567"[..])) {
+                Ok(()) => sent += 1,
+                Err(err) => assert_eq!(err.kind(), io::ErrorKind::WouldBlock),
+            }
+        }
+        assert_eq!(sent, 10);
+        assert_eq!(ctx.buffer_size(), 100);
+
+        // buffer is drained by the connection
+        assert_eq!(ctx.take_frames().unwrap().len(), 10);
+        assert_eq!(ctx.buffer_size(), 0);
+        assert!(ctx.try_send(OpCode::Text, Binary::from("text")).is_ok());
+        assert_eq!(ctx.buffer_size(), 6);
+    }
+
+    #[test]
+    fn test_send_capacity() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.set_high_water_mark(25);
+        assert_eq!(ctx.send_capacity(), 25);
+
+        // 10 bytes frames, nobody drains the buffer
+        ctx.try_send(OpCode::Binary, Binary::from(&b"01234567"[..])).unwrap();
+        assert_eq!(ctx.send_capacity(), 15);
+        ctx.try_send(OpCode::Binary, Binary::from(&b"01234567"[..])).unwrap();
+        assert_eq!(ctx.send_capacity(), 5);
+        ctx.try_send(OpCode::Binary, Binary::from(&b"01234567"[..])).unwrap();
+        assert_eq!(ctx.send_capacity(), 0);
+        assert!(ctx.try_send(OpCode::Binary, Binary::from(&b"01234567"[..])).is_err());
+
+        ctx.take_frames();
+        assert_eq!(ctx.send_capacity(), 25);
+    }
+
+    #[test]
+    fn test_try_send_control() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.try_send(OpCode::Ping, Binary::from("hi")).unwrap();
+        assert_eq!(written(&mut ctx), vec![Binary::from(&b"\x89\x02hi"[..])]);
+
+        // control frame limit
+        let err = ctx.try_send(OpCode::Ping, Binary::from(vec![0u8; 200])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(ctx.take_frames().is_none());
+
+        // close frame has to go through close()
+        for code in &[OpCode::Close, OpCode::Continue] {
+            let err = ctx.try_send(*code, Binary::from(&b"\x03\xe8"[..])).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert!(ctx.take_frames().is_none());
+        assert_eq!(ctx.close_state(), CloseState::Open);
+        assert!(ctx.try_send(OpCode::Text, Binary::from("text")).is_ok());
+    }
+
+    #[test]
+    fn test_fail_connection() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        let failure = Rc::new(Cell::new(None));
+        ctx.set_failure(failure.clone());
+
+        ctx.fail_connection();
+        assert!(ctx.take_frames().is_none());
+
+        failure.set(Some(CloseCode::Invalid));
+        ctx.fail_connection();
+        assert!(failure.get().is_none());
+        match ctx.take_frames().unwrap().pop() {
+            Some(ContextFrame::Chunk(Some(frame))) =>
+                assert_eq!(frame.as_ref(), &b"\x88\x02\x03\xef"[..]),
+            _ => panic!(),
+        }
+
+        // close frame already sent by the actor
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.set_failure(failure.clone());
+        ctx.close(Some((CloseCode::Normal, "")));
+        ctx.take_frames();
+        failure.set(Some(CloseCode::Size));
+        ctx.fail_connection();
+        assert!(ctx.take_frames().is_none());
+    }
+
+    fn written(ctx: &mut WebsocketContext<Stalled>) -> Vec<Binary> {
+        ctx.take_frames().map(|frames| frames.into_iter().filter_map(|frame| match frame {
+            ContextFrame::Chunk(Some(data)) => Some(data),
+            _ => None,
+        }).collect()).unwrap_or_else(Vec::new)
+    }
+
+    #[test]
+    fn test_server_initiated_close() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        let state = Rc::new(Cell::new(CloseState::Open));
+        ctx.set_close_state(state.clone());
+
+        ctx.text("before");
+        ctx.close(Some((CloseCode::Normal, "bye")));
+        assert_eq!(ctx.close_state(), CloseState::Sent);
+
+        // no data frames or second close frame after close
+        ctx.text("after");
+        ctx.binary(&b"after"[..]);
+        assert!(ctx.try_send(OpCode::Text, Binary::from("after")).is_err());
+        ctx.close(Some((CloseCode::Away, "")));
+        let frames = written(&mut ctx);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].as_ref(), &b"\x88\x05\x03\xe8bye"[..]);
+
+        // peer replies
+        state.set(state.get().received());
+        assert_eq!(ctx.close_state(), CloseState::Closed);
+        ctx.close_handshake();
+        assert!(written(&mut ctx).is_empty());
+    }
+
+    #[test]
+    fn test_client_initiated_close() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        let state = Rc::new(Cell::new(CloseState::Open));
+        ctx.set_close_state(state.clone());
+
+        let peer_close = Rc::new(Cell::new(None));
+        ctx.set_peer_close(peer_close.clone());
+
+        state.set(state.get().received());
+        peer_close.set(Some(CloseCode::Away));
+        assert_eq!(ctx.close_state(), CloseState::Received);
+
+        // status code is echoed back
+        ctx.close_handshake();
+        assert_eq!(ctx.close_state(), CloseState::Closed);
+        let frames = written(&mut ctx);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].as_ref(), &b"\x88\x02\x03\xe9"[..]);
+
+        ctx.text("after");
+        assert!(ctx.take_frames().is_none());
+    }
+
+    #[test]
+    fn test_close_reply_code() {
+        let cases: [(CloseCode, &[u8]); 4] = [
+            (CloseCode::Empty, b"\x88\x00"),
+            (CloseCode::Normal, b"\x88\x02\x03\xe8"),
+            (CloseCode::Private(4000), b"\x88\x02\x0f\xa0"),
+            (CloseCode::Reserved(1006), b"\x88\x02\x03\xea"),
+        ];
+        for &(code, reply) in cases.iter() {
+            let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+            let state = Rc::new(Cell::new(CloseState::Open));
+            ctx.set_close_state(state.clone());
+            ctx.set_peer_close(Rc::new(Cell::new(Some(code))));
+
+            state.set(state.get().received());
+            ctx.close_handshake();
+            assert_eq!(written(&mut ctx), vec![Binary::from(reply)], "{}", code);
+        }
+    }
+
+    #[test]
+    fn test_close_and_wait() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        let state = Rc::new(Cell::new(CloseState::Open));
+        ctx.set_close_state(state.clone());
+
+        let mut fut = ctx.close_and_wait(Some((CloseCode::Normal, "bye")));
+        assert_eq!(written(&mut ctx)[0].as_ref(), &b"\x88\x05\x03\xe8bye"[..]);
+        assert_eq!(ctx.close_waiters.len(), 1);
+
+        // peer echoes close frame
+        state.set(state.get().received());
+        ctx.close_handshake();
+        assert!(ctx.close_waiters.is_empty());
+        assert_eq!(fut.poll(&mut Stalled, &mut ctx), Ok(Async::Ready(())));
+    }
+
+    #[test]
+    fn test_close_and_wait_timeout() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        let mut fut = ctx.close_and_wait(None);
+        assert_eq!(ctx.close_waiters.len(), 1);
+
+        // peer never replies
+        ctx.close_timeout_elapsed();
+        assert!(ctx.close_waiters.is_empty());
+        assert_eq!(fut.poll(&mut Stalled, &mut ctx), Ok(Async::Ready(())));
+    }
+
+    #[test]
+    fn test_write_raw() {
+        let encoder = FrameEncoder::new(&WsConfig::server());
+        let frame = encoder.message("broadcast", OpCode::Text, true);
+
+        let mut first = WebsocketContext::new(HttpRequest::default(), Stalled);
+        let mut second = WebsocketContext::new(HttpRequest::default(), Stalled);
+        first.write_raw(frame.clone());
+        second.write_raw(frame.clone());
+        assert_eq!(first.buffer_size(), frame.len());
+
+        let first = written(&mut first);
+        let second = written(&mut second);
+        assert_eq!(first, vec![frame.clone()]);
+        assert_eq!(first, second);
+        assert_eq!(first[0].as_ref(), &b"\x81\x09broadcast"[..]);
+
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.close(None);
+        ctx.take_frames();
+        ctx.write_raw(frame);
+        assert!(ctx.take_frames().is_none());
+    }
+
+    #[test]
+    fn test_unsolicited_pong() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.pong("keepalive");
+        assert_eq!(written(&mut ctx), vec![Binary::from(&b"\x8a\x09keepalive"[..])]);
+
+        // control frame limit
+        let message: String = (0..125).map(|_| 'x').collect();
+        ctx.pong(&message);
+        let frames = written(&mut ctx);
+        assert_eq!(frames[0].len(), 127);
+        assert_eq!(&frames[0].as_ref()[..2], &b"\x8a\x7d"[..]);
+
+        ctx.pong(&(message + "x"));
+        assert!(ctx.take_frames().is_none());
+    }
+
+    #[test]
+    fn test_close_payload() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.close(None);
+        assert_eq!(written(&mut ctx)[0].as_ref(), &b"\x88\x00"[..]);
+
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.close(Some((CloseCode::Away, "")));
+        assert_eq!(written(&mut ctx)[0].as_ref(), &b"\x88\x02\x03\xe9"[..]);
+
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        ctx.close(Some((CloseCode::Normal, "done")));
+        assert_eq!(written(&mut ctx)[0].as_ref(), &b"\x88\x06\x03\xe8done"[..]);
+    }
+
+    #[test]
+    fn test_heartbeat_expired() {
+        let hb = Heartbeat{interval: Duration::new(5, 0), timeout: Duration::new(10, 0)};
+        let start = Instant::now();
+
+        // no traffic, connection closes once timeout elapses
+        assert!(!hb.expired(start, start + hb.interval));
+        assert!(hb.expired(start, start + hb.interval * 2));
+
+        // any inbound frame resets timer
+        let activity = start + Duration::new(8, 0);
+        assert!(!hb.expired(activity, start + hb.interval * 2));
+        assert!(!hb.expired(activity, start + hb.interval * 3));
+        assert!(hb.expired(activity, start + hb.interval * 4));
+
+        // frame received after the check started
+        assert!(!hb.expired(start + hb.interval, start));
+    }
+
+    #[test]
+    fn test_heartbeat_timeout() {
+        let mut ctx = WebsocketContext::new(HttpRequest::default(), Stalled);
+        let start = Instant::now();
+        let activity = Rc::new(Cell::new(start));
+        ctx.set_activity(activity.clone());
+        let hb = Heartbeat{interval: Duration::new(5, 0), timeout: Duration::new(12, 0)};
+
+        // peer is silent but within timeout, ping is sent and next beat scheduled
+        assert!(ctx.beat_at(hb, start + hb.interval));
+        assert_eq!(written(&mut ctx), vec![Binary::from(&b"\x89\x00"[..])]);
+        assert!(ctx.beat_at(hb, start + hb.interval * 2));
+        assert_eq!(written(&mut ctx), vec![Binary::from(&b"\x89\x00"[..])]);
+
+        // inbound frame at 8s resets activity, 15s would have timed out otherwise
+        activity.set(start + Duration::new(8, 0));
+        assert!(ctx.beat_at(hb, start + hb.interval * 3));
+        assert_eq!(written(&mut ctx), vec![Binary::from(&b"\x89\x00"[..])]);
+
+        // no traffic since, connection is closed with 1001 at 20s
+        assert!(!ctx.beat_at(hb, start + hb.interval * 4));
+        assert_eq!(ctx.close_state(), CloseState::Sent);
+        assert_eq!(written(&mut ctx),
+                   vec![Binary::from(&b"\x88\x13\x03\xe9Heartbeat timeout"[..])]);
+    }
+}
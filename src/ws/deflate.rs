@@ -0,0 +1,500 @@
+//! `permessage-deflate` extension support (RFC 7692)
+use std::cmp;
+use std::io::{Error, ErrorKind};
+use bytes::Bytes;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use ws::proto::ProtocolError;
+
+/// Trailing bytes of a sync flush, stripped by the sender
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// `permessage-deflate` compression context
+pub struct PerMessageDeflate {
+    window_bits: u8,
+    compress_no_context_takeover: bool,
+    decompress_no_context_takeover: bool,
+    compress: Compress,
+    decompress: Decompress,
+}
+
+impl PerMessageDeflate {
+
+    /// Create new context with negotiated parameters.
+    ///
+    /// `window_bits` is peer's LZ77 sliding window size, `no_context_takeover`
+    /// means compression context is reset after each message.
+    pub fn new(window_bits: u8, no_context_takeover: bool) -> PerMessageDeflate {
+        PerMessageDeflate {
+            window_bits: cmp::max(8, cmp::min(window_bits, 15)),
+            compress_no_context_takeover: no_context_takeover,
+            decompress_no_context_takeover: no_context_takeover,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+        }
+    }
+
+    /// Create new context for one side of the connection.
+    ///
+    /// Compression context is reset after each sent message if own
+    /// `no_context_takeover` parameter is set, decompression context
+    /// after each received message if peer's parameter is set.
+    pub fn with_params(params: &DeflateParams, server: bool) -> PerMessageDeflate {
+        let (window_bits, own, peer) = if server {
+            (params.client_max_window_bits,
+             params.server_no_context_takeover, params.client_no_context_takeover)
+        } else {
+            (params.server_max_window_bits,
+             params.client_no_context_takeover, params.server_no_context_takeover)
+        };
+        PerMessageDeflate {
+            compress_no_context_takeover: own,
+            decompress_no_context_takeover: peer,
+            ..PerMessageDeflate::new(window_bits, false)
+        }
+    }
+
+    /// Negotiated window bits
+    pub fn window_bits(&self) -> u8 {
+        self.window_bits
+    }
+
+    /// Are both contexts reset after each message
+    pub fn no_context_takeover(&self) -> bool {
+        self.compress_no_context_takeover && self.decompress_no_context_takeover
+    }
+
+    /// Deflate payload of a complete message.
+    ///
+    /// Trailing `00 00 FF FF` bytes of the sync flush are stripped.
+    pub fn compress(&mut self, data: &[u8]) -> Result<Bytes, Error> {
+        let mut output = Vec::with_capacity(data.len() + 64);
+        let mut consumed = 0;
+        loop {
+            if output.len() == output.capacity() {
+                let additional = output.capacity();
+                output.reserve(additional);
+            }
+            let total_in = self.compress.total_in();
+            self.compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            consumed += (self.compress.total_in() - total_in) as usize;
+
+            // sync flush is complete once compressor stops filling output buffer
+            if consumed >= data.len() && output.len() < output.capacity() {
+                break
+            }
+        }
+
+        if output.ends_with(&DEFLATE_TRAILER) {
+            let len = output.len() - DEFLATE_TRAILER.len();
+            output.truncate(len);
+        }
+        if self.compress_no_context_takeover {
+            self.compress.reset();
+        }
+        Ok(output.into())
+    }
+
+    /// Inflate payload of a complete message.
+    ///
+    /// `data` is the concatenated payload of all message fragments. Inflating
+    /// stops with `ProtocolError::MessageTooLarge` as soon as output exceeds
+    /// `max_size`. Decompression context is reset on any error.
+    pub fn decompress(&mut self, data: &[u8], max_size: usize) -> Result<Bytes, ProtocolError> {
+        match self.inflate(data, max_size) {
+            Ok(output) => {
+                if self.decompress_no_context_takeover {
+                    self.decompress.reset(false);
+                }
+                Ok(output.into())
+            }
+            Err(err) => {
+                self.decompress.reset(false);
+                Err(err)
+            }
+        }
+    }
+
+    fn inflate(&mut self, data: &[u8], max_size: usize) -> Result<Vec<u8>, ProtocolError> {
+        let mut input = Vec::with_capacity(data.len() + DEFLATE_TRAILER.len());
+        input.extend_from_slice(data);
+        input.extend_from_slice(&DEFLATE_TRAILER);
+
+        let capacity = cmp::max(data.len() * 2, 64);
+        let mut output = Vec::with_capacity(cmp::min(capacity, max_size.saturating_add(1)));
+        let mut consumed = 0;
+        loop {
+            if output.len() == output.capacity() {
+                let additional = output.capacity();
+                output.reserve(additional);
+            }
+            let total_in = self.decompress.total_in();
+            let total_out = self.decompress.total_out();
+            let status = self.decompress.decompress_vec(
+                &input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|_| ProtocolError::InvalidCompressedData)?;
+            consumed += (self.decompress.total_in() - total_in) as usize;
+
+            if output.len() > max_size {
+                return Err(ProtocolError::MessageTooLarge)
+            }
+
+            match status {
+                Status::StreamEnd => break,
+                _ => {
+                    if consumed >= input.len() && output.len() < output.capacity() {
+                        break
+                    }
+                    if self.decompress.total_in() == total_in &&
+                        self.decompress.total_out() == total_out &&
+                        output.len() < output.capacity()
+                    {
+                        return Err(ProtocolError::InvalidCompressedData)
+                    }
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Extensions accepted during `WebSocket` handshake
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct NegotiatedExtensions {
+    /// Accepted `permessage-deflate` parameters
+    pub deflate: Option<DeflateParams>,
+}
+
+/// Negotiated `permessage-deflate` parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeflateParams {
+    /// LZ77 window size used by the server to compress messages
+    pub server_max_window_bits: u8,
+    /// LZ77 window size used by the client to compress messages
+    pub client_max_window_bits: u8,
+    /// Server resets compression context after each message
+    pub server_no_context_takeover: bool,
+    /// Client resets compression context after each message
+    pub client_no_context_takeover: bool,
+}
+
+impl Default for DeflateParams {
+    fn default() -> DeflateParams {
+        DeflateParams {
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+        }
+    }
+}
+
+impl NegotiatedExtensions {
+
+    /// Select extensions from `Sec-WebSocket-Extensions` header offered by client.
+    ///
+    /// First `permessage-deflate` offer is selected, unknown, invalid
+    /// or duplicated parameters are dropped and the offer is accepted
+    /// without them. Unknown extensions are ignored.
+    pub fn parse(header: &str) -> NegotiatedExtensions {
+        let deflate = header.split(',')
+            .filter_map(|offer| {
+                let mut params = offer.split(';').map(|p| p.trim());
+                match params.next() {
+                    Some(name) if name.to_lowercase() == "permessage-deflate" =>
+                        DeflateParams::parse(params),
+                    _ => None,
+                }
+            })
+            .next();
+        NegotiatedExtensions{deflate: deflate}
+    }
+}
+
+impl DeflateParams {
+
+    fn parse<'a, I: Iterator<Item=&'a str>>(params: I) -> Option<DeflateParams> {
+        let mut result = DeflateParams::default();
+        let mut seen = Vec::new();
+
+        for param in params {
+            let mut parts = param.splitn(2, '=');
+            let name = parts.next().unwrap_or("").trim().to_lowercase();
+            let value = parts.next().map(|v| v.trim().trim_matches('"'));
+            if seen.contains(&name) {
+                debug!("Duplicated permessage-deflate parameter: {}", name);
+                continue
+            }
+
+            match (name.as_str(), value) {
+                ("server_no_context_takeover", None) =>
+                    result.server_no_context_takeover = true,
+                ("client_no_context_takeover", None) =>
+                    result.client_no_context_takeover = true,
+                ("server_max_window_bits", Some(bits)) => match window_bits(bits) {
+                    Some(bits) => result.server_max_window_bits = bits,
+                    None => {
+                        debug!("Invalid permessage-deflate parameter: {}", param);
+                        continue
+                    }
+                },
+                ("client_max_window_bits", None) => (),
+                ("client_max_window_bits", Some(bits)) => match window_bits(bits) {
+                    Some(bits) => result.client_max_window_bits = bits,
+                    None => {
+                        debug!("Invalid permessage-deflate parameter: {}", param);
+                        continue
+                    }
+                },
+                _ => {
+                    debug!("Unsupported permessage-deflate parameter: {}", param);
+                    continue
+                }
+            }
+            seen.push(name);
+        }
+        Some(result)
+    }
+
+    /// Value of `Sec-WebSocket-Extensions` response header,
+    /// window sizes are included if they are smaller than default
+    pub fn response_header(&self) -> String {
+        let mut hdr = String::from("permessage-deflate");
+        if self.server_no_context_takeover {
+            hdr.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            hdr.push_str("; client_no_context_takeover");
+        }
+        if self.server_max_window_bits < 15 {
+            hdr.push_str(&format!("; server_max_window_bits={}", self.server_max_window_bits));
+        }
+        if self.client_max_window_bits < 15 {
+            hdr.push_str(&format!("; client_max_window_bits={}", self.client_max_window_bits));
+        }
+        hdr
+    }
+}
+
+/// Window size is clamped to 8..15 range
+fn window_bits(value: &str) -> Option<u8> {
+    value.parse::<u8>().ok().map(|bits| cmp::max(8, cmp::min(bits, 15)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compress, Compression, FlushCompress};
+
+    const MAX_SIZE: usize = 1 << 20;
+
+    fn compress(c: &mut Compress, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + 64);
+        c.compress_vec(data, &mut out, FlushCompress::Sync).unwrap();
+        assert!(out.ends_with(&DEFLATE_TRAILER));
+        let len = out.len() - DEFLATE_TRAILER.len();
+        out.truncate(len);
+        out
+    }
+
+    #[test]
+    fn test_decompress() {
+        let mut c = Compress::new(Compression::default(), false);
+        let mut deflate = PerMessageDeflate::new(15, false);
+
+        let data = compress(&mut c, b"Hello, Hello, Hello");
+        let payload = deflate.decompress(&data, MAX_SIZE).unwrap();
+        assert_eq!(payload.as_ref(), &b"Hello, Hello, Hello"[..]);
+
+        // second message refers to the first one
+        let data = compress(&mut c, b"Hello, Hello, Hello");
+        let payload = deflate.decompress(&data, MAX_SIZE).unwrap();
+        assert_eq!(payload.as_ref(), &b"Hello, Hello, Hello"[..]);
+    }
+
+    #[test]
+    fn test_decompress_large() {
+        let text: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut c = Compress::new(Compression::default(), false);
+        let mut out = Vec::with_capacity(text.len() * 2);
+        c.compress_vec(&text, &mut out, FlushCompress::Sync).unwrap();
+        let len = out.len() - DEFLATE_TRAILER.len();
+        out.truncate(len);
+
+        let mut deflate = PerMessageDeflate::new(15, false);
+        let payload = deflate.decompress(&out, MAX_SIZE).unwrap();
+        assert_eq!(payload.as_ref(), &text[..]);
+    }
+
+    #[test]
+    fn test_decompress_no_context_takeover() {
+        let mut deflate = PerMessageDeflate::new(15, true);
+        for _ in 0..2 {
+            let mut c = Compress::new(Compression::default(), false);
+            let data = compress(&mut c, b"payload");
+            let payload = deflate.decompress(&data, MAX_SIZE).unwrap();
+            assert_eq!(payload.as_ref(), &b"payload"[..]);
+        }
+    }
+
+    #[test]
+    fn test_compress() {
+        let mut tx = PerMessageDeflate::new(15, false);
+        let mut rx = PerMessageDeflate::new(15, false);
+
+        for _ in 0..3 {
+            let data = tx.compress(b"Hello, Hello, Hello").unwrap();
+            assert!(!data.ends_with(&DEFLATE_TRAILER));
+            let payload = rx.decompress(&data, MAX_SIZE).unwrap();
+            assert_eq!(payload.as_ref(), &b"Hello, Hello, Hello"[..]);
+        }
+    }
+
+    #[test]
+    fn test_compress_no_context_takeover() {
+        let mut tx = PerMessageDeflate::new(15, true);
+        let first = tx.compress(b"Hello, Hello, Hello").unwrap();
+        let second = tx.compress(b"Hello, Hello, Hello").unwrap();
+        assert_eq!(first, second);
+
+        let mut rx = PerMessageDeflate::new(15, true);
+        assert_eq!(rx.decompress(&second, MAX_SIZE).unwrap().as_ref(),
+                   &b"Hello, Hello, Hello"[..]);
+    }
+
+    #[test]
+    fn test_context_takeover_params() {
+        let text = b"Hello, Hello, Hello";
+        let params = DeflateParams {
+            client_no_context_takeover: true, ..DeflateParams::default() };
+        let mut server = PerMessageDeflate::with_params(&params, true);
+        let mut client = PerMessageDeflate::with_params(&params, false);
+        assert!(!server.no_context_takeover());
+
+        // server keeps context, second message refers to the first one
+        let first = server.compress(text).unwrap();
+        let second = server.compress(text).unwrap();
+        assert!(second.len() < first.len());
+        for data in &[first, second] {
+            assert_eq!(client.decompress(data, MAX_SIZE).unwrap().as_ref(), &text[..]);
+        }
+
+        // client resets context after each message
+        let first = client.compress(text).unwrap();
+        let second = client.compress(text).unwrap();
+        assert_eq!(first, second);
+        for data in &[first, second] {
+            assert_eq!(server.decompress(data, MAX_SIZE).unwrap().as_ref(), &text[..]);
+        }
+
+        // reset decompressor can not follow context takeover
+        let mut tx = PerMessageDeflate::with_params(&DeflateParams::default(), true);
+        let mut rx = PerMessageDeflate::with_params(&params, true);
+        assert!(rx.decompress(&tx.compress(text).unwrap(), MAX_SIZE).is_ok());
+        let second = tx.compress(text).unwrap();
+        let res = rx.decompress(&second, MAX_SIZE);
+        assert!(res.map(|p| p.as_ref() != &text[..]).unwrap_or(true));
+    }
+
+    #[test]
+    fn test_decompress_bomb() {
+        // 1mb of zeros deflates to about 1kb
+        let mut tx = PerMessageDeflate::new(15, false);
+        let bomb = tx.compress(&vec![0u8; 1 << 20]).unwrap();
+        assert!(bomb.len() < 65_536);
+
+        let mut rx = PerMessageDeflate::new(15, false);
+        assert_eq!(rx.decompress(&bomb, 65_536).unwrap_err(), ProtocolError::MessageTooLarge);
+
+        // context is reset, next message is not inflated against garbage
+        let mut tx = PerMessageDeflate::new(15, false);
+        let data = tx.compress(b"Hello").unwrap();
+        assert_eq!(rx.decompress(&data, 65_536).unwrap().as_ref(), &b"Hello"[..]);
+    }
+
+    #[test]
+    fn test_decompress_invalid() {
+        let mut deflate = PerMessageDeflate::new(15, false);
+        assert!(deflate.decompress(&[0xff, 0xff, 0xff, 0xff], MAX_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let ext = NegotiatedExtensions::parse(
+            "permessage-deflate; client_max_window_bits, x-webkit-deflate-frame");
+        assert_eq!(ext.deflate, Some(DeflateParams::default()));
+        assert_eq!(ext.deflate.unwrap().response_header(), "permessage-deflate");
+
+        let ext = NegotiatedExtensions::parse(
+            "x-custom, permessage-deflate; server_no_context_takeover; \
+             client_no_context_takeover; client_max_window_bits=\"10\"");
+        let deflate = ext.deflate.unwrap();
+        assert_eq!(deflate.server_max_window_bits, 15);
+        assert_eq!(deflate.client_max_window_bits, 10);
+        assert!(deflate.server_no_context_takeover);
+        assert!(deflate.client_no_context_takeover);
+        assert_eq!(deflate.response_header(),
+                   "permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+    }
+
+    #[test]
+    fn test_negotiate_window_bits() {
+        // Chrome
+        let ext = NegotiatedExtensions::parse("permessage-deflate; client_max_window_bits");
+        let deflate = ext.deflate.unwrap();
+        assert_eq!(deflate.server_max_window_bits, 15);
+        assert_eq!(deflate.client_max_window_bits, 15);
+        assert_eq!(deflate.response_header(), "permessage-deflate");
+
+        // Firefox
+        let ext = NegotiatedExtensions::parse("permessage-deflate");
+        assert_eq!(ext.deflate.unwrap().response_header(), "permessage-deflate");
+
+        let ext = NegotiatedExtensions::parse(
+            "permessage-deflate; client_max_window_bits; server_max_window_bits=10");
+        let deflate = ext.deflate.unwrap();
+        assert_eq!(deflate.server_max_window_bits, 10);
+        assert_eq!(deflate.client_max_window_bits, 15);
+        assert_eq!(deflate.response_header(), "permessage-deflate; server_max_window_bits=10");
+
+        let ext = NegotiatedExtensions::parse(
+            "permessage-deflate; server_max_window_bits=12; client_max_window_bits=9; \
+             server_no_context_takeover");
+        let deflate = ext.deflate.unwrap();
+        assert_eq!(deflate.server_max_window_bits, 12);
+        assert_eq!(deflate.client_max_window_bits, 9);
+        assert_eq!(deflate.response_header(),
+                   "permessage-deflate; server_no_context_takeover; \
+                    server_max_window_bits=12; client_max_window_bits=9");
+
+        // out of range values are clamped
+        let ext = NegotiatedExtensions::parse(
+            "permessage-deflate; server_max_window_bits=7; client_max_window_bits=16");
+        let deflate = ext.deflate.unwrap();
+        assert_eq!(deflate.server_max_window_bits, 8);
+        assert_eq!(deflate.client_max_window_bits, 15);
+        assert_eq!(deflate.response_header(), "permessage-deflate; server_max_window_bits=8");
+    }
+
+    #[test]
+    fn test_negotiate_unsupported_params() {
+        assert_eq!(NegotiatedExtensions::parse(""), NegotiatedExtensions::default());
+        assert_eq!(NegotiatedExtensions::parse("x-webkit-deflate-frame").deflate, None);
+
+        // unsupported, invalid and duplicated parameters are dropped
+        let ext = NegotiatedExtensions::parse("permessage-deflate; unknown");
+        assert_eq!(ext.deflate, Some(DeflateParams::default()));
+        let ext = NegotiatedExtensions::parse("permessage-deflate; client_max_window_bits=x");
+        assert_eq!(ext.deflate, Some(DeflateParams::default()));
+        let ext = NegotiatedExtensions::parse("permessage-deflate; server_max_window_bits");
+        assert_eq!(ext.deflate, Some(DeflateParams::default()));
+        let ext = NegotiatedExtensions::parse(
+            "permessage-deflate; server_max_window_bits=10; server_max_window_bits=9");
+        let deflate = ext.deflate.unwrap();
+        assert_eq!(deflate.server_max_window_bits, 10);
+        assert_eq!(deflate.response_header(), "permessage-deflate; server_max_window_bits=10");
+        let ext = NegotiatedExtensions::parse("permessage-deflate; client_no_context_takeover=1");
+        assert_eq!(ext.deflate.unwrap().response_header(), "permessage-deflate");
+    }
+}
@@ -1,362 +1,3382 @@
-use std::{fmt, mem};
-use std::io::{Error, ErrorKind};
+use std::{cmp, fmt, io, str};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::Error;
 use std::iter::FromIterator;
-use bytes::{BytesMut, BufMut};
-use byteorder::{ByteOrder, BigEndian, NetworkEndian};
-use rand;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::{Bytes, BytesMut, BufMut};
+use byteorder::{ByteOrder, NetworkEndian};
+use rand::{IsaacRng, Rng, SeedableRng, StdRng};
+use rand::jitter::JitterRng;
 
 use body::Binary;
-use ws::proto::{OpCode, CloseCode};
-use ws::mask::apply_mask;
+use ws::proto::{OpCode, CloseCode, InvalidOpCode, ProtocolError, is_valid_close_code};
+use ws::mask::{apply_mask, apply_mask_offset};
+use ws::deflate::PerMessageDeflate;
+use ws::config::{WsConfig, ControlFrameTooLongPolicy};
+use ws::codec::{self, FrameHeader, header_len};
+
+pub use ws::codec::{RSV1, RSV2, RSV3};
 
 /// A struct representing a `WebSocket` frame.
-#[derive(Debug)]
-pub(crate) struct Frame {
+///
+/// Frames are equal if all flags, opcode, masking key and payload content are equal.
+#[derive(Debug, PartialEq)]
+pub struct Frame {
     finished: bool,
     rsv1: bool,
     rsv2: bool,
     rsv3: bool,
     opcode: OpCode,
     payload: Binary,
+    mask: Option<[u8; 4]>,
 }
 
 impl Frame {
 
-    /// Destruct frame
+    /// Is this the final fragment of a message
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Frame opcode
+    #[inline]
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    /// Is this a control frame: Close, Ping or Pong
+    #[inline]
+    pub fn is_control(&self) -> bool {
+        self.opcode.is_control()
+    }
+
+    /// Frame payload
+    #[inline]
+    pub fn payload(&self) -> &Binary {
+        &self.payload
+    }
+
+    /// Masking key of still masked payload.
+    ///
+    /// Set only for masked frames parsed with `WsConfig::unmask` disabled.
+    #[inline]
+    pub fn mask(&self) -> Option<[u8; 4]> {
+        self.mask
+    }
+
+    /// Destruct frame, still masked payload is unmasked
     pub fn unpack(self) -> (bool, OpCode, Binary) {
-        (self.finished, self.opcode, self.payload)
+        (self.finished, self.opcode, unmask_payload(self.payload, self.mask))
+    }
+
+    /// Destruct frame, reserved bits and masking key are preserved
+    pub fn unpack_full(self) -> FrameParts {
+        FrameParts {
+            finished: self.finished,
+            rsv1: self.rsv1,
+            rsv2: self.rsv2,
+            rsv3: self.rsv3,
+            opcode: self.opcode,
+            payload: self.payload,
+            mask: self.mask,
+        }
     }
 
     /// Create a new Close control frame.
+    ///
+    /// Close payload is limited to 125 bytes, reason longer than 123 bytes
+    /// is truncated at the last character boundary which fits.
     #[inline]
     pub fn close(code: CloseCode, reason: &str, genmask: bool) -> Binary {
-        let raw: [u8; 2] = unsafe {
-            let u: u16 = code.into();
-            mem::transmute(u.to_be())
-        };
+        let u: u16 = code.into();
+        let raw: [u8; 2] = [(u >> 8) as u8, u as u8];
 
         let payload = if let CloseCode::Empty = code {
             Vec::new()
         } else {
             Vec::from_iter(
                 raw[..].iter()
-                    .chain(reason.as_bytes().iter())
+                    .chain(truncate_reason(reason).as_bytes().iter())
                     .cloned())
         };
 
-        Frame::message(payload, OpCode::Close, true, genmask)
+        Frame::encode_control(OpCode::Close, &payload, genmask)
+            .expect("Close payload fits into control frame")
     }
 
-    /// Parse the input stream into a frame.
-    pub fn parse(buf: &mut BytesMut, server: bool) -> Result<Option<Frame>, Error> {
-        let mut idx = 2;
-        let mut size = buf.len();
+    /// Create a new Ping control frame.
+    ///
+    /// Control frame payload is limited to 125 bytes,
+    /// larger payload is rejected with `ProtocolError::ControlFrameTooLong`.
+    pub fn ping<B: Into<Binary>>(payload: B, genmask: bool) -> Result<Binary, ProtocolError> {
+        Frame::control(payload.into(), OpCode::Ping, genmask)
+    }
 
-        if size < 2 {
-            return Ok(None)
+    /// Create a new masked Ping frame, i.e. client side keepalive.
+    ///
+    /// Payload larger than 125 bytes is rejected
+    /// with `ProtocolError::ControlFrameTooLong`.
+    pub fn client_ping<B: Into<Binary>>(payload: B) -> Result<Binary, ProtocolError> {
+        Frame::control(payload.into(), OpCode::Ping, true)
+    }
+
+    /// Create a new Pong control frame.
+    ///
+    /// Control frame payload is limited to 125 bytes,
+    /// larger payload is rejected with `ProtocolError::ControlFrameTooLong`.
+    pub fn pong<B: Into<Binary>>(payload: B, genmask: bool) -> Result<Binary, ProtocolError> {
+        Frame::control(payload.into(), OpCode::Pong, genmask)
+    }
+
+    fn control(payload: Binary, code: OpCode, genmask: bool) -> Result<Binary, ProtocolError> {
+        Frame::encode_control(code, payload.as_ref(), genmask)
+    }
+
+    /// Generate binary representation of a control frame.
+    ///
+    /// Control frames always fit into 2 byte header, frame is assembled
+    /// in a stack buffer without length encoding branches of
+    /// `Frame::message()`. Data opcodes are rejected with
+    /// `ProtocolError::InvalidOpcode`, payload larger than 125 bytes
+    /// with `ProtocolError::ControlFrameTooLong`.
+    pub fn encode_control(code: OpCode, payload: &[u8], genmask: bool)
+                          -> Result<Binary, ProtocolError>
+    {
+        let opcode: u8 = code.into();
+        if !code.is_control() {
+            return Err(ProtocolError::InvalidOpcode(InvalidOpCode(opcode)))
         }
-        size -= 2;
-        let first = buf[0];
-        let second = buf[1];
-        let finished = first & 0x80 != 0;
-
-        // check masking
-        let masked = second & 0x80 != 0;
-        if !masked && server {
-            return Err(Error::new(
-                ErrorKind::Other, "Received an unmasked frame from client"))
-        } else if masked && !server {
-            return Err(Error::new(
-                ErrorKind::Other, "Received a masked frame from server"))
-        }
-
-        let rsv1 = first & 0x40 != 0;
-        let rsv2 = first & 0x20 != 0;
-        let rsv3 = first & 0x10 != 0;
-        let opcode = OpCode::from(first & 0x0F);
-        let len = second & 0x7F;
-
-        let length = if len == 126 {
-            if size < 2 {
-                return Ok(None)
-            }
-            let len = NetworkEndian::read_uint(&buf[idx..], 2) as usize;
-            size -= 2;
-            idx += 2;
-            len
-        } else if len == 127 {
-            if size < 8 {
-                return Ok(None)
-            }
-            let len = NetworkEndian::read_uint(&buf[idx..], 8) as usize;
-            size -= 8;
-            idx += 8;
-            len
+        let len = payload.len();
+        if len > 125 {
+            return Err(ProtocolError::ControlFrameTooLong(len))
+        }
+
+
+        let mut buf = [0u8; 131];
+        buf[0] = 0x80 | opcode;
+        let idx = if genmask {
+            let mask = random_mask();
+            buf[1] = 0x80 | len as u8;
+            buf[2..6].copy_from_slice(&mask);
+            buf[6..6+len].copy_from_slice(payload);
+            apply_mask(&mut buf[6..6+len], &mask);
+            6
         } else {
-            len as usize
+            buf[1] = len as u8;
+            buf[2..2+len].copy_from_slice(payload);
+            2
         };
+        Ok(Bytes::from(&buf[..idx+len]).into())
+    }
 
-        let mask = if server {
-            if size < 4 {
-                return Ok(None)
-            } else {
-                let mut mask_bytes = [0u8; 4];
-                size -= 4;
-                mask_bytes.copy_from_slice(&buf[idx..idx+4]);
-                idx += 4;
-                Some(mask_bytes)
+    /// Close code and reason of a Close frame.
+    ///
+    /// Returns `None` for other frames and for Close frames without
+    /// status code. Reserved codes 1005, 1006 and 1015 are reported as
+    /// `CloseCode::Reserved`, other codes not allowed on the wire
+    /// as `CloseCode::Protocol`.
+    pub fn close_reason(&self) -> Option<(CloseCode, String)> {
+        if self.opcode != OpCode::Close {
+            return None
+        }
+        match self.mask {
+            Some(_) => {
+                let payload = unmask_payload(self.payload.clone(), self.mask);
+                parse_close_payload(payload.as_ref())
             }
+            None => parse_close_payload(self.payload.as_ref()),
+        }
+    }
+
+    /// Parse the input stream into a frame.
+    ///
+    /// Frames with payload length larger than `config.max_frame_size` are
+    /// rejected right after the length field is read, before any payload
+    /// is buffered. Frames with reserved bits not enabled by `config`
+    /// are rejected as well. Oversized ping and pong frames are handled
+    /// according to `config.control_frame_policy`.
+    pub fn parse(buf: &mut BytesMut, config: &WsConfig)
+                 -> Result<Option<Frame>, ProtocolError>
+    {
+        let server = Frame::expects_mask(buf, config);
+        let (bits, max_size) = (config.reserved_bits(), config.max_frame_size);
+        let res = if config.unmask {
+            // payload is unmasked in place
+            codec::decode(&mut buf[..], server, bits, max_size)?
+                .map(|(header, idx)| (FrameHeader{mask: None, ..header}, idx))
         } else {
-            None
+            codec::decode_masked(buf, server, bits, max_size)?
+        };
+        let (header, idx) = match Frame::check_length_encoding(buf, res, config)? {
+            Some(header) => header,
+            None => return Ok(None),
         };
 
-        if size < length {
+        // get body
+        buf.split_to(idx);
+        let data = buf.split_to(header.length);
+        Frame::parse_payload(header, data, config).map(Some)
+    }
+
+    /// Parse the frame at the start of the buffer without consuming it.
+    ///
+    /// Returns the frame and its encoded length, caller advances
+    /// the buffer. Payload is copied out of the buffer.
+    pub fn peek(buf: &[u8], config: &WsConfig)
+                -> Result<Option<(Frame, usize)>, ProtocolError>
+    {
+        let (header, idx) = match Frame::parse_header(buf, config)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let len = idx + header.length;
+        if buf.len() < len {
             return Ok(None)
         }
 
-        // get body
-        buf.split_to(idx);
-        let mut data = if length > 0 {
-            buf.split_to(length)
+        let data = BytesMut::from(&buf[idx..len]);
+        Frame::parse_payload(header, data, config).map(|frame| Some((frame, len)))
+    }
+
+    /// Convert frame back to binary representation.
+    ///
+    /// Final fragment flag, reserved bits, opcode and payload are preserved.
+    /// Frame with still masked payload is encoded with its original masking key.
+    pub fn into_bytes(self, genmask: bool) -> Binary {
+        let builder = FrameBuilder::new()
+            .opcode(self.opcode)
+            .finished(self.finished)
+            .rsv1(self.rsv1)
+            .rsv2(self.rsv2)
+            .rsv3(self.rsv3);
+        if let Some(mask) = self.mask {
+            let mut payload = self.payload.as_ref().to_vec();
+            apply_mask(&mut payload, &mask);
+            builder.mask_key(mask).payload(payload).build()
         } else {
-            BytesMut::new()
+            builder.mask(genmask).payload(self.payload).build()
+        }
+    }
+
+    /// Parse all complete frames from the input stream.
+    ///
+    /// Parsing stops at the first incomplete frame, its bytes
+    /// are left in the buffer.
+    pub fn parse_all(buf: &mut BytesMut, config: &WsConfig)
+                     -> Result<Vec<Frame>, ProtocolError>
+    {
+        let mut frames = Vec::new();
+        while let Some(frame) = Frame::parse(buf, config)? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    /// Parse all complete frames from captured stream, i.e. for offline analysis.
+    ///
+    /// Returns frames and the number of trailing bytes
+    /// which form incomplete frame.
+    pub fn parse_all_from_slice(data: &[u8], config: &WsConfig)
+                                -> Result<(Vec<Frame>, usize), ProtocolError>
+    {
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while let Some((frame, len)) = Frame::peek(&data[offset..], config)? {
+            frames.push(frame);
+            offset += len;
+        }
+        Ok((frames, data.len() - offset))
+    }
+
+    /// Parse frame header, returns header and its size in bytes
+    fn parse_header(buf: &[u8], config: &WsConfig)
+                    -> Result<Option<(FrameHeader, usize)>, ProtocolError>
+    {
+        let res = codec::decode_header(
+            buf, Frame::expects_mask(buf, config), config.reserved_bits(), config.max_frame_size)?;
+        Frame::check_length_encoding(buf, res, config)
+    }
+
+    /// Server side expects masked frames
+    fn expects_mask(buf: &[u8], config: &WsConfig) -> bool {
+        // unmasked client frame is parsed the way client parses server frames
+        let unmasked = buf.len() >= 2 && buf[1] & 0x80 == 0;
+        config.server && !(unmasked && config.allow_unmasked_client)
+    }
+
+    /// Reject length encoded in more bytes than necessary, if configured
+    fn check_length_encoding(buf: &[u8], res: Option<(FrameHeader, usize)>, config: &WsConfig)
+                             -> Result<Option<(FrameHeader, usize)>, ProtocolError>
+    {
+        if let Some((ref header, _)) = res {
+            if config.strict_length_encoding {
+                let minimal = match buf[1] & 0x7F {
+                    126 => header.length >= 126,
+                    127 => header.length > 65_535,
+                    _ => true,
+                };
+                if !minimal {
+                    return Err(ProtocolError::NonMinimalLength)
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    fn parse_payload(header: FrameHeader, mut data: BytesMut, config: &WsConfig)
+                     -> Result<Frame, ProtocolError>
+    {
+        let opcode = match Frame::check_header(&header, config)? {
+            Some(opcode) => opcode,
+            None => return Ok(Frame {
+                payload: Binary::from(oversized_close_payload()),
+                ..Frame::default()
+            }),
+        };
+
+        // unmask in place
+        let mask = match header.mask {
+            Some(ref mask) if config.unmask => {
+                apply_mask(&mut data, mask);
+                None
+            }
+            mask => mask,
+        };
+
+        // oversized ping or pong accepted by `ControlFrameTooLongPolicy::Truncate`
+        match opcode {
+            OpCode::Ping | OpCode::Pong => data.truncate(125),
+            _ => (),
+        }
+
+        // payload shares memory with the read buffer
+        Ok(Frame {
+            finished: header.finished,
+            rsv1: header.rsv1,
+            rsv2: header.rsv2,
+            rsv3: header.rsv3,
+            opcode: opcode,
+            payload: Binary::Bytes(data.freeze()),
+            mask: mask,
+        })
+    }
+
+    /// Validate opcode and control frame length.
+    ///
+    /// Returns `None` if oversized Close frame has to be replaced
+    /// with protocol error Close frame.
+    fn check_header(header: &FrameHeader, config: &WsConfig)
+                    -> Result<Option<OpCode>, ProtocolError>
+    {
+        // Disallow bad opcode, unless reserved opcode is enabled
+        let opcode = match OpCode::try_from(header.opcode) {
+            Ok(opcode) => opcode,
+            Err(_) if config.is_reserved_opcode(header.opcode) =>
+                OpCode::Reserved(header.opcode),
+            Err(err) => return Err(ProtocolError::InvalidOpcode(err)),
         };
 
-        // Disallow bad opcode
-        if let OpCode::Bad = opcode {
-            return Err(
-                Error::new(
-                    ErrorKind::Other,
-                    format!("Encountered invalid opcode: {}", first & 0x0F)))
+        // control frames must not be fragmented
+        if opcode.is_control() && !header.finished {
+            return Err(ProtocolError::FragmentedControlFrame)
         }
 
         // control frames must have length <= 125
         match opcode {
-            OpCode::Ping | OpCode::Pong if length > 125 => {
-                return Err(
-                    Error::new(
-                        ErrorKind::Other,
-                        format!("Rejected WebSocket handshake.Received control frame with length: {}.", length)))
+            OpCode::Ping | OpCode::Pong if header.length > 125 => {
+                if config.control_frame_policy == ControlFrameTooLongPolicy::Fail {
+                    return Err(ProtocolError::ControlFrameTooLong(header.length))
+                }
+                debug!("Received {} frame with payload length {}, truncating to 125.",
+                       opcode, header.length);
             }
-            OpCode::Close if length > 125 => {
+            OpCode::Close if header.length > 125 && !config.morph_oversized_close =>
+                return Err(ProtocolError::ControlFrameTooLong(header.length)),
+            OpCode::Close if header.length > 125 => {
                 debug!("Received close frame with payload length exceeding 125. Morphing to protocol close frame.");
-                return Ok(Some(Frame::default()))
+                return Ok(None)
             }
+            OpCode::Close if header.length == 1 =>
+                return Err(ProtocolError::InvalidClosePayload),
             _ => ()
         }
-
-        // unmask
-        if let Some(ref mask) = mask {
-            apply_mask(&mut data, mask);
-        }
-
-        Ok(Some(Frame {
-            finished: finished,
-            rsv1: rsv1,
-            rsv2: rsv2,
-            rsv3: rsv3,
-            opcode: opcode,
-            payload: data.into(),
-        }))
+        Ok(Some(opcode))
     }
 
     /// Generate binary representation
     pub fn message<B: Into<Binary>>(data: B, code: OpCode,
                                     finished: bool, genmask: bool) -> Binary
     {
-        let payload = data.into();
-        let one: u8 = if finished {
-            0x80 | Into::<u8>::into(code)
-        } else {
-            code.into()
-        };
-        let payload_len = payload.len();
-        let (two, p_len) = if genmask {
-            (0x80, payload_len + 4)
-        } else {
-            (0, payload_len)
-        };
+        let mask = if genmask { Some(random_mask()) } else { None };
+        Frame::encode_message(data.into(), code, finished, mask)
+    }
 
-        let mut buf = if payload_len < 126 {
-            let mut buf = BytesMut::with_capacity(p_len + 2);
-            buf.put_slice(&[one, two | payload_len as u8]);
-            buf
-        } else if payload_len <= 65_535 {
-            let mut buf = BytesMut::with_capacity(p_len + 4);
-            buf.put_slice(&[one, two | 126]);
-            {
-                let buf_mut = unsafe{buf.bytes_mut()};
-                BigEndian::write_u16(&mut buf_mut[..2], payload_len as u16);
-            }
-            unsafe{buf.advance_mut(2)};
-            buf
-        } else {
-            let mut buf = BytesMut::with_capacity(p_len + 8);
-            buf.put_slice(&[one, two | 127]);
-            {
-                let buf_mut = unsafe{buf.bytes_mut()};
-                BigEndian::write_u64(&mut buf_mut[..8], payload_len as u64);
-            }
-            unsafe{buf.advance_mut(8)};
-            buf
-        };
+    /// Append binary representation to provided buffer.
+    ///
+    /// Unlike `Frame::message()` no new buffer is allocated
+    /// if `buf` has enough spare capacity.
+    pub fn encode_into<B: Into<Binary>>(buf: &mut BytesMut, data: B, code: OpCode,
+                                        finished: bool, genmask: bool)
+    {
+        FrameBuilder::new()
+            .opcode(code)
+            .finished(finished)
+            .mask(genmask)
+            .payload(data)
+            .build_into(buf)
+    }
 
-        if genmask {
-            let mask: [u8; 4] = rand::random();
-            unsafe {
-                {
-                    let buf_mut = buf.bytes_mut();
-                    buf_mut[..4].copy_from_slice(&mask);
-                    buf_mut[4..payload_len+4].copy_from_slice(payload.as_ref());
-                    apply_mask(&mut buf_mut[4..], &mask);
-                }
-                buf.advance_mut(payload_len + 4);
-            }
-            buf.into()
-        } else {
-            buf.put_slice(payload.as_ref());
-            buf.into()
-        }
+    /// Generate binary representation masked with provided masking key
+    pub fn message_with_mask<B: Into<Binary>>(data: B, code: OpCode,
+                                              finished: bool, mask: [u8; 4]) -> Binary
+    {
+        Frame::encode_message(data.into(), code, finished, Some(mask))
     }
-}
 
-impl Default for Frame {
-    fn default() -> Frame {
-        Frame {
-            finished: true,
+    fn encode_message(payload: Binary, code: OpCode,
+                      finished: bool, mask: Option<[u8; 4]>) -> Binary
+    {
+        let header = FrameHeader {
+            finished: finished,
             rsv1: false,
             rsv2: false,
             rsv3: false,
-            opcode: OpCode::Close,
-            payload: Binary::from(&b""[..]),
+            opcode: code.into(),
+            length: payload.len(),
+            mask: mask,
+        };
+        let mut buf = BytesMut::new();
+        encode_frame_into(&mut buf, &header, payload.as_ref());
+        buf.into()
+    }
+
+    /// Generate binary representation masked with a key
+    /// pulled from provided generator
+    pub fn message_with_generator<B, G>(data: B, code: OpCode,
+                                        finished: bool, generator: &mut G) -> Binary
+        where B: Into<Binary>, G: MaskGenerator + ?Sized
+    {
+        Frame::message_with_mask(data, code, finished, generator.next_mask())
+    }
+
+    /// Generate binary representation of a message compressed
+    /// with `permessage-deflate` context.
+    ///
+    /// Control frames are never compressed.
+    pub fn compressed_message(data: &[u8], code: OpCode, genmask: bool,
+                              deflate: &mut PerMessageDeflate) -> Result<Binary, Error>
+    {
+        let builder = FrameBuilder::new().opcode(code).mask(genmask);
+        match code {
+            OpCode::Text | OpCode::Binary =>
+                Ok(builder.rsv1(true).payload(deflate.compress(data)?).build()),
+            _ => Ok(builder.payload(Binary::from_slice(data)).build()),
         }
     }
 }
 
-impl fmt::Display for Frame {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f,
-            "
-<FRAME>
-    final: {}
-    reserved: {} {} {}
-    opcode: {}
-    payload length: {}
-    payload: 0x{}
-</FRAME>",
-               self.finished,
-               self.rsv1,
-               self.rsv2,
-               self.rsv3,
-               self.opcode,
-               self.payload.len(),
-               self.payload.as_ref().iter().map(
-                   |byte| format!("{:x}", byte)).collect::<String>())
+/// Parts of destructed frame, see `Frame::unpack_full()`
+#[derive(Debug)]
+pub struct FrameParts {
+    /// Final fragment flag
+    pub finished: bool,
+    /// First reserved bit, set for compressed messages by `permessage-deflate`
+    pub rsv1: bool,
+    /// Second reserved bit
+    pub rsv2: bool,
+    /// Third reserved bit
+    pub rsv3: bool,
+    /// Frame opcode
+    pub opcode: OpCode,
+    /// Frame payload, still masked if `mask` is set
+    pub payload: Binary,
+    /// Masking key of still masked payload, see `Frame::mask()`
+    pub mask: Option<[u8; 4]>,
+}
+
+/// Source of masking keys for client frames
+pub trait MaskGenerator {
+    /// Generate masking key for the next frame
+    fn next_mask(&mut self) -> [u8; 4];
+}
+
+/// Default mask generator, keys are produced by thread local `FallbackMask`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomMask;
+
+impl MaskGenerator for RandomMask {
+    fn next_mask(&mut self) -> [u8; 4] {
+        random_mask()
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Mask generator which prefers provided random number generator, by default
+/// `StdRng` seeded by OS. If it can not be created, i.e. OS generator is not
+/// available in sandbox, warning is logged and keys are produced by
+/// `IsaacRng` seeded from CPU timing jitter.
+pub struct FallbackMask<R> {
+    primary: Option<R>,
+    fallback: Option<IsaacRng>,
+}
 
-    #[test]
-    fn test_parse() {
-        let mut buf = BytesMut::from(&[0b00000001u8, 0b00000001u8][..]);
-        assert!(Frame::parse(&mut buf, false).unwrap().is_none());
-        buf.extend(b"1");
-        let frame = Frame::parse(&mut buf, false).unwrap().unwrap();
-        println!("FRAME: {}", frame);
-        assert!(!frame.finished);
-        assert_eq!(frame.opcode, OpCode::Text);
-        assert_eq!(frame.payload.as_ref(), &b"1"[..]);
+impl FallbackMask<StdRng> {
+    /// Create generator seeded by OS random number generator
+    pub fn new() -> FallbackMask<StdRng> {
+        FallbackMask::with_source(StdRng::new())
     }
+}
 
-    #[test]
-    fn test_parse_length0() {
-        let mut buf = BytesMut::from(&[0b00000001u8, 0b00000000u8][..]);
-        let frame = Frame::parse(&mut buf, false).unwrap().unwrap();
-        assert!(!frame.finished);
-        assert_eq!(frame.opcode, OpCode::Text);
-        assert!(frame.payload.is_empty());
+impl Default for FallbackMask<StdRng> {
+    fn default() -> FallbackMask<StdRng> {
+        FallbackMask::new()
     }
+}
 
-    #[test]
-    fn test_parse_length2() {
-        let mut buf = BytesMut::from(&[0b00000001u8, 126u8][..]);
-        assert!(Frame::parse(&mut buf, false).unwrap().is_none());
-        buf.extend(&[0u8, 4u8][..]);
-        buf.extend(b"1234");
+impl<R: Rng> FallbackMask<R> {
+    /// Create generator with fallible primary source
+    pub fn with_source(source: io::Result<R>) -> FallbackMask<R> {
+        match source {
+            Ok(rng) => FallbackMask{primary: Some(rng), fallback: None},
+            Err(err) => {
+                warn!("Random number generator is not available, \
+                       masking keys are generated by userspace generator: {}", err);
+                FallbackMask{primary: None, fallback: Some(fallback_rng())}
+            }
+        }
+    }
 
-        let frame = Frame::parse(&mut buf, false).unwrap().unwrap();
-        assert!(!frame.finished);
-        assert_eq!(frame.opcode, OpCode::Text);
-        assert_eq!(frame.payload.as_ref(), &b"1234"[..]);
+    /// Primary source failed, keys are produced by fallback generator
+    pub fn is_fallback(&self) -> bool {
+        self.primary.is_none()
     }
+}
 
-    #[test]
-    fn test_parse_length4() {
-        let mut buf = BytesMut::from(&[0b00000001u8, 127u8][..]);
-        assert!(Frame::parse(&mut buf, false).unwrap().is_none());
-        buf.extend(&[0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 4u8][..]);
-        buf.extend(b"1234");
+impl<R: Rng> MaskGenerator for FallbackMask<R> {
+    fn next_mask(&mut self) -> [u8; 4] {
+        match (&mut self.primary, &mut self.fallback) {
+            (&mut Some(ref mut rng), _) => rng.gen(),
+            (_, &mut Some(ref mut rng)) => rng.gen(),
+            _ => unreachable!(),
+        }
+    }
+}
 
-        let frame = Frame::parse(&mut buf, false).unwrap().unwrap();
-        assert!(!frame.finished);
-        assert_eq!(frame.opcode, OpCode::Text);
-        assert_eq!(frame.payload.as_ref(), &b"1234"[..]);
+/// Longest prefix of close reason which fits into control frame
+fn truncate_reason(reason: &str) -> &str {
+    if reason.len() <= 123 {
+        return reason
     }
+    let mut end = 123;
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    debug!("Close reason is truncated to {} bytes", end);
+    &reason[..end]
+}
 
-    #[test]
-    fn test_parse_frame_mask() {
-        let mut buf = BytesMut::from(&[0b00000001u8, 0b10000001u8][..]);
-        buf.extend(b"0001");
-        buf.extend(b"1");
+/// Userspace generator seeded by `JitterRng`, current time
+/// is used only if timer is not precise enough for jitter entropy.
+fn fallback_rng() -> IsaacRng {
+    let seed: [u32; 8] = match JitterRng::new() {
+        Ok(mut jitter) => jitter.gen(),
+        Err(err) => {
+            warn!("Timer jitter entropy is not available, \
+                   masking keys are seeded from current time: {}", err);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)
+                .unwrap_or_else(|_| Duration::new(0, 0));
+            [now.as_secs() as u32, (now.as_secs() >> 32) as u32, now.subsec_nanos(), 0, 0, 0, 0, 0]
+        }
+    };
+    IsaacRng::from_seed(&seed[..])
+}
 
-        assert!(Frame::parse(&mut buf, false).is_err());
+thread_local!(static MASKS: RefCell<FallbackMask<StdRng>> = RefCell::new(FallbackMask::new()));
 
-        let frame = Frame::parse(&mut buf, true).unwrap().unwrap();
-        assert!(!frame.finished);
-        assert_eq!(frame.opcode, OpCode::Text);
-        assert_eq!(frame.payload, vec![1u8].into());
+/// Append encoded frame to the buffer, spare capacity of the buffer is reused
+fn encode_frame_into(buf: &mut BytesMut, header: &FrameHeader, payload: &[u8]) {
+    buf.reserve(codec::encoded_len(payload.len(), header.mask.is_some()));
+    unsafe {
+        let written = codec::encode(buf.bytes_mut(), header, payload)
+            .expect("Buffer is reserved for encoded frame");
+        buf.advance_mut(written);
     }
+}
 
-    #[test]
-    fn test_parse_frame_no_mask() {
-        let mut buf = BytesMut::from(&[0b00000001u8, 0b00000001u8][..]);
-        buf.extend(&[1u8]);
+/// Masking key for the frame which does not have one set explicitly
+fn random_mask() -> [u8; 4] {
+    MASKS.with(|masks| masks.borrow_mut().next_mask())
+}
 
-        assert!(Frame::parse(&mut buf, true).is_err());
+/// Builder for binary representation of a frame.
+///
+/// By default builds final Text frame without reserved bits,
+/// masking and payload.
+pub struct FrameBuilder {
+    opcode: OpCode,
+    finished: bool,
+    rsv1: bool,
+    rsv2: bool,
+    rsv3: bool,
+    mask: bool,
+    mask_key: Option<[u8; 4]>,
+    payload: Binary,
+}
 
-        let frame = Frame::parse(&mut buf, false).unwrap().unwrap();
-        assert!(!frame.finished);
-        assert_eq!(frame.opcode, OpCode::Text);
-        assert_eq!(frame.payload, vec![1u8].into());
+impl Default for FrameBuilder {
+    fn default() -> FrameBuilder {
+        FrameBuilder {
+            opcode: OpCode::Text,
+            finished: true,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            mask: false,
+            mask_key: None,
+            payload: Binary::from(&b""[..]),
+        }
     }
+}
 
-    #[test]
-    fn test_ping_frame() {
-        let frame = Frame::message(Vec::from("data"), OpCode::Ping, true, false);
+impl FrameBuilder {
 
-        let mut v = vec![137u8, 4u8];
-        v.extend(b"data");
-        assert_eq!(frame, v.into());
+    /// Create new frame builder
+    pub fn new() -> FrameBuilder {
+        FrameBuilder::default()
     }
 
-    #[test]
-    fn test_pong_frame() {
-        let frame = Frame::message(Vec::from("data"), OpCode::Pong, true, false);
+    /// Set frame opcode
+    pub fn opcode(mut self, opcode: OpCode) -> Self {
+        self.opcode = opcode;
+        self
+    }
 
-        let mut v = vec![138u8, 4u8];
-        v.extend(b"data");
-        assert_eq!(frame, v.into());
+    /// Set final fragment flag
+    pub fn finished(mut self, finished: bool) -> Self {
+        self.finished = finished;
+        self
     }
 
-    #[test]
-    fn test_close_frame() {
-        let frame = Frame::close(CloseCode::Normal, "data", false);
+    /// Set first reserved bit
+    pub fn rsv1(mut self, rsv1: bool) -> Self {
+        self.rsv1 = rsv1;
+        self
+    }
+
+    /// Set second reserved bit
+    pub fn rsv2(mut self, rsv2: bool) -> Self {
+        self.rsv2 = rsv2;
+        self
+    }
+
+    /// Set third reserved bit
+    pub fn rsv3(mut self, rsv3: bool) -> Self {
+        self.rsv3 = rsv3;
+        self
+    }
+
+    /// Mask payload with random masking key
+    pub fn mask(mut self, mask: bool) -> Self {
+        self.mask = mask;
+        self
+    }
+
+    /// Mask payload with provided masking key
+    pub fn mask_key(mut self, key: [u8; 4]) -> Self {
+        self.mask = true;
+        self.mask_key = Some(key);
+        self
+    }
+
+    /// Set frame payload
+    pub fn payload<B: Into<Binary>>(mut self, payload: B) -> Self {
+        self.payload = payload.into();
+        self
+    }
+
+    /// Generate binary representation
+    pub fn build(self) -> Binary {
+        let mut buf = BytesMut::with_capacity(self.encoded_len());
+        self.build_into(&mut buf);
+        buf.into()
+    }
+
+    /// Append binary representation to provided buffer,
+    /// spare capacity of the buffer is reused.
+    pub fn build_into(self, buf: &mut BytesMut) {
+        let FrameBuilder {
+            opcode, finished, rsv1, rsv2, rsv3, mask: genmask, mask_key, payload } = self;
+
+        let header = FrameHeader {
+            finished: finished,
+            rsv1: rsv1,
+            rsv2: rsv2,
+            rsv3: rsv3,
+            opcode: opcode.into(),
+            length: payload.len(),
+            mask: if genmask { Some(mask_key.unwrap_or_else(random_mask)) } else { None },
+        };
+
+        encode_frame_into(buf, &header, payload.as_ref());
+    }
+
+    /// Size of binary representation: 2, 4 or 10 bytes of header
+    /// depending on payload length, masking key and payload
+    fn encoded_len(&self) -> usize {
+        codec::encoded_len(self.payload.len(), self.mask)
+    }
+}
+
+/// Encodes outgoing frames according to the role of the endpoint.
+///
+/// Client frames are always masked, server frames are never masked.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameEncoder {
+    server: bool,
+}
+
+impl FrameEncoder {
+
+    /// Create new encoder for the role of `config`
+    pub fn new(config: &WsConfig) -> FrameEncoder {
+        FrameEncoder{server: config.server}
+    }
+
+    /// Encode message frame
+    pub fn message<B: Into<Binary>>(&self, data: B, code: OpCode, finished: bool) -> Binary {
+        Frame::message(data, code, finished, !self.server)
+    }
+
+    /// Encode message frame compressed with `permessage-deflate` context
+    pub fn compressed_message(&self, data: &[u8], code: OpCode,
+                              deflate: &mut PerMessageDeflate) -> Result<Binary, Error>
+    {
+        Frame::compressed_message(data, code, !self.server, deflate)
+    }
+
+    /// Encode close frame
+    pub fn close(&self, code: CloseCode, reason: &str) -> Binary {
+        Frame::close(code, reason, !self.server)
+    }
+
+    /// Encode ping, pong or close frame, see `Frame::encode_control()`
+    pub fn control(&self, code: OpCode, payload: &[u8]) -> Result<Binary, ProtocolError> {
+        Frame::encode_control(code, payload, !self.server)
+    }
+
+    /// Encode frame prepared with `FrameBuilder`
+    ///
+    /// Masking of the frame has to match the role of the encoder,
+    /// mismatch panics in debug builds and is logged in release builds.
+    pub fn build(&self, frame: FrameBuilder) -> Binary {
+        self.check_role(frame.mask);
+        frame.build()
+    }
+
+    fn check_role(&self, genmask: bool) {
+        if genmask == self.server {
+            let role = if self.server { "Server" } else { "Client" };
+            let masked = if genmask { "masked" } else { "unmasked" };
+            debug_assert!(false, "{} encodes {} frame, peer rejects it", role, masked);
+            error!("{} encodes {} frame, peer rejects it", role, masked);
+        }
+    }
+}
+
+/// Splits outgoing message into a sequence of frames.
+///
+/// First frame carries message opcode, following frames are `Continue`
+/// frames, only the last one is finished. Payload is not copied
+/// if it is backed by `Bytes`.
+pub struct FrameFragmenter {
+    payload: Binary,
+    opcode: OpCode,
+    fragment_size: usize,
+    genmask: bool,
+    pos: usize,
+    done: bool,
+}
+
+impl FrameFragmenter {
+
+    /// Create new fragmenter, each frame carries at most `fragment_size`
+    /// bytes of payload.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fragment_size` is zero.
+    pub fn new<B: Into<Binary>>(data: B, code: OpCode,
+                                fragment_size: usize, genmask: bool) -> FrameFragmenter
+    {
+        assert!(fragment_size > 0, "Fragment size must be greater than zero");
+        FrameFragmenter {
+            payload: data.into(),
+            opcode: code,
+            fragment_size: fragment_size,
+            genmask: genmask,
+            pos: 0,
+            done: false,
+        }
+    }
+
+    /// Create new fragmenter for the client role, every frame
+    /// is masked with its own random masking key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `fragment_size` is zero.
+    pub fn client<B: Into<Binary>>(data: B, code: OpCode, fragment_size: usize)
+                                   -> FrameFragmenter
+    {
+        FrameFragmenter::new(data, code, fragment_size, true)
+    }
+}
+
+impl Iterator for FrameFragmenter {
+    type Item = Binary;
+
+    fn next(&mut self) -> Option<Binary> {
+        if self.done {
+            return None
+        }
+        let start = self.pos;
+        let end = cmp::min(start + self.fragment_size, self.payload.len());
+        let code = if start == 0 { self.opcode } else { OpCode::Continue };
+        self.pos = end;
+        self.done = end == self.payload.len();
+
+        Some(Frame::message(self.payload.slice(start..end), code, self.done, self.genmask))
+    }
+}
+
+/// Receives notifications from `FrameDecoder`, i.e. for exporting metrics
+pub trait FrameObserver {
+    /// Frame is decoded, `len` is the payload length.
+    ///
+    /// Called exactly once per complete frame, before reassembly,
+    /// so fragments of a message are reported separately.
+    fn on_frame(&mut self, _opcode: OpCode, _len: usize) {}
+
+    /// Decoder encountered protocol violation
+    fn on_error(&mut self, _err: &ProtocolError) {}
+}
+
+/// Incremental frame decoder.
+///
+/// Frame header is parsed and consumed from the buffer once, after that
+/// decoder only waits for the rest of the payload.
+pub struct FrameDecoder {
+    config: WsConfig,
+    header: Option<FrameHeader>,
+    auto_pong: bool,
+    pongs: VecDeque<Binary>,
+    needed: usize,
+    header_size: usize,
+    consumed: usize,
+    observer: Option<Box<FrameObserver>>,
+}
+
+impl FrameDecoder {
+
+    /// Create new decoder, see `Frame::parse()` for parameters description
+    pub fn new(config: WsConfig) -> FrameDecoder {
+        FrameDecoder {
+            config: config,
+            header: None,
+            auto_pong: false,
+            pongs: VecDeque::new(),
+            needed: 0,
+            header_size: 0,
+            consumed: 0,
+            observer: None,
+        }
+    }
+
+    /// Get decoder settings
+    pub fn config(&self) -> &WsConfig {
+        &self.config
+    }
+
+    /// Change decoder settings
+    pub fn set_config(&mut self, config: WsConfig) {
+        self.config = config;
+    }
+
+    /// Create read buffer with `config.read_buffer_capacity` initial capacity
+    pub fn buffer(&self) -> BytesMut {
+        BytesMut::with_capacity(self.config.read_buffer_capacity)
+    }
+
+    /// Respond to received Ping frames.
+    ///
+    /// For each Ping frame decoder prepares Pong frame with the same payload,
+    /// use `FrameDecoder::pong()` to get it. Ping frames are still returned
+    /// from `FrameDecoder::decode()`.
+    pub fn set_auto_pong(&mut self, auto_pong: bool) {
+        self.auto_pong = auto_pong;
+    }
+
+    /// Get next pending Pong frame
+    pub fn pong(&mut self) -> Option<Binary> {
+        self.pongs.pop_front()
+    }
+
+    /// Install observer notified about every decoded frame and protocol error
+    pub fn set_observer<O: FrameObserver + 'static>(&mut self, observer: O) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Number of additional bytes required to complete current frame.
+    ///
+    /// Updated every time `FrameDecoder::decode()` returns `Ok(None)`.
+    /// Until frame header is complete only missing header bytes are
+    /// counted, after that missing payload bytes.
+    pub fn bytes_needed(&self) -> usize {
+        self.needed
+    }
+
+    /// Number of bytes, header included, the last decoded frame occupied in the stream
+    pub fn consumed(&self) -> usize {
+        self.consumed
+    }
+
+    /// Decode next frame from the buffer
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, ProtocolError> {
+        let res = self.decode_frame(buf);
+        if let Some(ref mut observer) = self.observer {
+            match res {
+                Ok(Some(ref frame)) => observer.on_frame(frame.opcode, frame.payload.len()),
+                Err(ref err) => observer.on_error(err),
+                Ok(None) => (),
+            }
+        }
+        res
+    }
+
+    /// Decode next frame, payload is unmasked into reusable `scratch` buffer.
+    ///
+    /// Unlike `FrameDecoder::decode()` no buffer handle is created per frame,
+    /// frame payload borrows `scratch` until the next call. Payload is copied
+    /// anyway, so it is unmasked regardless of `WsConfig::unmask`.
+    pub fn decode_into<'a>(&mut self, buf: &mut BytesMut, scratch: &'a mut Vec<u8>)
+                           -> Result<Option<FrameRef<'a>>, ProtocolError>
+    {
+        let res = self.decode_frame_into(buf, scratch);
+        if let Some(ref mut observer) = self.observer {
+            match res {
+                Ok(Some((_, opcode))) => observer.on_frame(opcode, scratch.len()),
+                Err(ref err) => observer.on_error(err),
+                Ok(None) => (),
+            }
+        }
+        let scratch: &'a Vec<u8> = scratch;
+        match res {
+            Ok(Some((header, opcode))) => {
+                Ok(Some(FrameRef {
+                    finished: header.finished,
+                    rsv1: header.rsv1,
+                    rsv2: header.rsv2,
+                    rsv3: header.rsv3,
+                    opcode: opcode,
+                    payload: &scratch[..],
+                }))
+            }
+            Ok(None) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn decode_frame_into(&mut self, buf: &mut BytesMut, scratch: &mut Vec<u8>)
+                         -> Result<Option<(FrameHeader, OpCode)>, ProtocolError>
+    {
+        let header = match self.next_header(buf)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        scratch.clear();
+        scratch.extend_from_slice(&buf[..header.length]);
+        buf.advance(header.length);
+
+        let decoded = match Frame::check_header(&header, &self.config)? {
+            Some(opcode) => {
+                if let Some(ref mask) = header.mask {
+                    apply_mask(scratch, mask);
+                }
+                match opcode {
+                    OpCode::Ping | OpCode::Pong => scratch.truncate(125),
+                    _ => (),
+                }
+                (header, opcode)
+            }
+            None => {
+                scratch.clear();
+                scratch.extend_from_slice(&oversized_close_payload());
+                (FrameHeader{finished: true, rsv1: false, rsv2: false, rsv3: false,
+                             ..header}, OpCode::Close)
+            }
+        };
+
+        if self.auto_pong && decoded.1 == OpCode::Ping {
+            self.pongs.push_back(
+                Frame::message(scratch.clone(), OpCode::Pong, true, !self.config.server));
+        }
+        Ok(Some(decoded))
+    }
+
+    fn decode_frame(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, ProtocolError> {
+        let header = match self.next_header(buf)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+
+        let data = buf.split_to(header.length);
+        let frame = Frame::parse_payload(header, data, &self.config)?;
+
+        if self.auto_pong && frame.opcode == OpCode::Ping {
+            // payload is still masked if `config.unmask` is disabled
+            let payload = unmask_payload(frame.payload.clone(), frame.mask);
+            self.pongs.push_back(
+                Frame::message(payload, OpCode::Pong, true, !self.config.server));
+        }
+        Ok(Some(frame))
+    }
+
+    /// Header of the next frame, once its payload is completely buffered
+    fn next_header(&mut self, buf: &mut BytesMut) -> Result<Option<FrameHeader>, ProtocolError> {
+        let header = if let Some(header) = self.header.take() {
+            header
+        } else {
+            match Frame::parse_header(buf, &self.config)? {
+                Some((header, idx)) => {
+                    buf.advance(idx);
+                    self.header_size = idx;
+                    header
+                }
+                None => {
+                    self.needed = header_len(buf) - buf.len();
+                    return Ok(None)
+                }
+            }
+        };
+
+        if buf.len() < header.length {
+            self.needed = header.length - buf.len();
+            self.header = Some(header);
+            return Ok(None)
+        }
+        self.needed = 0;
+        self.consumed = self.header_size + header.length;
+        Ok(Some(header))
+    }
+}
+
+/// Frame decoded by `FrameDecoder::decode_into()`, payload borrows scratch buffer
+#[derive(Debug, PartialEq)]
+pub struct FrameRef<'a> {
+    /// Final fragment flag
+    pub finished: bool,
+    /// First reserved bit
+    pub rsv1: bool,
+    /// Second reserved bit
+    pub rsv2: bool,
+    /// Third reserved bit
+    pub rsv3: bool,
+    /// Frame opcode
+    pub opcode: OpCode,
+    /// Unmasked payload
+    pub payload: &'a [u8],
+}
+
+/// Header of a frame decoded by `StreamingFrameDecoder`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameHead {
+    /// Final fragment flag
+    pub finished: bool,
+    /// First reserved bit
+    pub rsv1: bool,
+    /// Second reserved bit
+    pub rsv2: bool,
+    /// Third reserved bit
+    pub rsv3: bool,
+    /// Frame opcode
+    pub opcode: OpCode,
+    /// Payload length
+    pub length: usize,
+    /// Masking key of payload chunks, set only for masked
+    /// frames decoded with `WsConfig::unmask` disabled
+    pub mask: Option<[u8; 4]>,
+}
+
+/// Item produced by `StreamingFrameDecoder`
+#[derive(Debug, PartialEq)]
+pub enum FrameChunk {
+    /// Frame header, followed by payload chunks of `length` bytes in total
+    Head(FrameHead),
+    /// Part of the frame payload, unmasked unless `FrameHead::mask` is set
+    Payload(Bytes),
+}
+
+/// Frame decoder which does not buffer whole frame payload.
+///
+/// Frame header is returned as soon as it is received, payload is
+/// returned in chunks as they arrive. Useful for very large frames,
+/// `config.max_frame_size` still limits the frame size. Frames are
+/// validated the same way as by `Frame::parse()`.
+pub struct StreamingFrameDecoder {
+    config: WsConfig,
+    mask: Option<[u8; 4]>,
+    offset: usize,
+    remaining: usize,
+    skip: usize,
+    replacement: Option<Bytes>,
+}
+
+impl StreamingFrameDecoder {
+
+    /// Create new decoder, see `Frame::parse()` for parameters description
+    pub fn new(config: WsConfig) -> StreamingFrameDecoder {
+        StreamingFrameDecoder {
+            config: config,
+            mask: None,
+            offset: 0,
+            remaining: 0,
+            skip: 0,
+            replacement: None,
+        }
+    }
+
+    /// Payload length declared by the header of the current frame
+    pub fn declared_len(&self) -> usize {
+        self.offset + self.remaining
+    }
+
+    /// Payload bytes of the current frame returned so far.
+    ///
+    /// Equals `declared_len()` once the frame is complete.
+    pub fn received_len(&self) -> usize {
+        self.offset
+    }
+
+    /// Decode next frame header or payload chunk from the buffer
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<FrameChunk>, ProtocolError> {
+        // payload of Close frame replacing oversized one
+        if let Some(data) = self.replacement.take() {
+            self.offset += data.len();
+            self.remaining = 0;
+            return Ok(Some(FrameChunk::Payload(data)))
+        }
+        if self.remaining > 0 {
+            if buf.is_empty() {
+                return Ok(None)
+            }
+            let size = cmp::min(self.remaining, buf.len());
+            let mut data = buf.split_to(size);
+            // masking key phase continues from previous chunk
+            if let Some(ref mask) = self.mask {
+                apply_mask_offset(&mut data, mask, self.offset);
+            }
+            self.offset += size;
+            self.remaining -= size;
+            return Ok(Some(FrameChunk::Payload(data.freeze())))
+        }
+
+        // rest of truncated ping or pong, or of replaced Close frame
+        if self.skip > 0 {
+            let size = cmp::min(self.skip, buf.len());
+            buf.advance(size);
+            self.skip -= size;
+            if self.skip > 0 {
+                return Ok(None)
+            }
+        }
+
+        let (header, idx) = match Frame::parse_header(buf, &self.config)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let opcode = match Frame::check_header(&header, &self.config)? {
+            Some(opcode) => opcode,
+            None => {
+                buf.advance(idx);
+                let data = Bytes::from(oversized_close_payload());
+                self.mask = None;
+                self.offset = 0;
+                self.remaining = data.len();
+                self.skip = header.length;
+                let head = FrameHead {
+                    finished: true, rsv1: false, rsv2: false, rsv3: false,
+                    opcode: OpCode::Close, length: data.len(), mask: None,
+                };
+                self.replacement = Some(data);
+                return Ok(Some(FrameChunk::Head(head)))
+            }
+        };
+        buf.advance(idx);
+
+        // oversized ping or pong accepted by `ControlFrameTooLongPolicy::Truncate`
+        let length = match opcode {
+            OpCode::Ping | OpCode::Pong => cmp::min(header.length, 125),
+            _ => header.length,
+        };
+        self.skip = header.length - length;
+
+        // masked chunks are passed through if `config.unmask` is disabled
+        let (mask, head_mask) = if self.config.unmask {
+            (header.mask, None)
+        } else {
+            (None, header.mask)
+        };
+        self.mask = mask;
+        self.offset = 0;
+        self.remaining = length;
+
+        Ok(Some(FrameChunk::Head(FrameHead {
+            finished: header.finished,
+            rsv1: header.rsv1,
+            rsv2: header.rsv2,
+            rsv3: header.rsv3,
+            opcode: opcode,
+            length: length,
+            mask: head_mask,
+        })))
+    }
+}
+
+/// Reassembles fragmented messages out of parsed frames.
+///
+/// Control frames are passed through immediately, even if they are
+/// interleaved with fragments of a data message. Text messages are
+/// validated as fragments arrive, invalid UTF-8 is reported as
+/// `ProtocolError::InvalidUtf8`.
+pub(crate) struct FrameReassembler {
+    opcode: Option<OpCode>,
+    compressed: bool,
+    buf: BytesMut,
+    validated: usize,
+    fragments: usize,
+    config: WsConfig,
+    deflate: Option<PerMessageDeflate>,
+}
+
+impl FrameReassembler {
+
+    /// Create new reassembler, `config.max_message_size` limits size
+    /// of reassembled message
+    pub fn new(config: WsConfig) -> FrameReassembler {
+        FrameReassembler {
+            opcode: None,
+            compressed: false,
+            buf: BytesMut::new(),
+            validated: 0,
+            fragments: 0,
+            config: config,
+            deflate: None,
+        }
+    }
+
+    /// Change reassembler settings
+    pub fn set_config(&mut self, config: WsConfig) {
+        self.config = config;
+    }
+
+    /// Inflate messages with `rsv1` bit set using negotiated
+    /// `permessage-deflate` context.
+    pub fn set_deflate(&mut self, deflate: PerMessageDeflate) {
+        self.deflate = Some(deflate);
+    }
+
+    /// Feed parsed frame into reassembler.
+    ///
+    /// Returns complete message once the final fragment is received.
+    /// Payload of frames parsed with `WsConfig::unmask` disabled is unmasked.
+    pub fn push(&mut self, frame: Frame)
+                -> Result<Option<(OpCode, Binary)>, ProtocolError>
+    {
+        let rsv1 = frame.rsv1;
+        let (finished, opcode, payload) = frame.unpack();
+
+        if opcode.is_control() {
+            if rsv1 {
+                return Err(ProtocolError::CompressedControlFrame)
+            }
+            return Ok(Some((opcode, payload)))
+        }
+
+        match opcode {
+            OpCode::Text | OpCode::Binary | OpCode::Reserved(_) => {
+                if self.opcode.is_some() {
+                    self.reset();
+                    return Err(ProtocolError::UnfinishedMessage)
+                }
+                if payload.len() > self.config.max_message_size {
+                    return Err(ProtocolError::MessageTooLarge)
+                }
+                if finished {
+                    let payload = if rsv1 {
+                        self.inflate(payload.as_ref())?
+                    } else {
+                        payload
+                    };
+                    if opcode == OpCode::Text && str::from_utf8(payload.as_ref()).is_err() {
+                        return Err(ProtocolError::InvalidUtf8)
+                    }
+                    Ok(Some((opcode, payload)))
+                } else {
+                    self.opcode = Some(opcode);
+                    self.compressed = rsv1;
+                    self.fragments = 1;
+                    self.buf.extend_from_slice(payload.as_ref());
+                    self.validate(false)?;
+                    Ok(None)
+                }
+            }
+            OpCode::Continue => {
+                let op = if let Some(op) = self.opcode {
+                    op
+                } else {
+                    return Err(ProtocolError::UnexpectedContinuation)
+                };
+                if rsv1 {
+                    self.reset();
+                    return Err(ProtocolError::CompressedContinuation)
+                }
+                if self.buf.len() + payload.len() > self.config.max_message_size {
+                    self.reset();
+                    return Err(ProtocolError::MessageTooLarge)
+                }
+                self.fragments += 1;
+                if self.fragments > self.config.max_fragments_per_message {
+                    self.reset();
+                    return Err(ProtocolError::TooManyFragments)
+                }
+                self.buf.extend_from_slice(payload.as_ref());
+                self.validate(finished)?;
+
+                if finished {
+                    let buf = self.buf.take();
+                    let compressed = self.compressed;
+                    self.reset();
+                    if compressed {
+                        let payload = self.inflate(buf.as_ref())?;
+                        if op == OpCode::Text && str::from_utf8(payload.as_ref()).is_err() {
+                            return Err(ProtocolError::InvalidUtf8)
+                        }
+                        Ok(Some((op, payload)))
+                    } else {
+                        Ok(Some((op, buf.into())))
+                    }
+                } else {
+                    Ok(None)
+                }
+            }
+            // control frames are delivered above, bad opcode is rejected by parser
+            _ => Err(ProtocolError::Internal),
+        }
+    }
+
+    /// Drop fragmented message in progress
+    fn reset(&mut self) {
+        self.opcode = None;
+        self.compressed = false;
+        self.validated = 0;
+        self.fragments = 0;
+        self.buf.clear();
+    }
+
+    /// Validate buffered text fragments, incomplete trailing UTF-8 sequence
+    /// is allowed unless this is the final fragment.
+    fn validate(&mut self, finished: bool) -> Result<(), ProtocolError> {
+        if self.opcode != Some(OpCode::Text) || self.compressed {
+            return Ok(())
+        }
+        let res = str::from_utf8(&self.buf[self.validated..]).map(|_| ());
+        match res {
+            Ok(()) => {
+                self.validated = self.buf.len();
+                Ok(())
+            }
+            Err(e) => {
+                if !finished && e.error_len().is_none() {
+                    self.validated += e.valid_up_to();
+                    Ok(())
+                } else {
+                    self.reset();
+                    Err(ProtocolError::InvalidUtf8)
+                }
+            }
+        }
+    }
+
+    fn inflate(&mut self, data: &[u8]) -> Result<Binary, ProtocolError> {
+        if let Some(ref mut deflate) = self.deflate {
+            deflate.decompress(data, self.config.max_message_size).map(|payload| payload.into())
+        } else {
+            Err(ProtocolError::UnnegotiatedCompression)
+        }
+    }
+}
+
+/// Item produced by `MessageDecoder`
+#[derive(Debug)]
+pub enum Decoded {
+    /// Complete data message, reassembled from all its fragments
+    Message(OpCode, Binary),
+    /// Control frame, delivered as soon as it arrives, even
+    /// in the middle of fragmented message
+    Control(Frame),
+}
+
+/// Decodes frames and reassembles fragmented data messages.
+///
+/// Control frames never enter reassembly, so they can not
+/// be mistaken for a fragment of the message in progress.
+pub struct MessageDecoder {
+    decoder: FrameDecoder,
+    reassembler: FrameReassembler,
+}
+
+impl MessageDecoder {
+
+    /// Create new decoder, see `Frame::parse()` for parameters description
+    pub fn new(config: WsConfig) -> MessageDecoder {
+        MessageDecoder {
+            decoder: FrameDecoder::new(config),
+            reassembler: FrameReassembler::new(config),
+        }
+    }
+
+    /// Inflate messages with `rsv1` bit set using negotiated
+    /// `permessage-deflate` context.
+    pub fn set_deflate(&mut self, deflate: PerMessageDeflate) {
+        self.reassembler.set_deflate(deflate);
+    }
+
+    /// Install observer notified about every decoded frame and protocol error
+    pub fn set_observer<O: FrameObserver + 'static>(&mut self, observer: O) {
+        self.decoder.set_observer(observer);
+    }
+
+    /// Decode next message or control frame from the buffer
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Decoded>, ProtocolError> {
+        while let Some(frame) = self.decoder.decode(buf)? {
+            if frame.opcode.is_control() {
+                if frame.rsv1 {
+                    return Err(ProtocolError::CompressedControlFrame)
+                }
+                return Ok(Some(Decoded::Control(frame)))
+            }
+            if let Some((opcode, payload)) = self.reassembler.push(frame)? {
+                return Ok(Some(Decoded::Message(opcode, payload)))
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Frame {
+        Frame {
+            finished: true,
+            rsv1: false,
+            rsv2: false,
+            rsv3: false,
+            opcode: OpCode::Close,
+            payload: Binary::from(&b""[..]),
+            mask: None,
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+            "
+<FRAME>
+    final: {}
+    reserved: {} {} {}
+    opcode: {}
+    payload length: {}
+    payload: 0x{}
+</FRAME>",
+               self.finished,
+               self.rsv1,
+               self.rsv2,
+               self.rsv3,
+               self.opcode,
+               self.payload.len(),
+               self.payload.as_ref().iter().map(
+                   |byte| format!("{:x}", byte)).collect::<String>())
+    }
+}
+
+/// Remove masking from still masked payload
+fn unmask_payload(payload: Binary, mask: Option<[u8; 4]>) -> Binary {
+    match mask {
+        Some(ref mask) => {
+            let mut data = Vec::from(payload.as_ref());
+            apply_mask(&mut data, mask);
+            Binary::from(data)
+        }
+        None => payload,
+    }
+}
+
+/// Payload of Close frame replacing oversized Close frame
+fn oversized_close_payload() -> Vec<u8> {
+    let code: u16 = CloseCode::Protocol.into();
+    let mut payload = vec![(code >> 8) as u8, code as u8];
+    payload.extend_from_slice(b"Close frame too large");
+    payload
+}
+
+/// Parse status code and reason of Close frame payload, see `Frame::close_reason()`
+pub(crate) fn parse_close_payload(payload: &[u8]) -> Option<(CloseCode, String)> {
+    if payload.len() < 2 {
+        return None
+    }
+    let code = NetworkEndian::read_u16(&payload[..2]);
+    let reason = String::from_utf8_lossy(&payload[2..]).into_owned();
+    match CloseCode::from_wire(code) {
+        CloseCode::Reserved(code) => Some((CloseCode::Reserved(code), reason)),
+        _ if !is_valid_close_code(code) =>
+            Some((CloseCode::Protocol, format!("Invalid close code: {}", code))),
+        code => Some((code, reason)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut buf = BytesMut::from(&[0b00000001u8, 0b00000001u8][..]);
+        assert!(Frame::parse(&mut buf, &WsConfig::client()).unwrap().is_none());
+        buf.extend(b"1");
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        println!("FRAME: {}", frame);
+        assert!(!frame.finished);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload.as_ref(), &b"1"[..]);
+    }
+
+    #[test]
+    fn test_accessors() {
+        let mut buf = BytesMut::from(&[0b10000010u8, 0b00000011u8][..]);
+        buf.extend(b"123");
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert!(frame.is_finished());
+        assert_eq!(frame.opcode(), OpCode::Binary);
+        assert_eq!(frame.payload().as_ref(), &b"123"[..]);
+
+        let (finished, opcode, payload) = frame.unpack();
+        assert!(finished);
+        assert_eq!(opcode, OpCode::Binary);
+        assert_eq!(payload.as_ref(), &b"123"[..]);
+    }
+
+    #[test]
+    fn test_parse_length0() {
+        let mut buf = BytesMut::from(&[0b00000001u8, 0b00000000u8][..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert!(!frame.finished);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert!(frame.payload.is_empty());
+    }
+
+    #[test]
+    fn test_parse_length2() {
+        let mut buf = BytesMut::from(&[0b00000001u8, 126u8][..]);
+        assert!(Frame::parse(&mut buf, &WsConfig::client()).unwrap().is_none());
+        buf.extend(&[0u8, 4u8][..]);
+        buf.extend(b"1234");
+
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert!(!frame.finished);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload.as_ref(), &b"1234"[..]);
+    }
+
+    #[test]
+    fn test_parse_length4() {
+        let mut buf = BytesMut::from(&[0b00000001u8, 127u8][..]);
+        assert!(Frame::parse(&mut buf, &WsConfig::client()).unwrap().is_none());
+        buf.extend(&[0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 0u8, 4u8][..]);
+        buf.extend(b"1234");
+
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert!(!frame.finished);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload.as_ref(), &b"1234"[..]);
+    }
+
+    #[test]
+    fn test_parse_length2_byte_order() {
+        // 0x0102, big endian
+        let mut buf = BytesMut::from(&[0b10000010u8, 126u8, 0x01, 0x02][..]);
+        let (header, idx) = Frame::parse_header(&buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(header.length, 258);
+        assert_eq!(idx, 4);
+
+        buf.extend(&[7u8; 258][..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.payload.len(), 258);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_parse_length8_byte_order() {
+        // 0x0000000000010000, big endian
+        let mut buf = BytesMut::from(&[0b10000010u8, 127u8][..]);
+        buf.extend(&[0u8, 0, 0, 0, 0, 0x01, 0, 0][..]);
+        let (header, idx) = Frame::parse_header(&buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(header.length, 65_536);
+        assert_eq!(idx, 10);
+
+        buf.extend(&vec![7u8; 65_536][..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.payload.len(), 65_536);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_parse_masked() {
+        let original = Frame::message_with_mask("payload", OpCode::Text, true, [1, 2, 3, 4]);
+        let config = WsConfig{unmask: false, ..WsConfig::server()};
+
+        let mut buf = BytesMut::from(original.as_ref());
+        let frame = Frame::parse(&mut buf, &config).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.mask(), Some([1, 2, 3, 4]));
+        assert_eq!(frame.payload.as_ref(), &original.as_ref()[6..]);
+
+        // original bytes are forwarded verbatim
+        assert_eq!(frame.into_bytes(false), original);
+
+        let mut buf = BytesMut::from(original.as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.mask(), None);
+        assert_eq!(frame.payload.as_ref(), &b"payload"[..]);
+    }
+
+    #[test]
+    fn test_parse_masked_unpack() {
+        let config = WsConfig{unmask: false, ..WsConfig::server()};
+        let original = Frame::message_with_mask("payload", OpCode::Text, true, [1, 2, 3, 4]);
+
+        let mut buf = BytesMut::from(original.as_ref());
+        let (_, _, payload) = Frame::parse(&mut buf, &config).unwrap().unwrap().unpack();
+        assert_eq!(payload.as_ref(), &b"payload"[..]);
+
+        let mut buf = BytesMut::from(original.as_ref());
+        let parts = Frame::parse(&mut buf, &config).unwrap().unwrap().unpack_full();
+        assert_eq!(parts.mask, Some([1, 2, 3, 4]));
+        assert_eq!(parts.payload.as_ref(), &original.as_ref()[6..]);
+
+        // close code is read from unmasked payload
+        let close = FrameBuilder::new().opcode(OpCode::Close).mask_key([5, 6, 7, 8])
+            .payload(&b"\x03\xe9bye"[..]).build();
+        let mut buf = BytesMut::from(close.as_ref());
+        let frame = Frame::parse(&mut buf, &config).unwrap().unwrap();
+        assert!(frame.mask().is_some());
+        assert_eq!(frame.close_reason(), Some((CloseCode::Away, "bye".to_owned())));
+    }
+
+    #[test]
+    fn test_parse_zero_copy() {
+        let mut buf = BytesMut::from(&[0b10000010u8, 126u8, 0u8, 200u8][..]);
+        buf.extend(&[7u8; 200][..]);
+        let ptr = buf[4..].as_ptr();
+
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.payload.len(), 200);
+        assert_eq!(frame.payload.as_ref().as_ptr(), ptr);
+
+        // masked payload is unmasked in place
+        let mut buf = BytesMut::from(&[0b10000010u8, 0b11111110u8, 0u8, 200u8][..]);
+        buf.extend(&[1u8, 2u8, 3u8, 4u8][..]);
+        buf.extend(&[0u8; 200][..]);
+        let ptr = buf[8..].as_ptr();
+
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref().as_ptr(), ptr);
+        assert_eq!(&frame.payload.as_ref()[..5], &[1u8, 2u8, 3u8, 4u8, 1u8][..]);
+    }
+
+    #[test]
+    fn test_parse_frame_mask() {
+        let mut buf = BytesMut::from(&[0b00000001u8, 0b10000001u8][..]);
+        buf.extend(b"0001");
+        buf.extend(b"1");
+
+        assert_eq!(Frame::parse(&mut buf, &WsConfig::client()).unwrap_err(),
+                   ProtocolError::MaskedServerFrame);
+
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert!(!frame.finished);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload, vec![1u8].into());
+    }
+
+    #[test]
+    fn test_parse_frame_no_mask() {
+        let mut buf = BytesMut::from(&[0b00000001u8, 0b00000001u8][..]);
+        buf.extend(&[1u8]);
+
+        assert_eq!(Frame::parse(&mut buf, &WsConfig::server()).unwrap_err(),
+                   ProtocolError::UnmaskedClientFrame);
+
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert!(!frame.finished);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload, vec![1u8].into());
+    }
+
+    #[test]
+    fn test_parse_allow_unmasked_client() {
+        let cfg = WsConfig{allow_unmasked_client: true, ..WsConfig::server()};
+        let mut buf = BytesMut::from(Frame::message("data", OpCode::Text, true, false).as_ref());
+        buf.extend(Frame::message("masked", OpCode::Text, true, true).as_ref());
+
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"data"[..]);
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"masked"[..]);
+
+        // unmasked frame split at header boundary
+        let mut decoder = FrameDecoder::new(cfg);
+        let msg = Frame::message(vec![0u8; 200], OpCode::Binary, true, false);
+        let mut buf = BytesMut::from(&msg.as_ref()[..2]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert_eq!(decoder.bytes_needed(), 2);
+        buf.extend(&msg.as_ref()[2..]);
+        assert_eq!(decoder.decode(&mut buf).unwrap().unwrap().payload.len(), 200);
+
+        // masked server frames are still rejected
+        let cfg = WsConfig{allow_unmasked_client: true, ..WsConfig::client()};
+        let mut buf = BytesMut::from(Frame::message("data", OpCode::Text, true, true).as_ref());
+        assert_eq!(Frame::parse(&mut buf, &cfg).unwrap_err(), ProtocolError::MaskedServerFrame);
+    }
+
+    #[test]
+    fn test_parse_strict_length_encoding() {
+        let mut frame = vec![0b1000_0010u8, 126, 0, 10];
+        frame.extend_from_slice(b"0123456789");
+        let strict = WsConfig{strict_length_encoding: true, ..WsConfig::client()};
+
+        let mut buf = BytesMut::from(&frame[..]);
+        let parsed = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(parsed.payload.as_ref(), &b"0123456789"[..]);
+
+        let mut buf = BytesMut::from(&frame[..]);
+        assert_eq!(Frame::parse(&mut buf, &strict).unwrap_err(), ProtocolError::NonMinimalLength);
+        assert_eq!(ProtocolError::NonMinimalLength.close_code(), CloseCode::Protocol);
+
+        // 64-bit length of 16-bit payload
+        let mut buf = BytesMut::from(&[0b1000_0010u8, 127, 0, 0, 0, 0, 0, 0, 1, 0][..]);
+        buf.extend(&[0u8; 256][..]);
+        assert_eq!(Frame::parse(&mut buf, &strict).unwrap_err(), ProtocolError::NonMinimalLength);
+
+        // minimal encodings are accepted
+        for len in &[125usize, 126, 65_535, 65_536] {
+            let cfg = WsConfig{max_frame_size: 65_536, ..strict};
+            let mut buf = BytesMut::from(
+                Frame::message(vec![0u8; *len], OpCode::Binary, true, false).as_ref());
+            assert_eq!(Frame::parse(&mut buf, &cfg).unwrap().unwrap().payload.len(), *len);
+        }
+    }
+
+    #[test]
+    fn test_parse_frame_max_size() {
+        let mut buf = BytesMut::from(&[0b00000001u8, 127u8][..]);
+        buf.extend(&[0u8, 0u8, 0u8, 1u8, 0u8, 0u8, 0u8, 0u8][..]);
+        assert_eq!(buf.len(), 10);
+
+        assert_eq!(Frame::parse(&mut buf, &WsConfig::client()).unwrap_err(),
+                   ProtocolError::FrameTooLarge);
+
+        let mut cfg = WsConfig::client();
+        cfg.max_frame_size = 1;
+        let mut buf = BytesMut::from(&[0b00000001u8, 0b00000010u8][..]);
+        buf.extend(b"12");
+        assert_eq!(Frame::parse(&mut buf, &cfg).unwrap_err(), ProtocolError::FrameTooLarge);
+
+        cfg.max_frame_size = 2;
+        let mut buf = BytesMut::from(&[0b00000001u8, 0b00000010u8][..]);
+        buf.extend(b"12");
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"12"[..]);
+
+        // oversized close frame within limit is still morphed
+        let mut buf = BytesMut::from(&[0b10001000u8, 126u8, 0u8, 200u8][..]);
+        buf.extend(&[0u8; 200][..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Close);
+        assert_eq!(frame.close_reason().unwrap().0, CloseCode::Protocol);
+    }
+
+    #[test]
+    fn test_parse_reserved_bits() {
+        let cfg = WsConfig{reserved_mask: RSV1, ..WsConfig::client()};
+        let mut buf = BytesMut::from(&[0b11000001u8, 0b00000001u8][..]);
+        buf.extend(b"1");
+        assert_eq!(Frame::parse(&mut buf, &WsConfig::client()).unwrap_err(),
+                   ProtocolError::ReservedBitSet);
+
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        assert!(frame.rsv1);
+        assert_eq!(frame.payload.as_ref(), &b"1"[..]);
+
+        let mut buf = BytesMut::from(&[0b10100001u8, 0b00000001u8][..]);
+        buf.extend(b"1");
+        assert_eq!(Frame::parse(&mut buf, &cfg).unwrap_err(), ProtocolError::ReservedBitSet);
+
+        let mut buf = BytesMut::from(&[0b10010001u8, 0b00000001u8][..]);
+        buf.extend(b"1");
+        assert_eq!(Frame::parse(&mut buf, &cfg).unwrap_err(), ProtocolError::ReservedBitSet);
+    }
+
+    #[test]
+    fn test_parse_reserved_bits_deflate() {
+        // permessage-deflate enables rsv1 only
+        let cfg = WsConfig{deflate: true, ..WsConfig::client()};
+        let build = |rsv1, rsv2, rsv3| BytesMut::from(
+            FrameBuilder::new().opcode(OpCode::Text)
+                .rsv1(rsv1).rsv2(rsv2).rsv3(rsv3).payload("1").build().as_ref());
+
+        let frame = Frame::parse(&mut build(true, false, false), &cfg).unwrap().unwrap();
+        assert!(frame.rsv1);
+        assert_eq!(Frame::parse(&mut build(true, true, false), &cfg).unwrap_err(),
+                   ProtocolError::ReservedBitSet);
+        assert_eq!(Frame::parse(&mut build(false, false, true), &cfg).unwrap_err(),
+                   ProtocolError::ReservedBitSet);
+        assert_eq!(Frame::parse(&mut build(true, false, true), &cfg).unwrap_err(),
+                   ProtocolError::ReservedBitSet);
+
+        // unless enabled by other extension
+        let cfg = WsConfig{reserved_mask: RSV3, ..cfg};
+        let frame = Frame::parse(&mut build(true, false, true), &cfg).unwrap().unwrap();
+        assert!(frame.rsv1 && frame.rsv3);
+        assert_eq!(Frame::parse(&mut build(true, true, false), &cfg).unwrap_err(),
+                   ProtocolError::ReservedBitSet);
+    }
+
+    #[test]
+    fn test_decoder() {
+        let mut decoder = FrameDecoder::new(WsConfig::client());
+        let mut buf = BytesMut::new();
+
+        // header is delivered one byte per call
+        for b in &[0b10000001u8, 126u8, 0u8] {
+            buf.extend(&[*b][..]);
+            assert!(decoder.decode(&mut buf).unwrap().is_none());
+            assert!(decoder.header.is_none());
+        }
+        buf.extend(&[4u8][..]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+
+        // header is consumed and never parsed again
+        assert!(decoder.header.is_some());
+        assert!(buf.is_empty());
+        for b in b"123" {
+            buf.extend(&[*b][..]);
+            assert!(decoder.decode(&mut buf).unwrap().is_none());
+            assert_eq!(buf.len(), 1 + (*b - b'1') as usize);
+        }
+        buf.extend(b"4");
+        let frame = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(frame.finished);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload.as_ref(), &b"1234"[..]);
+        assert!(decoder.header.is_none());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decoder_masked() {
+        let mut decoder = FrameDecoder::new(WsConfig::server());
+        let mut buf = BytesMut::from(&[0b10000001u8, 0b10000001u8][..]);
+        buf.extend(b"000");
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend(b"1");
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert!(buf.is_empty());
+        buf.extend(&[1u8][..]);
+        let frame = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &[1u8 ^ b'0'][..]);
+
+        let mut buf = BytesMut::from(&[0b10000001u8, 0b00000001u8, 1u8][..]);
+        assert_eq!(decoder.decode(&mut buf).unwrap_err(), ProtocolError::MaskedServerFrame);
+    }
+
+    #[test]
+    fn test_decoder_decode_into() {
+        let mut decoder = FrameDecoder::new(WsConfig::server());
+        decoder.set_auto_pong(true);
+        let mut scratch = Vec::with_capacity(16);
+        let ptr = scratch.as_ptr();
+
+        let mut buf = BytesMut::new();
+        for (i, payload) in ["a", "bc", "", "def"].iter().enumerate() {
+            buf.extend(Frame::message_with_mask(
+                *payload, OpCode::Binary, true, [i as u8, 2, 3, 4]).as_ref());
+        }
+        buf.extend(Frame::message_with_mask("hi", OpCode::Ping, true, [9, 8, 7, 6]).as_ref());
+        buf.extend(&Frame::message_with_mask("tail", OpCode::Text, true, [1, 2, 3, 4])
+                   .as_ref()[..5]);
+
+        for payload in &["a", "bc", "", "def"] {
+            let frame = decoder.decode_into(&mut buf, &mut scratch).unwrap().unwrap();
+            assert!(frame.finished);
+            assert_eq!(frame.opcode, OpCode::Binary);
+            assert_eq!(frame.payload, payload.as_bytes());
+        }
+        {
+            let frame = decoder.decode_into(&mut buf, &mut scratch).unwrap().unwrap();
+            assert_eq!(frame.opcode, OpCode::Ping);
+            assert_eq!(frame.payload, b"hi");
+        }
+        assert_eq!(decoder.pong(), Some(Binary::from(vec![0x8au8, 2u8, b'h', b'i'])));
+        assert!(decoder.decode_into(&mut buf, &mut scratch).unwrap().is_none());
+        assert_eq!(decoder.bytes_needed(), 5);
+
+        // scratch buffer is never reallocated
+        assert_eq!(scratch.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_decoder_auto_pong() {
+        let mut decoder = FrameDecoder::new(WsConfig::server());
+        decoder.set_auto_pong(true);
+
+        let mut buf = BytesMut::from(
+            Frame::message_with_mask("hi", OpCode::Ping, true, [1, 2, 3, 4]).as_ref());
+        let frame = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Ping);
+        assert_eq!(frame.payload.as_ref(), &b"hi"[..]);
+
+        assert_eq!(decoder.pong(), Some(Binary::from(vec![0x8au8, 2u8, b'h', b'i'])));
+        assert!(decoder.pong().is_none());
+
+        // ping payload is left masked, pong payload is not
+        let mut decoder = FrameDecoder::new(WsConfig{unmask: false, ..WsConfig::server()});
+        decoder.set_auto_pong(true);
+        let mut buf = BytesMut::from(
+            Frame::message_with_mask("hi", OpCode::Ping, true, [1, 2, 3, 4]).as_ref());
+        let frame = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.mask(), Some([1, 2, 3, 4]));
+        assert_eq!(frame.payload.as_ref(), &[b'h' ^ 1, b'i' ^ 2][..]);
+        assert_eq!(decoder.pong(), Some(Binary::from(vec![0x8au8, 2u8, b'h', b'i'])));
+
+        // client side pong is masked
+        let mut decoder = FrameDecoder::new(WsConfig::client());
+        decoder.set_auto_pong(true);
+        let mut buf = BytesMut::from(Frame::message("hi", OpCode::Ping, true, false).as_ref());
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        let mut buf = BytesMut::from(decoder.pong().unwrap().as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Pong);
+        assert_eq!(frame.payload.as_ref(), &b"hi"[..]);
+
+        // disabled by default
+        let mut decoder = FrameDecoder::new(WsConfig::client());
+        let mut buf = BytesMut::from(Frame::message("hi", OpCode::Ping, true, false).as_ref());
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert!(decoder.pong().is_none());
+    }
+
+    #[test]
+    fn test_parse_close_too_large() {
+        let mut buf = BytesMut::from(&[0b1000_1000u8, 126u8, 0u8, 200u8][..]);
+        buf.extend_from_slice(&[b'x'; 200][..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Close);
+        assert!(buf.is_empty());
+
+        let (code, _) = frame.close_reason().unwrap();
+        assert_eq!(code, CloseCode::Protocol);
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "32")]
+    fn test_parse_length_overflow() {
+        // 2^32 + 10 would be truncated to 10
+        let mut buf = BytesMut::from(&[0b0000_0001u8, 127u8][..]);
+        buf.extend_from_slice(&[0u8, 0, 0, 1, 0, 0, 0, 10]);
+        buf.extend_from_slice(b"1234567890");
+        let cfg = WsConfig{max_frame_size: usize::max_value(), ..WsConfig::client()};
+        let err = Frame::parse(&mut buf, &cfg).unwrap_err();
+        assert_eq!(err, ProtocolError::LengthOverflow);
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        let mut buf = BytesMut::from(Frame::ping("data", false).unwrap().as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Ping);
+        assert_eq!(frame.payload.as_ref(), &b"data"[..]);
+
+        let mut buf = BytesMut::from(Frame::pong("data", true).unwrap().as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Pong);
+        assert_eq!(frame.payload.as_ref(), &b"data"[..]);
+
+        assert!(Frame::ping(vec![0u8; 125], false).is_ok());
+        assert_eq!(Frame::ping(vec![0u8; 126], false),
+                   Err(ProtocolError::ControlFrameTooLong(126)));
+        assert_eq!(Frame::pong(vec![0u8; 126], true),
+                   Err(ProtocolError::ControlFrameTooLong(126)));
+    }
+
+    #[test]
+    fn test_client_ping() {
+        let ping = Frame::client_ping("keepalive").unwrap();
+        assert_eq!(ping.as_ref()[0], 0x89);
+        assert_eq!(ping.as_ref()[1], 0x80 | 9);
+
+        let mut buf = BytesMut::from(ping.as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Ping);
+        assert_eq!(frame.payload.as_ref(), &b"keepalive"[..]);
+
+        assert_eq!(Frame::client_ping(vec![0u8; 130]),
+                   Err(ProtocolError::ControlFrameTooLong(130)));
+    }
+
+    #[test]
+    fn test_arc_payload() {
+        use std::sync::Arc;
+
+        let data: Arc<[u8]> = Arc::new(*b"shared payload");
+        let frame = Frame { opcode: OpCode::Binary, payload: Binary::from(&data),
+                            ..Frame::default() };
+        let payload = frame.payload().clone();
+        assert_eq!(payload.as_ref().as_ptr(), data.as_ptr());
+        assert_eq!(Arc::strong_count(&data), 3);
+
+        let mut buf = BytesMut::from(
+            Frame::message(payload, OpCode::Binary, true, false).as_ref());
+        let parsed = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(parsed.payload, frame.payload);
+    }
+
+    #[test]
+    fn test_control_frame_policy() {
+        let ping = FrameEncoder::new(&WsConfig::client()).message(
+            vec![b'x'; 200], OpCode::Ping, true);
+
+        let mut buf = BytesMut::from(ping.as_ref());
+        assert_eq!(Frame::parse(&mut buf, &WsConfig::server()).unwrap_err(),
+                   ProtocolError::ControlFrameTooLong(200));
+
+        let cfg = WsConfig {
+            control_frame_policy: ControlFrameTooLongPolicy::Truncate, ..WsConfig::server() };
+        let mut buf = BytesMut::from(ping.as_ref());
+        buf.extend(Frame::message("next", OpCode::Text, true, true).as_ref());
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Ping);
+        assert_eq!(frame.payload.as_ref(), &[b'x'; 125][..]);
+
+        // following frame is parsed as usual
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"next"[..]);
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let ptr = buf.as_ptr();
+        Frame::encode_into(&mut buf, "first", OpCode::Text, true, false);
+        Frame::encode_into(&mut buf, vec![1u8; 300], OpCode::Binary, false, false);
+        Frame::encode_into(&mut buf, "", OpCode::Continue, true, false);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.len(), 2 + 5 + 4 + 300 + 2);
+
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert_eq!(frame.payload.as_ref(), &b"first"[..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Binary);
+        assert!(!frame.finished);
+        assert_eq!(frame.payload.as_ref(), &[1u8; 300][..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Continue);
+        assert!(frame.finished);
+        assert!(buf.is_empty());
+
+        // masked frames
+        let mut buf = BytesMut::new();
+        Frame::encode_into(&mut buf, "one", OpCode::Text, true, true);
+        Frame::encode_into(&mut buf, "two", OpCode::Text, true, true);
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"one"[..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"two"[..]);
+    }
+
+    #[test]
+    fn test_message_parse_round_trip() {
+        use rand::{Rng, SeedableRng, XorShiftRng};
+
+        let mut rng = XorShiftRng::from_seed([0x193a_6754, 0xa8a7_d469, 0x9783_0e05, 0x113b_a7bb]);
+        let boundaries = [0, 1, 124, 125, 126, 127, 65_534, 65_535, 65_536, 65_537];
+        let codes = [OpCode::Continue, OpCode::Text, OpCode::Binary, OpCode::Ping, OpCode::Pong];
+
+        for i in 0..500 {
+            let code = *rng.choose(&codes).unwrap();
+            let control = code.is_control();
+            let len = if control {
+                rng.gen_range(0, 126)
+            } else if i % 2 == 0 {
+                *rng.choose(&boundaries).unwrap()
+            } else {
+                rng.gen_range(0, 70_000)
+            };
+            let finished = control || rng.gen();
+            let payload: Vec<u8> = rng.gen_iter().take(len).collect();
+
+            // client frames are masked, server frames are not
+            let server = rng.gen();
+            let cfg = WsConfig {
+                server: !server, max_frame_size: 1 << 20, ..WsConfig::default() };
+            let msg = Frame::message(payload.clone(), code, finished, !server);
+
+            let mut buf = BytesMut::from(msg.as_ref());
+            let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+            assert_eq!(frame.opcode, code);
+            assert_eq!(frame.finished, finished);
+            assert_eq!(frame.payload.as_ref(), &payload[..]);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_length_boundaries() {
+        // payload length, length field size
+        let cases = [(125, 0), (126, 2), (65_535, 2), (65_536, 8)];
+
+        for &(len, field) in &cases {
+            for &mask in &[false, true] {
+                let payload = vec![7u8; len];
+                let expected = 2 + field + if mask { 4 } else { 0 } + len;
+                let builder = FrameBuilder::new().opcode(OpCode::Binary).mask(mask)
+                    .payload(payload.clone());
+                assert_eq!(builder.encoded_len(), expected);
+
+                // exactly sized buffer is not reallocated
+                let mut buf = BytesMut::with_capacity(expected);
+                let ptr = buf.as_ptr();
+                builder.build_into(&mut buf);
+                assert_eq!(buf.len(), expected);
+                assert_eq!(buf.as_ptr(), ptr);
+
+                let second = buf[1] & 0x7F;
+                match field {
+                    0 => assert_eq!(second as usize, len),
+                    2 => assert_eq!(second, 126),
+                    _ => assert_eq!(second, 127),
+                }
+
+                let cfg = WsConfig {
+                    server: mask, max_frame_size: 1 << 20, ..WsConfig::default() };
+                let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+                assert_eq!(frame.payload.as_ref(), &payload[..]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decoder_bytes_needed() {
+        let mut decoder = FrameDecoder::new(WsConfig::client());
+        let mut buf = BytesMut::new();
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert_eq!(decoder.bytes_needed(), 2);
+
+        buf.extend(&[0b1000_0010u8, 126]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert_eq!(decoder.bytes_needed(), 2);
+
+        // header is complete, declared payload length is 200
+        buf.extend(&[0u8, 200]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert_eq!(decoder.bytes_needed(), 200);
+
+        buf.extend(&[0u8; 150][..]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert_eq!(decoder.bytes_needed(), 50);
+
+        buf.extend(&[0u8; 50][..]);
+        assert_eq!(decoder.decode(&mut buf).unwrap().unwrap().payload.len(), 200);
+        assert_eq!(decoder.bytes_needed(), 0);
+
+        // masked frame header
+        let mut decoder = FrameDecoder::new(WsConfig::server());
+        let mut buf = BytesMut::from(&[0b1000_0001u8, 0b1000_0101, 1][..]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert_eq!(decoder.bytes_needed(), 3);
+    }
+
+    #[test]
+    fn test_parse_reserved_opcode() {
+        let mut buf = BytesMut::from(&[0b1000_0011u8, 0b0000_0001, b'1'][..]);
+        let err = Frame::parse(&mut buf, &WsConfig::client()).unwrap_err();
+        assert_eq!(err, ProtocolError::InvalidOpcode(InvalidOpCode(3)));
+
+        let cfg = WsConfig::client().reserved_opcode(3);
+        let mut buf = BytesMut::from(&[0b1000_0011u8, 0b0000_0001, b'1'][..]);
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Reserved(3));
+        assert_eq!(frame.payload.as_ref(), &b"1"[..]);
+
+        // other reserved opcodes are still rejected
+        let mut buf = BytesMut::from(&[0b1000_0100u8, 0b0000_0001, b'1'][..]);
+        let err = Frame::parse(&mut buf, &cfg).unwrap_err();
+        assert_eq!(err, ProtocolError::InvalidOpcode(InvalidOpCode(4)));
+
+        // encoded and reassembled as data frame
+        let msg = Frame::message("data", OpCode::Reserved(3), true, false);
+        assert_eq!(msg.as_ref()[0], 0b1000_0011);
+        let mut buf = BytesMut::from(msg.as_ref());
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        let mut r = FrameReassembler::new(cfg);
+        let (op, payload) = r.push(frame).unwrap().unwrap();
+        assert_eq!(op, OpCode::Reserved(3));
+        assert_eq!(payload.as_ref(), &b"data"[..]);
+    }
+
+    #[test]
+    fn test_decoder_observer() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        #[derive(Default)]
+        struct Counting {
+            frames: Vec<(OpCode, usize)>,
+            errors: Vec<ProtocolError>,
+        }
+
+        impl FrameObserver for Rc<RefCell<Counting>> {
+            fn on_frame(&mut self, opcode: OpCode, len: usize) {
+                self.borrow_mut().frames.push((opcode, len));
+            }
+            fn on_error(&mut self, err: &ProtocolError) {
+                self.borrow_mut().errors.push(*err);
+            }
+        }
+
+        let counting = Rc::new(RefCell::new(Counting::default()));
+        let mut decoder = FrameDecoder::new(WsConfig::client());
+        decoder.set_observer(Rc::clone(&counting));
+
+        let mut buf = BytesMut::from(Frame::message("hello", OpCode::Text, true, false).as_ref());
+        let len = buf.len();
+        buf.truncate(len - 1);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert!(counting.borrow().frames.is_empty());
+        buf.extend(b"o");
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert_eq!(counting.borrow().frames, vec![(OpCode::Text, 5)]);
+
+        let mut buf = BytesMut::from(Frame::message("masked", OpCode::Text, true, true).as_ref());
+        assert!(decoder.decode(&mut buf).is_err());
+        assert_eq!(counting.borrow().errors, vec![ProtocolError::MaskedServerFrame]);
+    }
+
+    #[test]
+    fn test_message_decoder_observer() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        struct Sizes(Rc<RefCell<Vec<(OpCode, usize)>>>);
+
+        impl FrameObserver for Sizes {
+            fn on_frame(&mut self, opcode: OpCode, len: usize) {
+                self.0.borrow_mut().push((opcode, len));
+            }
+        }
+
+        let sizes = Rc::new(RefCell::new(Vec::new()));
+        let mut decoder = MessageDecoder::new(WsConfig::client());
+        decoder.set_observer(Sizes(Rc::clone(&sizes)));
+
+        let mut buf = BytesMut::new();
+        Frame::encode_into(&mut buf, "Hel", OpCode::Text, false, false);
+        Frame::encode_into(&mut buf, "", OpCode::Ping, true, false);
+        Frame::encode_into(&mut buf, "lo", OpCode::Continue, true, false);
+        Frame::encode_into(&mut buf, vec![2u8; 300], OpCode::Binary, true, false);
+        Frame::encode_into(&mut buf, "ping", OpCode::Ping, true, false);
+
+        let mut decoded = 0;
+        while decoder.decode(&mut buf).unwrap().is_some() {
+            decoded += 1;
+        }
+        assert_eq!(decoded, 4);
+        assert_eq!(*sizes.borrow(),
+                   vec![(OpCode::Text, 3), (OpCode::Ping, 0), (OpCode::Continue, 2),
+                        (OpCode::Binary, 300), (OpCode::Ping, 4)]);
+    }
+
+    #[test]
+    fn test_parse_all() {
+        let mut buf = BytesMut::new();
+        Frame::encode_into(&mut buf, "one", OpCode::Text, true, false);
+        Frame::encode_into(&mut buf, vec![2u8; 200], OpCode::Binary, true, false);
+        Frame::encode_into(&mut buf, "", OpCode::Ping, true, false);
+        buf.extend(&[0b1000_0010u8, 126, 0]);
+
+        let frames = Frame::parse_all(&mut buf, &WsConfig::client()).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].payload.as_ref(), &b"one"[..]);
+        assert_eq!(frames[1].payload.len(), 200);
+        assert_eq!(frames[2].opcode, OpCode::Ping);
+        assert_eq!(&buf[..], &[0b1000_0010u8, 126, 0][..]);
+
+        assert!(Frame::parse_all(&mut buf, &WsConfig::client()).unwrap().is_empty());
+        assert_eq!(buf.len(), 3);
+    }
+
+    #[test]
+    fn test_parse_empty_masked() {
+        let frame = Frame::message_with_mask("", OpCode::Text, true, [1, 2, 3, 4]);
+        assert_eq!(frame.as_ref(), &[0b1000_0001u8, 0b1000_0000, 1, 2, 3, 4][..]);
+
+        // masking key is incomplete
+        let mut buf = BytesMut::from(&frame.as_ref()[..5]);
+        assert!(Frame::parse(&mut buf, &WsConfig::server()).unwrap().is_none());
+        assert_eq!(buf.len(), 5);
+
+        buf.extend(&frame.as_ref()[5..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert!(frame.finished);
+        assert_eq!(frame.opcode, OpCode::Text);
+        assert!(frame.payload.is_empty());
+        assert!(buf.is_empty());
+
+        let mut decoder = FrameDecoder::new(WsConfig::server());
+        let mut buf = BytesMut::from(&[0b1000_0001u8, 0b1000_0000, 1, 2, 3, 4][..]);
+        assert!(decoder.decode(&mut buf).unwrap().unwrap().payload.is_empty());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_into_bytes() {
+        let cfg = WsConfig { reserved_mask: RSV1 | RSV3, ..WsConfig::client() };
+        let msg = FrameBuilder::new().opcode(OpCode::Binary).finished(false)
+            .rsv1(true).rsv3(true).payload("data").build();
+        assert_eq!(msg.as_ref()[0], 0b0101_0010);
+
+        let mut buf = BytesMut::from(msg.as_ref());
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        assert!(frame.rsv1);
+        assert_eq!(frame.into_bytes(false), msg);
+
+        // masked for the server
+        let mut buf = BytesMut::from(msg.as_ref());
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        let mut buf = BytesMut::from(frame.into_bytes(true).as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig { server: true, ..cfg }).unwrap().unwrap();
+        assert!(!frame.finished);
+        assert!(frame.rsv1 && !frame.rsv2 && frame.rsv3);
+        assert_eq!(frame.opcode, OpCode::Binary);
+        assert_eq!(frame.payload.as_ref(), &b"data"[..]);
+    }
+
+    #[test]
+    fn test_frame_eq() {
+        let cfg = WsConfig { deflate: true, ..WsConfig::client() };
+        let plain = FrameBuilder::new().opcode(OpCode::Text).payload("data").build();
+        let compressed = FrameBuilder::new()
+            .opcode(OpCode::Text).rsv1(true).payload("data").build();
+
+        let parse = |data: &Binary| Frame::parse(
+            &mut BytesMut::from(data.as_ref()), &cfg).unwrap().unwrap();
+        assert_eq!(parse(&plain), parse(&plain));
+        assert_ne!(parse(&plain), parse(&compressed));
+
+        // payload is compared by content
+        let frame = Frame { payload: Binary::from(Vec::from("data")), ..parse(&plain) };
+        assert_eq!(frame, parse(&plain));
+    }
+
+    #[test]
+    fn test_unpack_full() {
+        let cfg = WsConfig { deflate: true, ..WsConfig::client() };
+        let msg = FrameBuilder::new().opcode(OpCode::Text).rsv1(true).payload("data").build();
+        let mut buf = BytesMut::from(msg.as_ref());
+        let parts = Frame::parse(&mut buf, &cfg).unwrap().unwrap().unpack_full();
+        assert!(parts.finished);
+        assert!(parts.rsv1);
+        assert!(!parts.rsv2);
+        assert!(!parts.rsv3);
+        assert_eq!(parts.opcode, OpCode::Text);
+        assert_eq!(parts.payload.as_ref(), &b"data"[..]);
+        assert_eq!(parts.mask, None);
+    }
+
+    #[test]
+    fn test_parse_split_mask() {
+        let msg = Frame::message_with_mask(vec![5u8; 200], OpCode::Binary, true, [1, 2, 3, 4]);
+        // header, 16-bit length and 2 of 4 mask bytes
+        let mut buf = BytesMut::from(&msg.as_ref()[..6]);
+        assert!(Frame::parse(&mut buf, &WsConfig::server()).unwrap().is_none());
+        assert_eq!(&buf[..], &msg.as_ref()[..6]);
+
+        buf.extend(&msg.as_ref()[6..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &[5u8; 200][..]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_streaming_decoder() {
+        let payload: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let msg = Frame::message_with_mask(
+            payload.clone(), OpCode::Binary, true, [0x12, 0x34, 0x56, 0x78]);
+        let cfg = WsConfig { max_frame_size: 1 << 20, ..WsConfig::server() };
+        let mut decoder = StreamingFrameDecoder::new(cfg);
+
+        let mut head = None;
+        let mut received = Vec::new();
+        let mut buf = BytesMut::new();
+        let mut pos = 0;
+        // odd sized reads, split inside of the header and masking key phase
+        for size in [3, 7, 1001, 4093, 33_333].iter().cycle() {
+            if pos >= msg.len() {
+                break
+            }
+            let end = cmp::min(pos + size, msg.len());
+            buf.extend(&msg.as_ref()[pos..end]);
+            pos = end;
+
+            while let Some(chunk) = decoder.decode(&mut buf).unwrap() {
+                match chunk {
+                    FrameChunk::Head(h) => head = Some(h),
+                    FrameChunk::Payload(data) => {
+                        assert!(head.is_some());
+                        received.extend_from_slice(&data);
+                    }
+                }
+            }
+        }
+
+        let head = head.unwrap();
+        assert!(head.finished);
+        assert_eq!(head.opcode, OpCode::Binary);
+        assert_eq!(head.length, payload.len());
+        assert_eq!(head.mask, None);
+        assert_eq!(received, payload);
+        assert!(buf.is_empty());
+
+        // next frame
+        buf.extend(Frame::message("next", OpCode::Text, true, true).as_ref());
+        match decoder.decode(&mut buf).unwrap() {
+            Some(FrameChunk::Head(h)) => assert_eq!(h.length, 4),
+            _ => panic!(),
+        }
+        assert_eq!(decoder.decode(&mut buf).unwrap(),
+                   Some(FrameChunk::Payload(Bytes::from_static(b"next"))));
+    }
+
+    #[test]
+    fn test_streaming_decoder_masked() {
+        let msg = Frame::message_with_mask("0123456789", OpCode::Text, true, [1, 2, 3, 4]);
+        let cfg = WsConfig{unmask: false, ..WsConfig::server()};
+        let mut decoder = StreamingFrameDecoder::new(cfg);
+
+        // masked chunks are passed through
+        let mut buf = BytesMut::from(&msg.as_ref()[..10]);
+        match decoder.decode(&mut buf).unwrap() {
+            Some(FrameChunk::Head(h)) => assert_eq!(h.mask, Some([1, 2, 3, 4])),
+            _ => panic!(),
+        }
+        assert_eq!(decoder.decode(&mut buf).unwrap(),
+                   Some(FrameChunk::Payload(Bytes::from(&msg.as_ref()[6..10]))));
+        buf.extend(&msg.as_ref()[10..]);
+        assert_eq!(decoder.decode(&mut buf).unwrap(),
+                   Some(FrameChunk::Payload(Bytes::from(&msg.as_ref()[10..]))));
+    }
+
+    #[test]
+    fn test_streaming_decoder_control_policy() {
+        let ping = FrameBuilder::new().opcode(OpCode::Ping).payload(vec![b'x'; 200]).build();
+        let mut buf = BytesMut::from(ping.as_ref());
+        let mut decoder = StreamingFrameDecoder::new(WsConfig::client());
+        assert_eq!(decoder.decode(&mut buf).unwrap_err(), ProtocolError::ControlFrameTooLong(200));
+
+        // truncated the same way as by `Frame::parse()`
+        let cfg = WsConfig {
+            control_frame_policy: ControlFrameTooLongPolicy::Truncate, ..WsConfig::client() };
+        let mut decoder = StreamingFrameDecoder::new(cfg);
+        let mut buf = BytesMut::from(&ping.as_ref()[..100]);
+        match decoder.decode(&mut buf).unwrap() {
+            Some(FrameChunk::Head(h)) => assert_eq!(h.length, 125),
+            _ => panic!(),
+        }
+        let mut received = Vec::new();
+        while let Some(FrameChunk::Payload(data)) = decoder.decode(&mut buf).unwrap() {
+            received.extend_from_slice(&data);
+        }
+        buf.extend(&ping.as_ref()[100..]);
+        buf.extend(Frame::message("next", OpCode::Text, true, false).as_ref());
+        if let Some(FrameChunk::Payload(data)) = decoder.decode(&mut buf).unwrap() {
+            received.extend_from_slice(&data);
+        }
+        assert_eq!(&received[..], &[b'x'; 125][..]);
+        assert_eq!(decoder.received_len(), 125);
+        match decoder.decode(&mut buf).unwrap() {
+            Some(FrameChunk::Head(h)) => assert_eq!(h.opcode, OpCode::Text),
+            _ => panic!(),
+        }
+        assert_eq!(decoder.decode(&mut buf).unwrap(),
+                   Some(FrameChunk::Payload(Bytes::from_static(b"next"))));
+    }
+
+    #[test]
+    fn test_streaming_decoder_oversized_close() {
+        let mut payload = vec![0x03u8, 0xe8u8];
+        payload.extend_from_slice(&[b'x'; 198]);
+        let close = FrameBuilder::new().opcode(OpCode::Close).payload(payload).build();
+
+        // replaced with protocol error close frame
+        let mut buf = BytesMut::from(close.as_ref());
+        buf.extend(Frame::message("next", OpCode::Text, true, false).as_ref());
+        let mut decoder = StreamingFrameDecoder::new(WsConfig::client());
+        match decoder.decode(&mut buf).unwrap() {
+            Some(FrameChunk::Head(h)) => {
+                assert_eq!(h.opcode, OpCode::Close);
+                assert_eq!(h.length, oversized_close_payload().len());
+            }
+            _ => panic!(),
+        }
+        match decoder.decode(&mut buf).unwrap() {
+            Some(FrameChunk::Payload(data)) =>
+                assert_eq!(parse_close_payload(&data),
+                           Some((CloseCode::Protocol, "Close frame too large".to_owned()))),
+            _ => panic!(),
+        }
+        match decoder.decode(&mut buf).unwrap() {
+            Some(FrameChunk::Head(h)) => assert_eq!(h.opcode, OpCode::Text),
+            _ => panic!(),
+        }
+
+        let cfg = WsConfig { morph_oversized_close: false, ..WsConfig::client() };
+        let mut buf = BytesMut::from(close.as_ref());
+        let mut decoder = StreamingFrameDecoder::new(cfg);
+        assert_eq!(decoder.decode(&mut buf).unwrap_err(), ProtocolError::ControlFrameTooLong(200));
+    }
+
+    #[test]
+    fn test_streaming_decoder_lengths() {
+        let msg = Frame::message_with_mask("0123456789", OpCode::Text, true, [1, 2, 3, 4]);
+        let mut decoder = StreamingFrameDecoder::new(WsConfig::server());
+        assert_eq!(decoder.declared_len(), 0);
+        assert_eq!(decoder.received_len(), 0);
+
+        // header and 4 bytes of payload
+        let mut buf = BytesMut::from(&msg.as_ref()[..10]);
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert_eq!(decoder.declared_len(), 10);
+        assert_eq!(decoder.received_len(), 0);
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert_eq!(decoder.declared_len(), 10);
+        assert_eq!(decoder.received_len(), 4);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+
+        buf.extend(&msg.as_ref()[10..]);
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert_eq!(decoder.declared_len(), 10);
+        assert_eq!(decoder.received_len(), 10);
+    }
+
+    #[test]
+    fn test_message_mask_alignment() {
+        let mask = [0x01, 0x02, 0x04, 0x08];
+        for len in &[1usize, 3, 7, 126] {
+            let payload: Vec<u8> = (0..*len).map(|i| i as u8).collect();
+            let msg = Frame::message_with_mask(payload.clone(), OpCode::Binary, true, mask);
+            let start = msg.len() - len;
+            assert_eq!(&msg.as_ref()[start - 4..start], &mask[..]);
+
+            // first payload byte is masked with first byte of the key
+            let masked = &msg.as_ref()[start..];
+            for (i, byte) in masked.iter().enumerate() {
+                assert_eq!(*byte, payload[i] ^ mask[i % 4]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decoder_buffer() {
+        let cfg = WsConfig{read_buffer_capacity: 16_384, ..WsConfig::client()};
+        let decoder = FrameDecoder::new(cfg);
+        assert!(decoder.buffer().capacity() >= 16_384);
+
+        let mut decoder = FrameDecoder::new(WsConfig{read_buffer_capacity: 64, ..cfg});
+        let mut buf = decoder.buffer();
+        assert!(buf.capacity() >= 64);
+        assert!(buf.capacity() < 16_384);
+
+        // buffer grows for larger frames
+        let payload = vec![1u8; 1000];
+        buf.extend(Frame::message("ping", OpCode::Ping, true, false).as_ref());
+        buf.extend(Frame::message(payload.clone(), OpCode::Binary, true, false).as_ref());
+        assert_eq!(decoder.decode(&mut buf).unwrap().unwrap().opcode, OpCode::Ping);
+        let frame = decoder.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &payload[..]);
+    }
+
+    #[test]
+    fn test_decoder_consumed() {
+        let mut decoder = FrameDecoder::new(WsConfig::client());
+        let mut buf = BytesMut::from(&[0b1000_0001u8, 0b0000_0001, b'1'][..]);
+        buf.extend(Frame::message(vec![0u8; 200], OpCode::Binary, true, false).as_ref());
+
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert_eq!(decoder.consumed(), 3);
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert_eq!(decoder.consumed(), 204);
+
+        // frame received in parts
+        let mut decoder = FrameDecoder::new(WsConfig::server());
+        let msg = Frame::message("data", OpCode::Text, true, true);
+        let mut buf = BytesMut::from(&msg.as_ref()[..7]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        buf.extend(&msg.as_ref()[7..]);
+        assert!(decoder.decode(&mut buf).unwrap().is_some());
+        assert_eq!(decoder.consumed(), 10);
+    }
+
+    #[test]
+    fn test_mask_generator() {
+        struct Counting(u8);
+
+        impl MaskGenerator for Counting {
+            fn next_mask(&mut self) -> [u8; 4] {
+                self.0 += 1;
+                [self.0; 4]
+            }
+        }
+
+        let mut gen = Counting(0);
+        for i in 1..4u8 {
+            let frame = Frame::message_with_generator("data", OpCode::Text, true, &mut gen);
+            assert_eq!(gen.0, i);
+            assert_eq!(&frame.as_ref()[2..6], &[i; 4][..]);
+
+            let mut buf = BytesMut::from(frame.as_ref());
+            let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+            assert_eq!(frame.payload.as_ref(), &b"data"[..]);
+        }
+
+        // trait object
+        let gen: &mut MaskGenerator = &mut gen;
+        let _ = Frame::message_with_generator("data", OpCode::Text, true, gen);
+    }
+
+    #[test]
+    fn test_encode_control() {
+        let frame = Frame::encode_control(OpCode::Ping, b"ping", false).unwrap();
+        assert_eq!(frame, Frame::message("ping", OpCode::Ping, true, false));
+
+        let payload = [0x5au8; 125];
+        let frame = Frame::encode_control(OpCode::Pong, &payload, false).unwrap();
+        assert_eq!(frame, Frame::message(payload.to_vec(), OpCode::Pong, true, false));
+        assert_eq!(Frame::encode_control(OpCode::Pong, &[0u8; 126], false),
+                   Err(ProtocolError::ControlFrameTooLong(126)));
+        assert_eq!(Frame::encode_control(OpCode::Text, b"ping", false),
+                   Err(ProtocolError::InvalidOpcode(InvalidOpCode(1))));
+
+        let frame = Frame::encode_control(OpCode::Ping, b"ping", true).unwrap();
+        let mut buf = BytesMut::from(frame.as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert!(frame.finished);
+        assert!(frame.is_control());
+        assert_eq!(frame.opcode, OpCode::Ping);
+        assert_eq!(frame.payload.as_ref(), &b"ping"[..]);
+    }
+
+    #[test]
+    fn test_fallback_mask() {
+        use rand::XorShiftRng;
+
+        // primary source is preferred
+        let mut gen = FallbackMask::with_source(Ok(XorShiftRng::from_seed([1, 2, 3, 4])));
+        assert!(!gen.is_fallback());
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        assert_eq!(gen.next_mask(), rng.gen::<[u8; 4]>());
+
+        // failed source
+        let mut gen = FallbackMask::<XorShiftRng>::with_source(
+            Err(io::Error::new(io::ErrorKind::Other, "no entropy")));
+        assert!(gen.is_fallback());
+        let keys: Vec<_> = (0..4).map(|_| gen.next_mask()).collect();
+        assert!(keys.windows(2).any(|w| w[0] != w[1]));
+
+        let frame = Frame::message_with_generator("data", OpCode::Text, true, &mut gen);
+        let mut buf = BytesMut::from(frame.as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"data"[..]);
+
+        // fallback generators are seeded independently
+        let mut other = FallbackMask::<XorShiftRng>::with_source(
+            Err(io::Error::new(io::ErrorKind::Other, "no entropy")));
+        let other_keys: Vec<_> = (0..4).map(|_| other.next_mask()).collect();
+        assert_ne!(keys, other_keys);
+    }
+
+    #[test]
+    fn test_encoder_masking() {
+        let client = FrameEncoder::new(&WsConfig::client());
+        let server = FrameEncoder::new(&WsConfig::server());
+        let mut deflate = PerMessageDeflate::new(15, false);
+
+        for code in &[OpCode::Text, OpCode::Binary, OpCode::Ping, OpCode::Pong] {
+            assert!(client.message("data", *code, true).as_ref()[1] & 0x80 != 0);
+            assert!(server.message("data", *code, true).as_ref()[1] & 0x80 == 0);
+
+            let frame = client.compressed_message(b"data", *code, &mut deflate).unwrap();
+            assert!(frame.as_ref()[1] & 0x80 != 0);
+            let frame = server.compressed_message(b"data", *code, &mut deflate).unwrap();
+            assert!(frame.as_ref()[1] & 0x80 == 0);
+        }
+        assert!(client.message("data", OpCode::Continue, false).as_ref()[1] & 0x80 != 0);
+        assert!(client.close(CloseCode::Normal, "").as_ref()[1] & 0x80 != 0);
+        assert!(server.close(CloseCode::Normal, "").as_ref()[1] & 0x80 == 0);
+
+        // encoded frames are accepted by the peer
+        let mut buf = BytesMut::from(client.message("data", OpCode::Text, true).as_ref());
+        assert!(Frame::parse(&mut buf, &WsConfig::server()).unwrap().is_some());
+        let mut buf = BytesMut::from(server.message("data", OpCode::Text, true).as_ref());
+        assert!(Frame::parse(&mut buf, &WsConfig::client()).unwrap().is_some());
+
+        let frame = client.build(FrameBuilder::new().payload("data").mask(true));
+        assert!(frame.as_ref()[1] & 0x80 != 0);
+        let frame = server.build(FrameBuilder::new().payload("data"));
+        assert!(frame.as_ref()[1] & 0x80 == 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Server encodes masked frame")]
+    fn test_encoder_server_masked() {
+        let server = FrameEncoder::new(&WsConfig::server());
+        server.build(FrameBuilder::new().payload("data").mask_key([1, 2, 3, 4]));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Client encodes unmasked frame")]
+    fn test_encoder_client_unmasked() {
+        let client = FrameEncoder::new(&WsConfig::client());
+        client.build(FrameBuilder::new().payload("data"));
+    }
+
+    #[test]
+    fn test_fragmenter() {
+        let data: Vec<u8> = (0..10 * 1024).map(|i| (i % 251) as u8).collect();
+        let frames: Vec<_> = FrameFragmenter::new(
+            data.clone(), OpCode::Binary, 4096, true).collect();
+        assert_eq!(frames.len(), 3);
+
+        let cfg = WsConfig::server();
+        let mut decoder = FrameDecoder::new(cfg);
+        let mut r = FrameReassembler::new(cfg);
+        let mut buf = BytesMut::new();
+        for f in &frames {
+            buf.extend_from_slice(f.as_ref());
+        }
+
+        let first = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(!first.finished);
+        assert_eq!(first.opcode, OpCode::Binary);
+        assert_eq!(first.payload.len(), 4096);
+        assert!(r.push(first).unwrap().is_none());
+
+        let second = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(!second.finished);
+        assert_eq!(second.opcode, OpCode::Continue);
+        assert!(r.push(second).unwrap().is_none());
+
+        let last = decoder.decode(&mut buf).unwrap().unwrap();
+        assert!(last.finished);
+        assert_eq!(last.opcode, OpCode::Continue);
+        assert_eq!(last.payload.len(), 2048);
+        let (op, payload) = r.push(last).unwrap().unwrap();
+        assert_eq!(op, OpCode::Binary);
+        assert_eq!(payload.as_ref(), &data[..]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_fragmenter_client() {
+        let frames: Vec<_> = FrameFragmenter::client("012345678", OpCode::Text, 4).collect();
+        assert_eq!(frames.len(), 3);
+
+        // every frame is masked with its own key
+        for f in &frames {
+            assert!(f.as_ref()[1] & 0x80 != 0);
+        }
+        let keys: Vec<_> = frames.iter().map(|f| &f.as_ref()[2..6]).collect();
+        assert!(keys[0] != keys[1] || keys[1] != keys[2]);
+
+        let cfg = WsConfig::server();
+        let mut decoder = FrameDecoder::new(cfg);
+        let mut r = FrameReassembler::new(cfg);
+        let mut buf = BytesMut::new();
+        for f in &frames {
+            buf.extend_from_slice(f.as_ref());
+        }
+
+        let mut codes = Vec::new();
+        let mut message = None;
+        while let Some(frame) = decoder.decode(&mut buf).unwrap() {
+            codes.push((frame.opcode, frame.finished, frame.payload.len()));
+            message = r.push(frame).unwrap();
+        }
+        assert_eq!(codes, vec![(OpCode::Text, false, 4),
+                               (OpCode::Continue, false, 4),
+                               (OpCode::Continue, true, 1)]);
+        let (op, payload) = message.unwrap();
+        assert_eq!(op, OpCode::Text);
+        assert_eq!(payload.as_ref(), &b"012345678"[..]);
+    }
+
+    #[test]
+    fn test_message_decoder_opening_continuation() {
+        // message must not start with continuation, even non-final one
+        let mut buf = BytesMut::new();
+        buf.extend(Frame::message("Hello, ", OpCode::Continue, false, true).as_ref());
+        buf.extend(Frame::message("World", OpCode::Continue, true, true).as_ref());
+
+        let mut decoder = MessageDecoder::new(WsConfig::server());
+        let err = decoder.decode(&mut buf).err().unwrap();
+        assert_eq!(err, ProtocolError::UnexpectedContinuation);
+        assert_eq!(err.close_code(), CloseCode::Protocol);
+        let code: u16 = err.close_code().into();
+        assert_eq!(code, 1002);
+    }
+
+    #[test]
+    fn test_message_decoder_interleaved_control() {
+        let mut buf = BytesMut::new();
+        buf.extend(Frame::message("Hello, ", OpCode::Text, false, true).as_ref());
+        buf.extend(Frame::message("ping", OpCode::Ping, true, true).as_ref());
+        buf.extend(Frame::message("World", OpCode::Continue, true, true).as_ref());
+
+        let mut decoder = MessageDecoder::new(WsConfig::server());
+        match decoder.decode(&mut buf).unwrap() {
+            Some(Decoded::Control(frame)) => {
+                assert_eq!(frame.opcode, OpCode::Ping);
+                assert_eq!(frame.payload.as_ref(), &b"ping"[..]);
+            }
+            item => panic!("unexpected item: {:?}", item),
+        }
+        match decoder.decode(&mut buf).unwrap() {
+            Some(Decoded::Message(opcode, payload)) => {
+                assert_eq!(opcode, OpCode::Text);
+                assert_eq!(payload.as_ref(), &b"Hello, World"[..]);
+            }
+            item => panic!("unexpected item: {:?}", item),
+        }
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+
+        // compressed control frame
+        let frame = FrameBuilder::new().opcode(OpCode::Pong).rsv1(true).mask(true).build();
+        let mut buf = BytesMut::from(frame.as_ref());
+        let mut decoder = MessageDecoder::new(WsConfig{deflate: true, ..WsConfig::server()});
+        assert_eq!(decoder.decode(&mut buf).unwrap_err(), ProtocolError::CompressedControlFrame);
+    }
+
+    #[test]
+    fn test_fragmenter_small() {
+        let mut frames = FrameFragmenter::new("data", OpCode::Text, 4096, false);
+        assert_eq!(frames.next(), Some(Frame::message("data", OpCode::Text, true, false)));
+        assert!(frames.next().is_none());
+
+        let mut frames = FrameFragmenter::new("", OpCode::Text, 4096, false);
+        assert_eq!(frames.next(), Some(Frame::message("", OpCode::Text, true, false)));
+        assert!(frames.next().is_none());
+    }
+
+    fn frame(finished: bool, opcode: OpCode, payload: &'static [u8]) -> Frame {
+        Frame { finished: finished, rsv1: false, rsv2: false, rsv3: false,
+                opcode: opcode, payload: payload.into(), mask: None }
+    }
+
+    #[test]
+    fn test_reassemble() {
+        let mut r = FrameReassembler::new(WsConfig::server());
+
+        let (op, payload) = r.push(frame(true, OpCode::Text, b"single")).unwrap().unwrap();
+        assert_eq!(op, OpCode::Text);
+        assert_eq!(payload.as_ref(), &b"single"[..]);
+
+        assert!(r.push(frame(false, OpCode::Binary, b"12")).unwrap().is_none());
+        assert!(r.push(frame(false, OpCode::Continue, b"34")).unwrap().is_none());
+        let (op, payload) = r.push(frame(true, OpCode::Continue, b"56")).unwrap().unwrap();
+        assert_eq!(op, OpCode::Binary);
+        assert_eq!(payload.as_ref(), &b"123456"[..]);
+    }
+
+    #[test]
+    fn test_reassemble_masked() {
+        let config = WsConfig{unmask: false, ..WsConfig::server()};
+        let mut r = FrameReassembler::new(config);
+
+        let mut buf = BytesMut::new();
+        for frame in FrameFragmenter::client("hello world", OpCode::Text, 4) {
+            buf.extend(frame.as_ref());
+        }
+        let frames = Frame::parse_all(&mut buf, &config).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert!(frames.iter().all(|frame| frame.mask().is_some()));
+
+        let mut message = None;
+        for frame in frames {
+            message = r.push(frame).unwrap();
+        }
+        let (op, payload) = message.unwrap();
+        assert_eq!(op, OpCode::Text);
+        assert_eq!(payload.as_ref(), &b"hello world"[..]);
+    }
+
+    #[test]
+    fn test_reassemble_interleaved_ping() {
+        let mut r = FrameReassembler::new(WsConfig::server());
+
+        assert!(r.push(frame(false, OpCode::Text, b"hello ")).unwrap().is_none());
+        let (op, payload) = r.push(frame(true, OpCode::Ping, b"ping")).unwrap().unwrap();
+        assert_eq!(op, OpCode::Ping);
+        assert_eq!(payload.as_ref(), &b"ping"[..]);
+        let (op, _) = r.push(frame(true, OpCode::Pong, b"")).unwrap().unwrap();
+        assert_eq!(op, OpCode::Pong);
+
+        let (op, payload) = r.push(frame(true, OpCode::Continue, b"world")).unwrap().unwrap();
+        assert_eq!(op, OpCode::Text);
+        assert_eq!(payload.as_ref(), &b"hello world"[..]);
+    }
+
+    #[test]
+    fn test_reassemble_nested_fragment() {
+        let mut r = FrameReassembler::new(WsConfig::server());
+
+        assert!(r.push(frame(false, OpCode::Text, b"1")).unwrap().is_none());
+        assert!(r.push(frame(false, OpCode::Binary, b"2")).is_err());
+
+        let mut r = FrameReassembler::new(WsConfig::server());
+        assert!(r.push(frame(false, OpCode::Text, b"1")).unwrap().is_none());
+        assert!(r.push(frame(true, OpCode::Text, b"2")).is_err());
+    }
+
+    #[test]
+    fn test_reassemble_lone_continuation() {
+        let mut r = FrameReassembler::new(WsConfig::server());
+        let err = r.push(frame(true, OpCode::Continue, b"1")).err().unwrap();
+        assert_eq!(err, ProtocolError::UnexpectedContinuation);
+        assert_eq!(err.to_string(), "Unexpected continuation frame");
+
+        let err = r.push(frame(false, OpCode::Continue, b"1")).err().unwrap();
+        assert_eq!(err, ProtocolError::UnexpectedContinuation);
+
+        // continuation after completed message
+        assert!(r.push(frame(true, OpCode::Text, b"1")).unwrap().is_some());
+        assert!(r.push(frame(true, OpCode::Continue, b"2")).is_err());
+    }
+
+    #[test]
+    fn test_reassemble_max_size() {
+        let mut r = FrameReassembler::new(WsConfig{max_message_size: 4, ..WsConfig::server()});
+
+        assert!(r.push(frame(false, OpCode::Binary, b"12")).unwrap().is_none());
+        assert!(r.push(frame(false, OpCode::Continue, b"34")).unwrap().is_none());
+        let err = r.push(frame(true, OpCode::Continue, b"5")).err().unwrap();
+        assert_eq!(err, ProtocolError::MessageTooLarge);
+        assert_eq!(err.close_code(), CloseCode::Size);
+
+        assert!(r.push(frame(true, OpCode::Binary, b"12345")).is_err());
+    }
+
+    #[test]
+    fn test_reassemble_max_fragments() {
+        let cfg = WsConfig{max_fragments_per_message: 3, ..WsConfig::server()};
+        let mut r = FrameReassembler::new(cfg);
+
+        assert!(r.push(frame(false, OpCode::Text, b"1")).unwrap().is_none());
+        assert!(r.push(frame(false, OpCode::Continue, b"2")).unwrap().is_none());
+        assert_eq!(r.push(frame(true, OpCode::Continue, b"3")).unwrap(),
+                   Some((OpCode::Text, Binary::from("123"))));
+
+        assert!(r.push(frame(false, OpCode::Binary, b"1")).unwrap().is_none());
+        assert!(r.push(frame(false, OpCode::Continue, b"2")).unwrap().is_none());
+        assert!(r.push(frame(false, OpCode::Continue, b"3")).unwrap().is_none());
+        let err = r.push(frame(false, OpCode::Continue, b"4")).err().unwrap();
+        assert_eq!(err, ProtocolError::TooManyFragments);
+        assert_eq!(err.close_code(), CloseCode::Size);
+
+        // interleaved control frames are not counted
+        assert!(r.push(frame(false, OpCode::Binary, b"1")).unwrap().is_none());
+        assert!(r.push(frame(true, OpCode::Ping, b"")).unwrap().is_some());
+        assert!(r.push(frame(false, OpCode::Continue, b"2")).unwrap().is_none());
+        assert!(r.push(frame(true, OpCode::Ping, b"")).unwrap().is_some());
+        assert!(r.push(frame(true, OpCode::Continue, b"3")).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_reassemble_deflate() {
+        use flate2::{Compress, Compression, FlushCompress};
+
+        let mut c = Compress::new(Compression::default(), false);
+        let mut data = Vec::with_capacity(128);
+        c.compress_vec(b"Hello, Hello, Hello", &mut data, FlushCompress::Sync).unwrap();
+        let len = data.len() - 4;
+        data.truncate(len);
+        let (first, second) = data.split_at(len / 2);
+
+        let mut r = FrameReassembler::new(WsConfig::server());
+        r.set_deflate(PerMessageDeflate::new(15, false));
+        let mut f = Frame { finished: false, rsv1: true, rsv2: false, rsv3: false,
+                            opcode: OpCode::Text, payload: Binary::from_slice(first),
+                            mask: None };
+        assert!(r.push(f).unwrap().is_none());
+        f = Frame { finished: true, rsv1: false, rsv2: false, rsv3: false,
+                    opcode: OpCode::Continue, payload: Binary::from_slice(second),
+                    mask: None };
+        let (op, payload) = r.push(f).unwrap().unwrap();
+        assert_eq!(op, OpCode::Text);
+        assert_eq!(payload.as_ref(), &b"Hello, Hello, Hello"[..]);
+
+        // compressed frame without negotiated extension
+        let mut r = FrameReassembler::new(WsConfig::server());
+        f = Frame { finished: true, rsv1: true, rsv2: false, rsv3: false,
+                    opcode: OpCode::Text, payload: Binary::from_slice(&data),
+                    mask: None };
+        assert_eq!(r.push(f).err().unwrap(), ProtocolError::UnnegotiatedCompression);
+
+        // garbage compressed payload
+        let mut r = FrameReassembler::new(WsConfig::server());
+        r.set_deflate(PerMessageDeflate::new(15, false));
+        f = Frame { finished: true, rsv1: true, rsv2: false, rsv3: false,
+                    opcode: OpCode::Binary, payload: Binary::from_slice(b"\xff\xff\xff\xff"),
+                    mask: None };
+        let err = r.push(f).err().unwrap();
+        assert_eq!(err, ProtocolError::InvalidCompressedData);
+        assert_eq!(err.close_code(), CloseCode::Invalid);
+    }
+
+    #[test]
+    fn test_compressed_message() {
+        let mut deflate = PerMessageDeflate::new(15, false);
+        let data = b"Hello, Hello, Hello, Hello";
+        let frame = Frame::compressed_message(data, OpCode::Text, false, &mut deflate).unwrap();
+        assert_eq!(frame.as_ref()[0], 0x80 | RSV1 | 0x01);
+
+        let mut buf = BytesMut::from(frame.as_ref());
+        let cfg = WsConfig{deflate: true, ..WsConfig::client()};
+        let frame = Frame::parse(&mut buf, &cfg).unwrap().unwrap();
+        let mut r = FrameReassembler::new(WsConfig::server());
+        r.set_deflate(PerMessageDeflate::new(15, false));
+        let (op, payload) = r.push(frame).unwrap().unwrap();
+        assert_eq!(op, OpCode::Text);
+        assert_eq!(payload.as_ref(), &data[..]);
+
+        // control frames are not compressed
+        let frame = Frame::compressed_message(b"ping", OpCode::Ping, false, &mut deflate).unwrap();
+        assert_eq!(frame, Frame::message(Vec::from("ping"), OpCode::Ping, true, false));
+    }
+
+    #[test]
+    fn test_frame_builder() {
+        assert_eq!(FrameBuilder::new().payload("data").build(),
+                   Frame::message("data", OpCode::Text, true, false));
+        assert_eq!(FrameBuilder::new().build(), Binary::from(vec![0x81u8, 0u8]));
+
+        let frame = FrameBuilder::new()
+            .opcode(OpCode::Binary)
+            .finished(false)
+            .rsv1(true)
+            .payload(Vec::from("data"))
+            .build();
+        let mut v = vec![0x42u8, 4u8];
+        v.extend(b"data");
+        assert_eq!(frame, v.into());
+
+        let frame = FrameBuilder::new().mask(true).payload("data").build();
+        let mut buf = BytesMut::from(frame.as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"data"[..]);
+    }
+
+    #[test]
+    fn test_message_with_mask() {
+        let frame = Frame::message_with_mask("hello", OpCode::Text, true, [1, 2, 3, 4]);
+        assert_eq!(frame, Binary::from(
+            vec![0x81u8, 0x85u8, 1, 2, 3, 4, 0x69, 0x67, 0x6f, 0x68, 0x6e]));
+
+        let mut buf = BytesMut::from(frame.as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.payload.as_ref(), &b"hello"[..]);
+    }
+
+    #[test]
+    fn test_parse_protocol_errors() {
+        let mut buf = BytesMut::from(&[0b10000011u8, 0b00000000u8][..]);
+        let err = Frame::parse(&mut buf, &WsConfig::client()).unwrap_err();
+        assert_eq!(err, ProtocolError::InvalidOpcode(InvalidOpCode(3)));
+        assert_eq!(err.to_string(), "Encountered reserved data opcode: 3");
+        assert_eq!(err.close_code(), CloseCode::Protocol);
+
+        let mut buf = BytesMut::from(&[0b10001001u8, 126u8, 0u8, 126u8][..]);
+        buf.extend(&[0u8; 126][..]);
+        let err = Frame::parse(&mut buf, &WsConfig::client()).unwrap_err();
+        assert_eq!(err, ProtocolError::ControlFrameTooLong(126));
+        assert_eq!(err.close_code(), CloseCode::Protocol);
+
+        let mut buf = BytesMut::from(&[0b10000010u8, 126u8, 0u8, 200u8][..]);
+        let cfg = WsConfig{max_frame_size: 100, ..WsConfig::client()};
+        let err = Frame::parse(&mut buf, &cfg).unwrap_err();
+        assert_eq!(err, ProtocolError::FrameTooLarge);
+        assert_eq!(err.close_code(), CloseCode::Size);
+
+        let err: Error = err.into();
+        assert_eq!(err.to_string(), "Frame too large");
+    }
+
+    #[test]
+    fn test_parse_fragmented_control() {
+        let mut buf = BytesMut::from(&[0b00001001u8, 0b00000001u8][..]);
+        buf.extend(b"1");
+        assert_eq!(Frame::parse(&mut buf, &WsConfig::client()).unwrap_err(),
+                   ProtocolError::FragmentedControlFrame);
+
+        let mut buf = BytesMut::from(&[0b00001000u8, 0b00000000u8][..]);
+        assert_eq!(Frame::parse(&mut buf, &WsConfig::client()).unwrap_err(),
+                   ProtocolError::FragmentedControlFrame);
+
+        let mut buf = BytesMut::from(&[0b10001001u8, 0b00000001u8][..]);
+        buf.extend(b"1");
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Ping);
+    }
+
+    #[test]
+    fn test_ping_frame() {
+        let frame = Frame::message(Vec::from("data"), OpCode::Ping, true, false);
+
+        let mut v = vec![137u8, 4u8];
+        v.extend(b"data");
+        assert_eq!(frame, v.into());
+    }
+
+    #[test]
+    fn test_pong_frame() {
+        let frame = Frame::message(Vec::from("data"), OpCode::Pong, true, false);
+
+        let mut v = vec![138u8, 4u8];
+        v.extend(b"data");
+        assert_eq!(frame, v.into());
+    }
+
+    #[test]
+    fn test_close_frame() {
+        let frame = Frame::close(CloseCode::Normal, "data", false);
 
         let mut v = vec![136u8, 6u8, 3u8, 232u8];
         v.extend(b"data");
         assert_eq!(frame, v.into());
     }
+
+    #[test]
+    fn test_close_frame_payload() {
+        let mut buf = BytesMut::from(Frame::close(CloseCode::Normal, "data", false).as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.opcode, OpCode::Close);
+        assert_eq!(frame.payload.as_ref(), &[3u8, 232u8, b'd', b'a', b't', b'a'][..]);
+
+        let mut buf = BytesMut::from(Frame::close(CloseCode::Empty, "data", false).as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert!(frame.payload.is_empty());
+    }
+
+    #[test]
+    fn test_parse_all_from_slice() {
+        let mut capture = Vec::new();
+        capture.extend_from_slice(
+            Frame::message_with_mask("text", OpCode::Text, true, [1, 2, 3, 4]).as_ref());
+        capture.extend_from_slice(
+            Frame::message_with_mask("ping", OpCode::Ping, true, [5, 6, 7, 8]).as_ref());
+        capture.extend_from_slice(Frame::close(CloseCode::Normal, "bye", true).as_ref());
+
+        let (frames, trailing) =
+            Frame::parse_all_from_slice(&capture, &WsConfig::server()).unwrap();
+        assert_eq!(trailing, 0);
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].opcode, OpCode::Text);
+        assert_eq!(frames[0].payload.as_ref(), &b"text"[..]);
+        assert_eq!(frames[1].opcode, OpCode::Ping);
+        assert_eq!(frames[1].payload.as_ref(), &b"ping"[..]);
+        assert_eq!(frames[2].close_reason(), Some((CloseCode::Normal, "bye".to_owned())));
+
+        // capture ends in the middle of a frame
+        let len = capture.len();
+        let (frames, trailing) =
+            Frame::parse_all_from_slice(&capture[..len - 2], &WsConfig::server()).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(trailing, 13 - 2);
+
+        assert!(Frame::parse_all_from_slice(&capture, &WsConfig::client()).is_err());
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut buf = BytesMut::from(
+            Frame::message_with_mask("hello", OpCode::Text, true, [1, 2, 3, 4]).as_ref());
+        buf.extend(&b"\x81"[..]);
+        let len = buf.len();
+
+        let (first, n) = Frame::peek(&buf, &WsConfig::server()).unwrap().unwrap();
+        let (second, m) = Frame::peek(&buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(first, second);
+        assert_eq!((n, m), (11, 11));
+        assert_eq!(first.payload.as_ref(), &b"hello"[..]);
+        assert_eq!(buf.len(), len);
+
+        // caller advances buffer
+        buf.split_to(n);
+        assert!(Frame::peek(&buf, &WsConfig::server()).unwrap().is_none());
+        assert_eq!(buf.len(), 1);
+    }
+
+    #[test]
+    fn test_close_long_reason() {
+        let reason: String = (0..200).map(|_| 'x').collect();
+        let mut buf = BytesMut::from(Frame::close(CloseCode::Normal, &reason, false).as_ref());
+        assert_eq!(buf.len(), 127);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.payload.len(), 125);
+        assert_eq!(frame.close_reason(), Some((CloseCode::Normal, reason[..123].to_owned())));
+
+        // multi-byte character is not split
+        let reason: String = (0..100).map(|_| '\u{e9}').collect();
+        let mut buf = BytesMut::from(Frame::close(CloseCode::Normal, &reason, true).as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.payload.len(), 124);
+        assert_eq!(frame.close_reason(), Some((CloseCode::Normal, reason[..122].to_owned())));
+    }
+
+    #[test]
+    fn test_parse_close_one_byte() {
+        let frame = FrameBuilder::new().opcode(OpCode::Close).payload(&b"\x03"[..]).build();
+        let mut buf = BytesMut::from(frame.as_ref());
+        let err = Frame::parse(&mut buf, &WsConfig::client()).unwrap_err();
+        assert_eq!(err, ProtocolError::InvalidClosePayload);
+        assert_eq!(err.close_code(), CloseCode::Protocol);
+
+        let mut buf = BytesMut::from(frame.as_ref());
+        let mut decoder = StreamingFrameDecoder::new(WsConfig::client());
+        assert_eq!(decoder.decode(&mut buf).unwrap_err(), ProtocolError::InvalidClosePayload);
+
+        // empty payload and status code only are fine
+        let frame = FrameBuilder::new().opcode(OpCode::Close).build();
+        let mut buf = BytesMut::from(frame.as_ref());
+        assert!(Frame::parse(&mut buf, &WsConfig::client()).unwrap().is_some());
+        let frame = FrameBuilder::new().opcode(OpCode::Close).payload(&b"\x03\xe8"[..]).build();
+        let mut buf = BytesMut::from(frame.as_ref());
+        assert!(Frame::parse(&mut buf, &WsConfig::client()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_parse_oversized_close() {
+        let mut payload = vec![0x03u8, 0xe8u8];
+        payload.extend_from_slice(&[b'x'; 198]);
+        let frame = FrameBuilder::new().opcode(OpCode::Close).payload(payload).build();
+
+        // replaced with protocol error close frame
+        let mut buf = BytesMut::from(frame.as_ref());
+        let parsed = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(parsed.opcode, OpCode::Close);
+        assert_eq!(parsed.close_reason(),
+                   Some((CloseCode::Protocol, "Close frame too large".to_owned())));
+        assert!(buf.is_empty());
+
+        // raw error
+        let config = WsConfig{morph_oversized_close: false, ..WsConfig::client()};
+        let mut buf = BytesMut::from(frame.as_ref());
+        let err = Frame::parse(&mut buf, &config).err().unwrap();
+        assert_eq!(err, ProtocolError::ControlFrameTooLong(200));
+        assert_eq!(err.close_code(), CloseCode::Protocol);
+    }
+
+    #[test]
+    fn test_close_reason() {
+        let mut buf = BytesMut::from(Frame::close(CloseCode::Away, "bye", false).as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.close_reason(), Some((CloseCode::Away, "bye".to_owned())));
+
+        let mut buf = BytesMut::from(
+            Frame::close(CloseCode::Private(4001), "bye", false).as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.close_reason(), Some((CloseCode::Private(4001), "bye".to_owned())));
+
+        let mut buf = BytesMut::from(Frame::close(CloseCode::Empty, "", false).as_ref());
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.close_reason(), None);
+
+        let mut buf = BytesMut::from(&[0x88u8, 0x04u8, 0x03u8, 0xe8u8, 0xffu8, b'a'][..]);
+        let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.close_reason(), Some((CloseCode::Normal, "\u{fffd}a".to_owned())));
+
+        for code in &[0u16, 999, 1004, 2999, 5000] {
+            let mut buf = BytesMut::from(
+                Frame::close(CloseCode::Other(*code), "", false).as_ref());
+            let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+            assert_eq!(frame.close_reason().unwrap().0, CloseCode::Protocol);
+        }
+
+        // reserved codes are not allowed on the wire
+        for code in &[1005u16, 1006, 1015] {
+            let mut buf = BytesMut::from(
+                Frame::close(CloseCode::Other(*code), "bye", false).as_ref());
+            let frame = Frame::parse(&mut buf, &WsConfig::client()).unwrap().unwrap();
+            assert_eq!(frame.close_reason(), Some((CloseCode::Reserved(*code), "bye".to_owned())));
+        }
+
+        let frame = Frame::parse(
+            &mut BytesMut::from(Frame::message("text", OpCode::Text, true, false).as_ref()),
+            &WsConfig::client()).unwrap().unwrap();
+        assert_eq!(frame.close_reason(), None);
+    }
+
+    #[test]
+    fn test_reassemble_utf8() {
+        // "€" is encoded as e2 82 ac
+        let mut r = FrameReassembler::new(WsConfig::server());
+        assert!(r.push(frame(false, OpCode::Text, b"a\xe2")).unwrap().is_none());
+        assert!(r.push(frame(false, OpCode::Continue, b"\x82")).unwrap().is_none());
+        let (op, payload) = r.push(frame(true, OpCode::Continue, b"\xacb")).unwrap().unwrap();
+        assert_eq!(op, OpCode::Text);
+        assert_eq!(payload.as_ref(), "a\u{20ac}b".as_bytes());
+
+        // lone continuation byte fails before final fragment
+        let mut r = FrameReassembler::new(WsConfig::server());
+        assert!(r.push(frame(false, OpCode::Text, b"a")).unwrap().is_none());
+        let err = r.push(frame(false, OpCode::Continue, b"\x80")).err().unwrap();
+        assert_eq!(err, ProtocolError::InvalidUtf8);
+
+        // incomplete sequence in final fragment
+        let mut r = FrameReassembler::new(WsConfig::server());
+        assert!(r.push(frame(false, OpCode::Text, b"a")).unwrap().is_none());
+        let err = r.push(frame(true, OpCode::Continue, b"\xe2\x82")).err().unwrap();
+        assert_eq!(err, ProtocolError::InvalidUtf8);
+
+        let mut r = FrameReassembler::new(WsConfig::server());
+        let err = r.push(frame(true, OpCode::Text, b"\xff")).err().unwrap();
+        assert_eq!(err, ProtocolError::InvalidUtf8);
+
+        // binary messages are not validated
+        let mut r = FrameReassembler::new(WsConfig::server());
+        assert!(r.push(frame(true, OpCode::Binary, b"\xff")).unwrap().is_some());
+    }
 }
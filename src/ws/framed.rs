@@ -0,0 +1,340 @@
+//! Frame level stream adapters
+use std::io;
+use std::io::Write;
+use bytes::{BytesMut, BufMut};
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+use body::Binary;
+use ws::frame::{Frame, FrameDecoder, FrameEncoder};
+use ws::proto::{OpCode, CloseCode};
+use ws::config::WsConfig;
+
+const LW_BUFFER_SIZE: usize = 4096;
+const HW_BUFFER_SIZE: usize = 16_384;
+const MAX_WRITE_BUFFER_SIZE: usize = 65_536;
+
+/// Stream of `WebSocket` frames read from `AsyncRead` object.
+///
+/// Stream ends after Close frame is received. Bytes of incomplete frame
+/// are kept by the stream between polls, future polling the stream
+/// can be dropped and recreated without losing data.
+pub struct FrameStream<R> {
+    io: R,
+    buf: BytesMut,
+    decoder: FrameDecoder,
+    closed: bool,
+}
+
+impl<R: AsyncRead> FrameStream<R> {
+
+    /// Create new frame stream.
+    ///
+    /// Frames read by server side (`WsConfig::server()`) have to be masked.
+    pub fn new(io: R, config: WsConfig) -> FrameStream<R> {
+        let decoder = FrameDecoder::new(config);
+        FrameStream {
+            io: io,
+            buf: decoder.buffer(),
+            decoder: decoder,
+            closed: false,
+        }
+    }
+
+    /// Get reference to underlying io object
+    pub fn get_ref(&self) -> &R {
+        &self.io
+    }
+
+    /// Get mutable reference to underlying io object
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.io
+    }
+}
+
+impl<R: AsyncRead> Stream for FrameStream<R> {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Frame>, io::Error> {
+        if self.closed {
+            return Ok(Async::Ready(None))
+        }
+
+        loop {
+            if let Some(frame) = self.decoder.decode(&mut self.buf)? {
+                if frame.opcode() == OpCode::Close {
+                    self.closed = true;
+                }
+                return Ok(Async::Ready(Some(frame)))
+            }
+
+            if self.buf.remaining_mut() < LW_BUFFER_SIZE {
+                self.buf.reserve(HW_BUFFER_SIZE);
+            }
+            match self.io.read_buf(&mut self.buf)? {
+                Async::Ready(0) => {
+                    self.closed = true;
+                    return if self.buf.is_empty() {
+                        Ok(Async::Ready(None))
+                    } else {
+                        Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof, "Connection closed in the middle of frame"))
+                    }
+                }
+                Async::Ready(_) => (),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Sink of `WebSocket` messages written to `AsyncWrite` object.
+///
+/// Each item is encoded as a single frame, client side frames are masked.
+/// Close frame is sent when sink gets closed.
+pub struct FrameSink<W> {
+    io: W,
+    buf: BytesMut,
+    encoder: FrameEncoder,
+    closed: bool,
+}
+
+impl<W: AsyncWrite> FrameSink<W> {
+
+    /// Create new frame sink.
+    ///
+    /// Frames written by client side (`WsConfig::client()`) are masked.
+    pub fn new(io: W, config: WsConfig) -> FrameSink<W> {
+        FrameSink {
+            io: io,
+            buf: BytesMut::new(),
+            encoder: FrameEncoder::new(&config),
+            closed: false,
+        }
+    }
+
+    /// Get reference to underlying io object
+    pub fn get_ref(&self) -> &W {
+        &self.io
+    }
+
+    /// Get mutable reference to underlying io object
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.io
+    }
+
+    fn write_to_io(&mut self) -> Poll<(), io::Error> {
+        while !self.buf.is_empty() {
+            match self.io.write(self.buf.as_ref()) {
+                Ok(0) => return Err(io::Error::new(
+                    io::ErrorKind::WriteZero, "Can not write frame to io")),
+                Ok(n) => {
+                    let _ = self.buf.split_to(n);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock =>
+                    return Ok(Async::NotReady),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<W: AsyncWrite> Sink for FrameSink<W> {
+    type SinkItem = (OpCode, Binary);
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: (OpCode, Binary)) -> StartSend<(OpCode, Binary), io::Error> {
+        if self.closed {
+            return Err(io::Error::new(io::ErrorKind::Other, "Sink is closed"))
+        }
+        if self.buf.len() >= MAX_WRITE_BUFFER_SIZE {
+            self.write_to_io()?;
+            if self.buf.len() >= MAX_WRITE_BUFFER_SIZE {
+                return Ok(AsyncSink::NotReady(item))
+            }
+        }
+        let (opcode, payload) = item;
+        self.buf.extend_from_slice(
+            self.encoder.message(payload, opcode, true).as_ref());
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), io::Error> {
+        try_ready!(self.write_to_io());
+        match self.io.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), io::Error> {
+        if !self.closed {
+            self.closed = true;
+            self.buf.extend_from_slice(
+                self.encoder.close(CloseCode::Normal, "").as_ref());
+        }
+        try_ready!(self.poll_complete());
+        self.io.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use futures::Future;
+
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl io::Read for Chunks {
+        fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(chunk) => {
+                    if chunk.is_empty() {
+                        return Err(io::Error::new(io::ErrorKind::WouldBlock, ""))
+                    }
+                    dst[..chunk.len()].copy_from_slice(&chunk);
+                    Ok(chunk.len())
+                }
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl AsyncRead for Chunks {}
+
+    #[test]
+    fn test_frame_stream() {
+        let frame = Frame::message(Vec::from("data"), OpCode::Text, true, false);
+        let close = Frame::close(CloseCode::Normal, "", false);
+        let mut chunks = VecDeque::new();
+        chunks.push_back(Vec::from(&frame.as_ref()[..1]));
+        chunks.push_back(Vec::new());
+        chunks.push_back(Vec::from(&frame.as_ref()[1..3]));
+        chunks.push_back(Vec::from(&frame.as_ref()[3..]));
+        chunks.push_back(Vec::from(close.as_ref()));
+        chunks.push_back(Vec::from(close.as_ref()));
+
+        let mut stream = FrameStream::new(Chunks(chunks), WsConfig::client());
+        match stream.poll() {
+            Ok(Async::NotReady) => (),
+            _ => panic!("Should not be ready"),
+        }
+        match stream.poll() {
+            Ok(Async::Ready(Some(frame))) => {
+                assert!(frame.is_finished());
+                assert_eq!(frame.opcode(), OpCode::Text);
+                assert_eq!(frame.payload().as_ref(), &b"data"[..]);
+            }
+            _ => panic!("Frame expected"),
+        }
+        match stream.poll() {
+            Ok(Async::Ready(Some(frame))) => assert_eq!(frame.opcode(), OpCode::Close),
+            _ => panic!("Close frame expected"),
+        }
+        match stream.poll() {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("Stream should be closed"),
+        }
+    }
+
+    #[test]
+    fn test_frame_stream_cancelled_read() {
+        let frame = Frame::message(Vec::from("data"), OpCode::Text, true, false);
+        let mut chunks = VecDeque::new();
+        chunks.push_back(Vec::from(&frame.as_ref()[..3]));
+        chunks.push_back(Vec::new());
+        chunks.push_back(Vec::from(&frame.as_ref()[3..]));
+        let mut stream = FrameStream::new(Chunks(chunks), WsConfig::client());
+
+        // read is dropped in the middle of the frame
+        {
+            let mut fut = stream.by_ref().into_future();
+            match fut.poll() {
+                Ok(Async::NotReady) => (),
+                _ => panic!("Should not be ready"),
+            }
+        }
+
+        let mut fut = stream.by_ref().into_future();
+        match fut.poll() {
+            Ok(Async::Ready((Some(frame), _))) =>
+                assert_eq!(frame.payload().as_ref(), &b"data"[..]),
+            _ => panic!("Frame expected"),
+        }
+    }
+
+    #[test]
+    fn test_frame_stream_error() {
+        let mut chunks = VecDeque::new();
+        chunks.push_back(vec![0b10000001u8, 0b00000001u8, b'1']);
+        let mut stream = FrameStream::new(Chunks(chunks), WsConfig::server());
+        assert!(stream.poll().is_err());
+
+        let mut chunks = VecDeque::new();
+        chunks.push_back(vec![0b10000001u8]);
+        let mut stream = FrameStream::new(Chunks(chunks), WsConfig::client());
+        assert!(stream.poll().is_err());
+
+        let mut stream = FrameStream::new(Chunks(VecDeque::new()), WsConfig::client());
+        match stream.poll() {
+            Ok(Async::Ready(None)) => (),
+            _ => panic!("Stream should be closed"),
+        }
+    }
+
+    #[derive(Default)]
+    struct Written {
+        buf: Vec<u8>,
+        shutdown: bool,
+    }
+
+    impl io::Write for Written {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Written {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            self.shutdown = true;
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn test_frame_sink() {
+        let mut sink = FrameSink::new(Written::default(), WsConfig::server());
+        match sink.start_send((OpCode::Text, Binary::from("data"))) {
+            Ok(AsyncSink::Ready) => (),
+            _ => panic!("Should be ready"),
+        }
+        assert!(sink.get_ref().buf.is_empty());
+        assert_eq!(sink.poll_complete().unwrap(), Async::Ready(()));
+        assert_eq!(sink.get_ref().buf, vec![0x81u8, 4u8, b'd', b'a', b't', b'a']);
+
+        assert_eq!(sink.close().unwrap(), Async::Ready(()));
+        assert!(sink.get_ref().shutdown);
+        assert_eq!(&sink.get_ref().buf[6..], &[0x88u8, 2u8, 0x03, 0xe8][..]);
+        assert!(sink.start_send((OpCode::Text, Binary::from("data"))).is_err());
+    }
+
+    #[test]
+    fn test_frame_sink_client() {
+        let mut sink = FrameSink::new(Written::default(), WsConfig::client());
+        let _ = sink.start_send((OpCode::Binary, Binary::from("data"))).unwrap();
+        let _ = sink.poll_complete().unwrap();
+
+        let mut buf = BytesMut::from(sink.get_ref().buf.as_slice());
+        let frame = Frame::parse(&mut buf, &WsConfig::server()).unwrap().unwrap();
+        assert_eq!(frame.opcode(), OpCode::Binary);
+        assert_eq!(frame.payload().as_ref(), &b"data"[..]);
+    }
+}
@@ -1,12 +1,22 @@
 //! This is code from [Tungstenite project](https://github.com/snapview/tungstenite-rs)
 use std::cmp::min;
-use std::mem::uninitialized;
-use std::ptr::copy_nonoverlapping;
+use byteorder::{ByteOrder, NativeEndian};
 
 /// Mask/unmask a frame.
 #[inline]
 pub fn apply_mask(buf: &mut [u8], mask: &[u8; 4]) {
-    apply_mask_fast32(buf, mask)
+    apply_mask_offset(buf, mask, 0)
+}
+
+/// Mask/unmask part of a frame payload.
+///
+/// `offset` is position of `buf` within the payload, masking key
+/// is rotated so its phase matches the payload offset.
+#[inline]
+pub fn apply_mask_offset(buf: &mut [u8], mask: &[u8; 4], offset: usize) {
+    let mask = [mask[offset & 3], mask[(offset + 1) & 3],
+                mask[(offset + 2) & 3], mask[(offset + 3) & 3]];
+    apply_mask_fast64(buf, &mask)
 }
 
 /// A safe unoptimized mask application.
@@ -18,72 +28,61 @@ fn apply_mask_fallback(buf: &mut [u8], mask: &[u8; 4]) {
     }
 }
 
-/// Faster version of `apply_mask()` which operates on 4-byte blocks.
+/// Version of `apply_mask()` which operates on 8-byte blocks.
 #[inline]
-#[allow(dead_code)]
-fn apply_mask_fast32(buf: &mut [u8], mask: &[u8; 4]) {
-    // TODO replace this with read_unaligned() as it stabilizes.
-    let mask_u32 = unsafe {
-        let mut m: u32 = uninitialized();
-        #[allow(trivial_casts)]
-        copy_nonoverlapping(mask.as_ptr(), &mut m as *mut _ as *mut u8, 4);
-        m
-    };
-
-    let mut ptr = buf.as_mut_ptr();
-    let mut len = buf.len();
-
+fn apply_mask_fast64(buf: &mut [u8], mask: &[u8; 4]) {
     // Possible first unaligned block.
-    let head = min(len, (4 - (ptr as usize & 3)) & 3);
-    let mask_u32 = if head > 0 {
-        unsafe {
-            xor_mem(ptr, mask_u32, head);
-            ptr = ptr.offset(head as isize);
-        }
-        len -= head;
-        if cfg!(target_endian = "big") {
-            mask_u32.rotate_left(8 * head as u32)
-        } else {
-            mask_u32.rotate_right(8 * head as u32)
-        }
-    } else {
-        mask_u32
-    };
+    let head = min(buf.len(), (8 - (buf.as_ptr() as usize & 7)) & 7);
+    debug_assert!(head < 8);
+    let (head_buf, rest) = buf.split_at_mut(head);
+    for (i, byte) in head_buf.iter_mut().enumerate() {
+        *byte ^= mask[i & 3];
+    }
 
-    if len > 0 {
-        debug_assert_eq!(ptr as usize % 4, 0);
+    // Mask rotated by the head length, in memory order.
+    let mut mask_bytes = [0u8; 8];
+    for (i, byte) in mask_bytes.iter_mut().enumerate() {
+        *byte = mask[(head + i) & 3];
     }
+    let mask_u64 = NativeEndian::read_u64(&mask_bytes);
 
     // Properly aligned middle of the data.
-    while len > 4 {
-        unsafe {
-            *(ptr as *mut u32) ^= mask_u32;
-            ptr = ptr.offset(4);
-            len -= 4;
-        }
+    let words = rest.len() / 8;
+    let (body, tail) = rest.split_at_mut(words * 8);
+    debug_assert!(body.len() == words * 8 && tail.len() < 8);
+    debug_assert!(words == 0 || body.as_ptr() as usize & 7 == 0);
+    let ptr = body.as_mut_ptr() as *mut u64;
+    for i in 0..words {
+        unsafe { *ptr.offset(i as isize) ^= mask_u64; }
     }
 
-    // Possible last block.
-    if len > 0 {
-        unsafe { xor_mem(ptr, mask_u32, len); }
+    // Possible last block, body length is a multiple of the mask length.
+    for (i, byte) in tail.iter_mut().enumerate() {
+        *byte ^= mask[(head + i) & 3];
     }
 }
 
-#[inline]
-// TODO: copy_nonoverlapping here compiles to call memcpy. While it is not so inefficient,
-// it could be done better. The compiler does not see that len is limited to 3.
-unsafe fn xor_mem(ptr: *mut u8, mask: u32, len: usize) {
-    let mut b: u32 = uninitialized();
-    #[allow(trivial_casts)]
-    copy_nonoverlapping(ptr, &mut b as *mut _ as *mut u8, len);
-    b ^= mask;
-    #[allow(trivial_casts)]
-    copy_nonoverlapping(&b as *const _ as *const u8, ptr, len);
-}
-
 #[cfg(test)]
 mod tests {
- use super::{apply_mask_fallback, apply_mask_fast32};
+ use super::{apply_mask, apply_mask_fallback, apply_mask_fast64, apply_mask_offset};
+
+    #[test]
+    fn test_apply_mask_bench_sizes() {
+        // buffer sizes of the benchmarks, optimized and naive versions must agree
+        let mask = [0x6d, 0xb6, 0xb2, 0x80];
+        for size in &[64, 4096, 1 << 20] {
+            let data: Vec<u8> = (0..*size + 3).map(|i| (i * 7 + 3) as u8).collect();
+            for offset in 0..4 {
+                let mut masked = data.clone();
+                apply_mask_fallback(&mut masked[offset..offset + size], &mask);
+
+                let mut masked_fast = data.clone();
+                apply_mask(&mut masked_fast[offset..offset + size], &mask);
+
+                assert!(masked == masked_fast, "size: {}, offset: {}", size, offset);
+            }
+        }
+    }
 
     #[test]
     fn test_apply_mask() {
@@ -101,7 +100,7 @@ mod tests {
             apply_mask_fallback(&mut masked, &mask);
 
             let mut masked_fast = unmasked.clone();
-            apply_mask_fast32(&mut masked_fast, &mask);
+            apply_mask(&mut masked_fast, &mask);
 
             assert_eq!(masked, masked_fast);
         }
@@ -112,9 +111,130 @@ mod tests {
             apply_mask_fallback(&mut masked[1..], &mask);
 
             let mut masked_fast = unmasked.clone();
-            apply_mask_fast32(&mut masked_fast[1..], &mask);
+            apply_mask(&mut masked_fast[1..], &mask);
 
             assert_eq!(masked, masked_fast);
         }
     }
+
+    #[test]
+    fn test_apply_mask_fast64() {
+        let masks = [
+            [0x6d, 0xb6, 0xb2, 0x80],
+            [0xb6, 0xb2, 0x80, 0x6d],
+            [0xb2, 0x80, 0x6d, 0xb6],
+            [0x80, 0x6d, 0xb6, 0xb2],
+        ];
+        let data: Vec<u8> = (0..1032u32).map(|i| (i * 7 + 3) as u8).collect();
+
+        for mask in &masks {
+            for offset in 0..8 {
+                for len in 0..1024 {
+                    let mut masked = data.clone();
+                    apply_mask_fallback(&mut masked[offset..offset+len], mask);
+
+                    let mut masked_fast = data.clone();
+                    apply_mask_fast64(&mut masked_fast[offset..offset+len], mask);
+
+                    assert_eq!(masked, masked_fast);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_mask_short() {
+        let mask = [0x6d, 0xb6, 0xb2, 0x80];
+        let data: Vec<u8> = (0..16u8).collect();
+
+        for len in &[1, 3, 5, 7, 9, 15] {
+            for offset in 0..8 {
+                let mut expected = data.clone();
+                for i in 0..*len {
+                    expected[offset + i] ^= mask[i % 4];
+                }
+
+                let mut masked = data.clone();
+                apply_mask_fast64(&mut masked[offset..offset + len], &mask);
+                assert_eq!(masked, expected);
+
+                let mut masked = data.clone();
+                apply_mask(&mut masked[offset..offset + len], &mask);
+                assert_eq!(masked, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_mask_offset() {
+        let mask = [0x6d, 0xb6, 0xb2, 0x80];
+        let unmasked: Vec<u8> = (0..64u8).collect();
+
+        let mut masked = unmasked.clone();
+        apply_mask_fallback(&mut masked, &mask);
+
+        // masking sub-slice with offset 0 breaks key phase
+        let mut masked_zero = unmasked.clone();
+        apply_mask_offset(&mut masked_zero[..5], &mask, 0);
+        apply_mask_offset(&mut masked_zero[5..], &mask, 0);
+        assert_ne!(masked, masked_zero);
+
+        let mut masked_offset = unmasked.clone();
+        apply_mask_offset(&mut masked_offset[..5], &mask, 0);
+        apply_mask_offset(&mut masked_offset[5..], &mask, 5);
+        assert_eq!(masked, masked_offset);
+
+        // round trip
+        apply_mask_offset(&mut masked_offset[..30], &mask, 0);
+        apply_mask_offset(&mut masked_offset[30..], &mask, 30);
+        assert_eq!(masked_offset, unmasked);
+    }
+}
+
+/// Run with `cargo bench` on nightly compiler
+#[cfg(all(test, actix_nightly))]
+mod benches {
+    use testing::{black_box, Bencher};
+    use super::{apply_mask, apply_mask_fallback};
+
+    const MASK: [u8; 4] = [0x6d, 0xb6, 0xb2, 0x80];
+
+    fn bench_mask(b: &mut Bencher, size: usize, f: fn(&mut [u8], &[u8; 4])) {
+        let mut buf = vec![0u8; size];
+        b.bytes = size as u64;
+        b.iter(|| {
+            f(&mut buf, &MASK);
+            black_box(&buf);
+        });
+    }
+
+    #[bench]
+    fn bench_apply_mask_64(b: &mut Bencher) {
+        bench_mask(b, 64, apply_mask);
+    }
+
+    #[bench]
+    fn bench_apply_mask_4k(b: &mut Bencher) {
+        bench_mask(b, 4096, apply_mask);
+    }
+
+    #[bench]
+    fn bench_apply_mask_1m(b: &mut Bencher) {
+        bench_mask(b, 1 << 20, apply_mask);
+    }
+
+    #[bench]
+    fn bench_apply_mask_fallback_64(b: &mut Bencher) {
+        bench_mask(b, 64, apply_mask_fallback);
+    }
+
+    #[bench]
+    fn bench_apply_mask_fallback_4k(b: &mut Bencher) {
+        bench_mask(b, 4096, apply_mask_fallback);
+    }
+
+    #[bench]
+    fn bench_apply_mask_fallback_1m(b: &mut Bencher) {
+        bench_mask(b, 1 << 20, apply_mask_fallback);
+    }
 }
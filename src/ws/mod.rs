@@ -43,6 +43,9 @@
 //! #      .finish();
 //! # }
 //! ```
+use std::rc::Rc;
+use std::cell::Cell;
+use std::time::Instant;
 use bytes::BytesMut;
 use http::{Method, StatusCode, header};
 use futures::{Async, Poll, Stream};
@@ -60,17 +63,31 @@ mod proto;
 mod context;
 mod mask;
 mod client;
-
-use self::frame::Frame;
-use self::proto::{hash_key, OpCode};
-pub use self::proto::CloseCode;
-pub use self::context::WebsocketContext;
+mod deflate;
+mod framed;
+mod config;
+pub mod codec;
+#[cfg(feature = "test")]
+pub mod test;
+
+use self::frame::{FrameReassembler, parse_close_payload};
+use self::proto::{hash_key, verify_key};
+pub use self::frame::{Frame, FrameBuilder, FrameDecoder, FrameEncoder, FrameFragmenter,
+                      FrameObserver, FrameParts, FrameHead, FrameChunk, StreamingFrameDecoder,
+                      FrameRef, MessageDecoder, Decoded,
+                      MaskGenerator, RandomMask, FallbackMask, RSV1, RSV2, RSV3};
+pub use self::proto::{CloseCode, OpCode, InvalidOpCode, ProtocolError};
+pub use self::deflate::{PerMessageDeflate, NegotiatedExtensions, DeflateParams};
+pub use self::framed::{FrameStream, FrameSink};
+pub use self::config::{WsConfig, ControlFrameTooLongPolicy};
+pub use self::context::{WebsocketContext, CloseState, CloseWait};
 pub use self::client::{WsClient, WsClientError, WsClientReader, WsClientWriter, WsClientFuture};
 
 const SEC_WEBSOCKET_ACCEPT: &str = "SEC-WEBSOCKET-ACCEPT";
 const SEC_WEBSOCKET_KEY: &str = "SEC-WEBSOCKET-KEY";
 const SEC_WEBSOCKET_VERSION: &str = "SEC-WEBSOCKET-VERSION";
-// const SEC_WEBSOCKET_PROTOCOL: &'static str = "SEC-WEBSOCKET-PROTOCOL";
+const SEC_WEBSOCKET_EXTENSIONS: &str = "SEC-WEBSOCKET-EXTENSIONS";
+const SEC_WEBSOCKET_PROTOCOL: &str = "SEC-WEBSOCKET-PROTOCOL";
 
 
 /// `WebSocket` Message
@@ -80,25 +97,79 @@ pub enum Message {
     Binary(Binary),
     Ping(String),
     Pong(String),
-    Close,
+    Close(WsClose),
     Closed,
     Error
 }
 
+/// Closing of the connection by the peer, delivered
+/// as `Message::Close` right before `Message::Closed`
+#[derive(Debug, Clone, PartialEq)]
+pub struct WsClose {
+    /// Status code of the peer's Close frame, `CloseCode::Empty` if
+    /// it has no status code, `CloseCode::Abnormal` if connection
+    /// dropped without Close frame
+    pub code: CloseCode,
+    /// Close reason sent by the peer
+    pub reason: String,
+    /// Status code is other than `CloseCode::Normal`
+    pub abnormal: bool,
+}
+
+impl WsClose {
+    fn new(code: CloseCode, reason: String) -> WsClose {
+        WsClose {
+            code: code,
+            reason: reason,
+            abnormal: code != CloseCode::Normal,
+        }
+    }
+
+    fn from_payload(payload: &[u8]) -> WsClose {
+        let (code, reason) = parse_close_payload(payload)
+            .unwrap_or_else(|| (CloseCode::Empty, String::new()));
+        WsClose::new(code, reason)
+    }
+}
+
 impl ResponseType for Message {
     type Item = ();
     type Error = ();
 }
 
 /// Do websocket handshake and start actor
-pub fn start<A, S>(mut req: HttpRequest<S>, actor: A) -> Result<HttpResponse, Error>
+pub fn start<A, S>(req: HttpRequest<S>, actor: A) -> Result<HttpResponse, Error>
     where A: Actor<Context=WebsocketContext<A, S>> + Handler<Message>,
           S: 'static
 {
-    let mut resp = handshake(&req)?;
-    let stream = WsStream::new(req.payload_mut().readany());
+    start_with_protocols(req, actor, &[])
+}
+
+/// Do websocket handshake with subprotocol negotiation and start actor.
+///
+/// Selected protocol is available via `WebsocketContext::protocol()`.
+pub fn start_with_protocols<A, S>(mut req: HttpRequest<S>, actor: A, protocols: &[&str])
+                                  -> Result<HttpResponse, Error>
+    where A: Actor<Context=WebsocketContext<A, S>> + Handler<Message>,
+          S: 'static
+{
+    let mut resp = handshake_with_protocols(&req, protocols, false)?;
+    let protocol = select_protocol(&req, protocols).map(|p| p.to_owned());
+    let mut stream = WsStream::new(req.payload_mut().readany());
+
+    let extensions = negotiate_extensions(&req);
+    if let Some(ref deflate) = extensions.deflate {
+        resp.header(SEC_WEBSOCKET_EXTENSIONS, deflate.response_header().as_str());
+        stream = stream.deflate(PerMessageDeflate::with_params(deflate, true));
+    }
 
     let mut ctx = WebsocketContext::new(req, actor);
+    ctx.set_extensions(extensions);
+    ctx.set_protocol(protocol);
+    ctx.set_activity(stream.activity.clone());
+    ctx.set_failure(stream.failure.clone());
+    ctx.set_close_state(stream.close_state.clone());
+    ctx.set_peer_close(stream.peer_close.clone());
     ctx.add_message_stream(stream);
 
     Ok(resp.body(ctx)?)
@@ -108,11 +179,44 @@ pub fn start<A, S>(mut req: HttpRequest<S>, actor: A) -> Result<HttpResponse, Er
 ///
 /// This function returns handshake `HttpResponse`, ready to send to peer.
 /// It does not perform any IO.
-///
-// /// `protocols` is a sequence of known protocols. On successful handshake,
-// /// the returned response headers contain the first protocol in this list
-// /// which the server also knows.
 pub fn handshake<S>(req: &HttpRequest<S>) -> Result<HttpResponseBuilder, WsHandshakeError> {
+    handshake_with_protocols(req, &[], false)
+}
+
+/// Prepare `WebSocket` handshake response with additional headers.
+///
+/// `f` fills extra headers of the response, i.e. cookies. Headers required
+/// by the handshake (`Upgrade`, `Connection`, `Transfer-Encoding` and
+/// `Sec-WebSocket-Accept`) can not be overridden and are skipped.
+pub fn handshake_with_headers<S, F>(req: &HttpRequest<S>, f: F)
+                                    -> Result<HttpResponseBuilder, WsHandshakeError>
+    where F: FnOnce(&mut header::HeaderMap)
+{
+    let mut resp = handshake(req)?;
+    let mut headers = header::HeaderMap::new();
+    f(&mut headers);
+    for (name, value) in headers.iter() {
+        if *name == header::UPGRADE || *name == header::CONNECTION ||
+            *name == header::TRANSFER_ENCODING || *name == SEC_WEBSOCKET_ACCEPT
+        {
+            warn!("Can not override handshake header: {}", name);
+            continue
+        }
+        resp.header(name.clone(), value.as_bytes());
+    }
+    Ok(resp)
+}
+
+/// Prepare `WebSocket` handshake response with subprotocol negotiation.
+///
+/// `protocols` is a sequence of known protocols. On successful handshake,
+/// the returned response headers contain the first protocol offered by
+/// client which the server also knows. If none matches, response does not
+/// contain `Sec-WebSocket-Protocol` header, unless `strict` is set, then
+/// handshake fails.
+pub fn handshake_with_protocols<S>(req: &HttpRequest<S>, protocols: &[&str], strict: bool)
+                                   -> Result<HttpResponseBuilder, WsHandshakeError>
+{
     // WebSocket accepts only GET
     if *req.method() != Method::GET {
         return Err(WsHandshakeError::GetMethodRequired)
@@ -126,10 +230,10 @@ pub fn handshake<S>(req: &HttpRequest<S>) -> Result<HttpResponseBuilder, WsHands
             false
         }
     } else {
-        false
+        return Err(WsHandshakeError::NoWebsocketUpgrade)
     };
     if !has_hdr {
-        return Err(WsHandshakeError::NoWebsocketUpgrade)
+        return Err(WsHandshakeError::BadWebsocketUpgrade)
     }
 
     // Upgrade connection
@@ -143,7 +247,7 @@ pub fn handshake<S>(req: &HttpRequest<S>) -> Result<HttpResponseBuilder, WsHands
     }
     let supported_ver = {
         if let Some(hdr) = req.headers().get(SEC_WEBSOCKET_VERSION) {
-            hdr == "13" || hdr == "8" || hdr == "7"
+            hdr == "13"
         } else {
             false
         }
@@ -154,19 +258,59 @@ pub fn handshake<S>(req: &HttpRequest<S>) -> Result<HttpResponseBuilder, WsHands
 
     // check client handshake for validity
     if !req.headers().contains_key(SEC_WEBSOCKET_KEY) {
-        return Err(WsHandshakeError::BadWebsocketKey)
+        return Err(WsHandshakeError::NoWebsocketKey)
     }
     let key = {
         let key = req.headers().get(SEC_WEBSOCKET_KEY).unwrap();
+        if !verify_key(key.as_ref()) {
+            return Err(WsHandshakeError::BadWebsocketKey)
+        }
         hash_key(key.as_ref())
     };
 
-    Ok(HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS)
-       .connection_type(ConnectionType::Upgrade)
-       .header(header::UPGRADE, "websocket")
-       .header(header::TRANSFER_ENCODING, "chunked")
-       .header(SEC_WEBSOCKET_ACCEPT, key.as_str())
-       .take())
+    let protocol = select_protocol(req, protocols);
+    if protocol.is_none() && strict && !protocols.is_empty() {
+        return Err(WsHandshakeError::UnsupportedProtocol)
+    }
+
+    let mut resp = HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS);
+    resp.connection_type(ConnectionType::Upgrade)
+        .header(header::UPGRADE, "websocket")
+        .header(header::TRANSFER_ENCODING, "chunked")
+        .header(SEC_WEBSOCKET_ACCEPT, key.as_str());
+    if let Some(protocol) = protocol {
+        resp.header(SEC_WEBSOCKET_PROTOCOL, protocol);
+    }
+    Ok(resp.take())
+}
+
+/// Select first protocol offered by client in `Sec-WebSocket-Protocol`
+/// header which is also in `protocols` list
+pub fn select_protocol<'a, S>(req: &HttpRequest<S>, protocols: &[&'a str]) -> Option<&'a str> {
+    for hdr in req.headers().get_all(SEC_WEBSOCKET_PROTOCOL) {
+        if let Ok(s) = hdr.to_str() {
+            for offered in s.split(',').map(|p| p.trim()) {
+                if let Some(p) = protocols.iter().find(|p| **p == offered) {
+                    return Some(*p)
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Select extensions offered by client in `Sec-WebSocket-Extensions` header
+pub fn negotiate_extensions<S>(req: &HttpRequest<S>) -> NegotiatedExtensions {
+    let mut extensions = NegotiatedExtensions::default();
+    for hdr in req.headers().get_all(SEC_WEBSOCKET_EXTENSIONS) {
+        if let Ok(s) = hdr.to_str() {
+            let ext = NegotiatedExtensions::parse(s);
+            if extensions.deflate.is_none() {
+                extensions.deflate = ext.deflate;
+            }
+        }
+    }
+    extensions
 }
 
 /// Maps `Payload` stream into stream of `ws::Message` items
@@ -175,6 +319,14 @@ pub struct WsStream {
     buf: BytesMut,
     closed: bool,
     error_sent: bool,
+    close_sent: bool,
+    decoder: FrameDecoder,
+    reassembler: FrameReassembler,
+    config: WsConfig,
+    activity: Rc<Cell<Instant>>,
+    failure: Rc<Cell<Option<CloseCode>>>,
+    close_state: Rc<Cell<CloseState>>,
+    peer_close: Rc<Cell<Option<CloseCode>>>,
 }
 
 impl WsStream {
@@ -182,7 +334,58 @@ impl WsStream {
         WsStream { rx: payload,
                    buf: BytesMut::new(),
                    closed: false,
-                   error_sent: false }
+                   error_sent: false,
+                   close_sent: false,
+                   decoder: FrameDecoder::new(WsConfig::server()),
+                   reassembler: FrameReassembler::new(WsConfig::server()),
+                   config: WsConfig::server(),
+                   activity: Rc::new(Cell::new(Instant::now())),
+                   failure: Rc::new(Cell::new(None)),
+                   close_state: Rc::new(Cell::new(CloseState::Open)),
+                   peer_close: Rc::new(Cell::new(None)) }
+    }
+
+    /// Set max frame size
+    ///
+    /// By default max size is set to 64kb. Same limit applies
+    /// to messages reassembled from fragmented frames.
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.config.max_frame_size = size;
+        self.config.max_message_size = size;
+        self.apply_config()
+    }
+
+    /// Inflate compressed messages with negotiated `permessage-deflate` context
+    pub fn deflate(mut self, deflate: PerMessageDeflate) -> Self {
+        self.config.deflate = true;
+        self.reassembler.set_deflate(deflate);
+        self.apply_config()
+    }
+
+    /// Set protocol settings, role of the settings is ignored
+    /// since stream is always a server side
+    pub fn config(mut self, config: WsConfig) -> Self {
+        self.config = WsConfig{server: true, ..config};
+        self.apply_config()
+    }
+
+    fn apply_config(mut self) -> Self {
+        self.decoder.set_config(self.config);
+        self.reassembler.set_config(self.config);
+        if self.buf.is_empty() {
+            self.buf = self.decoder.buffer();
+        }
+        self
+    }
+
+    /// Record protocol violation, context fails the connection
+    /// with corresponding close code
+    fn fail(&mut self, err: ProtocolError) -> Poll<Option<Message>, ()> {
+        debug!("WebSocket protocol error: {}", err);
+        self.closed = true;
+        self.error_sent = true;
+        self.failure.set(Some(err.close_code()));
+        Ok(Async::Ready(Some(Message::Error)))
     }
 }
 
@@ -214,19 +417,28 @@ impl Stream for WsStream {
         }
 
         loop {
-            match Frame::parse(&mut self.buf, true) {
+            match self.decoder.decode(&mut self.buf) {
                 Ok(Some(frame)) => {
                     // trace!("WsFrame {}", frame);
-                    let (_finished, opcode, payload) = frame.unpack();
+                    self.activity.set(Instant::now());
+                    let (opcode, payload) = match self.reassembler.push(frame) {
+                        Ok(Some(msg)) => msg,
+                        Ok(None) => continue,
+                        Err(err) => return self.fail(err),
+                    };
 
                     match opcode {
-                        OpCode::Continue => continue,
-                        OpCode::Bad =>
-                            return Ok(Async::Ready(Some(Message::Error))),
+                        OpCode::Continue | OpCode::Bad =>
+                            return self.fail(ProtocolError::Internal),
+                        OpCode::Reserved(n) =>
+                            return self.fail(ProtocolError::InvalidOpcode(InvalidOpCode(n))),
                         OpCode::Close => {
                             self.closed = true;
-                            self.error_sent = true;
-                            return Ok(Async::Ready(Some(Message::Closed)))
+                            self.close_sent = true;
+                            self.close_state.set(self.close_state.get().received());
+                            let close = WsClose::from_payload(payload.as_ref());
+                            self.peer_close.set(Some(close.code));
+                            return Ok(Async::Ready(Some(Message::Close(close))))
                         },
                         OpCode::Ping =>
                             return Ok(Async::Ready(Some(
@@ -244,12 +456,18 @@ impl Stream for WsStream {
                                 Ok(s) =>
                                     return Ok(Async::Ready(Some(Message::Text(s)))),
                                 Err(_) =>
-                                    return Ok(Async::Ready(Some(Message::Error))),
+                                    return self.fail(ProtocolError::InvalidUtf8),
                             }
                         }
                     }
                 }
                 Ok(None) => {
+                    if (done || self.closed) && !self.close_sent && !self.error_sent {
+                        // connection dropped without Close frame
+                        self.close_sent = true;
+                        let close = WsClose::new(CloseCode::Abnormal, String::new());
+                        return Ok(Async::Ready(Some(Message::Close(close))))
+                    }
                     if done {
                         return Ok(Async::Ready(None))
                     } else if self.closed {
@@ -263,11 +481,7 @@ impl Stream for WsStream {
                         return Ok(Async::NotReady)
                     }
                 },
-                Err(_) => {
-                    self.closed = true;
-                    self.error_sent = true;
-                    return Ok(Async::Ready(Some(Message::Error)));
-                }
+                Err(err) => return self.fail(err),
             }
         }
     }
@@ -277,7 +491,100 @@ impl Stream for WsStream {
 mod tests {
     use super::*;
     use std::str::FromStr;
+    use bytes::Bytes;
+    use futures::future::{lazy, result};
+    use tokio_core::reactor::Core;
     use http::{Method, HeaderMap, Version, Uri, header};
+    use error::ResponseError;
+    use payload::{Payload, PayloadWriter};
+
+    fn poll_messages(data: &[u8], eof: bool) -> Vec<Message> {
+        let data = Bytes::from(data);
+        Core::new().unwrap().run(lazy(move || {
+            let (mut sender, payload) = Payload::new(false);
+            sender.feed_data(data);
+            if eof {
+                sender.feed_eof();
+            }
+            let mut stream = WsStream::new(payload.readany());
+            let mut messages = Vec::new();
+            while let Ok(Async::Ready(Some(msg))) = stream.poll() {
+                messages.push(msg);
+            }
+            result::<_, ()>(Ok(messages))
+        })).unwrap()
+    }
+
+    #[test]
+    fn test_message_size_limit() {
+        let mut data = Vec::new();
+        data.extend_from_slice(Frame::message("01234567", OpCode::Binary, false, true).as_ref());
+        for _ in 0..2 {
+            data.extend_from_slice(
+                Frame::message("01234567", OpCode::Continue, false, true).as_ref());
+        }
+        let data = Bytes::from(data);
+
+        Core::new().unwrap().run(lazy(move || {
+            let (mut sender, payload) = Payload::new(false);
+            sender.feed_data(data);
+            let cfg = WsConfig{max_frame_size: 8, max_message_size: 16, ..WsConfig::server()};
+            let mut stream = WsStream::new(payload.readany()).config(cfg);
+
+            // third fragment exceeds the limit, final fragment is not awaited
+            match stream.poll() {
+                Ok(Async::Ready(Some(Message::Error))) => (),
+                res => panic!("unexpected result: {:?}", res),
+            }
+            assert_eq!(stream.failure.get(), Some(CloseCode::Size));
+            result::<_, ()>(Ok(()))
+        })).unwrap();
+    }
+
+    #[test]
+    fn test_close_clean() {
+        let frame = Frame::close(CloseCode::Normal, "done", true);
+        let messages = poll_messages(frame.as_ref(), false);
+        assert_eq!(messages, vec![
+            Message::Close(WsClose{code: CloseCode::Normal, reason: "done".to_owned(),
+                                   abnormal: false}),
+            Message::Closed]);
+    }
+
+    #[test]
+    fn test_close_abnormal() {
+        let frame = Frame::close(CloseCode::Error, "oops", true);
+        let messages = poll_messages(frame.as_ref(), false);
+        assert_eq!(messages[0], Message::Close(
+            WsClose{code: CloseCode::Error, reason: "oops".to_owned(), abnormal: true}));
+
+        let frame = Frame::close(CloseCode::Policy, "", true);
+        let messages = poll_messages(frame.as_ref(), false);
+        assert_eq!(messages[0], Message::Close(
+            WsClose{code: CloseCode::Policy, reason: String::new(), abnormal: true}));
+
+        // Close frame without status code
+        let frame = Frame::close(CloseCode::Empty, "", true);
+        match poll_messages(frame.as_ref(), false)[0] {
+            Message::Close(ref close) => assert_eq!(close.code, CloseCode::Empty),
+            ref msg => panic!("unexpected message: {:?}", msg),
+        }
+
+        // reserved status code sent by peer
+        let frame = Frame::close(CloseCode::Other(1006), "", true);
+        let messages = poll_messages(frame.as_ref(), false);
+        assert_eq!(messages[0], Message::Close(
+            WsClose{code: CloseCode::Reserved(1006), reason: String::new(), abnormal: true}));
+
+        // connection dropped without Close frame
+        let frame = Frame::message("text", OpCode::Text, true, true);
+        let messages = poll_messages(frame.as_ref(), true);
+        assert_eq!(messages, vec![
+            Message::Text("text".to_owned()),
+            Message::Close(WsClose{code: CloseCode::Abnormal, reason: String::new(),
+                                   abnormal: true}),
+            Message::Closed]);
+    }
 
     #[test]
     fn test_handshake() {
@@ -294,7 +601,7 @@ mod tests {
                        header::HeaderValue::from_static("test"));
         let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
                                    Version::HTTP_11, headers, None);
-        assert_eq!(WsHandshakeError::NoWebsocketUpgrade, handshake(&req).err().unwrap());
+        assert_eq!(WsHandshakeError::BadWebsocketUpgrade, handshake(&req).err().unwrap());
 
         let mut headers = HeaderMap::new();
         headers.insert(header::UPGRADE,
@@ -311,6 +618,9 @@ mod tests {
         let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
                                    Version::HTTP_11, headers, None);
         assert_eq!(WsHandshakeError::NoVersionHeader, handshake(&req).err().unwrap());
+        let resp = handshake(&req).err().unwrap().error_response();
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(resp.headers().get(SEC_WEBSOCKET_VERSION).unwrap(), "13");
 
         let mut headers = HeaderMap::new();
         headers.insert(header::UPGRADE,
@@ -322,6 +632,23 @@ mod tests {
         let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
                                    Version::HTTP_11, headers, None);
         assert_eq!(WsHandshakeError::UnsupportedVersion, handshake(&req).err().unwrap());
+        let resp = handshake(&req).err().unwrap().error_response();
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(resp.headers().get(SEC_WEBSOCKET_VERSION).unwrap(), "13");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE,
+                       header::HeaderValue::from_static("websocket"));
+        headers.insert(header::CONNECTION,
+                       header::HeaderValue::from_static("upgrade"));
+        headers.insert(SEC_WEBSOCKET_VERSION,
+                       header::HeaderValue::from_static("8"));
+        let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
+                                   Version::HTTP_11, headers, None);
+        assert_eq!(WsHandshakeError::UnsupportedVersion, handshake(&req).err().unwrap());
+        let resp = handshake(&req).err().unwrap().error_response();
+        assert_eq!(resp.status(), StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(resp.headers().get(SEC_WEBSOCKET_VERSION).unwrap(), "13");
 
         let mut headers = HeaderMap::new();
         headers.insert(header::UPGRADE,
@@ -332,7 +659,9 @@ mod tests {
                        header::HeaderValue::from_static("13"));
         let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
                                    Version::HTTP_11, headers, None);
-        assert_eq!(WsHandshakeError::BadWebsocketKey, handshake(&req).err().unwrap());
+        assert_eq!(WsHandshakeError::NoWebsocketKey, handshake(&req).err().unwrap());
+        assert_eq!(StatusCode::BAD_REQUEST,
+                   handshake(&req).err().unwrap().error_response().status());
 
         let mut headers = HeaderMap::new();
         headers.insert(header::UPGRADE,
@@ -345,7 +674,113 @@ mod tests {
                        header::HeaderValue::from_static("13"));
         let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
                                    Version::HTTP_11, headers, None);
-        assert_eq!(StatusCode::SWITCHING_PROTOCOLS,
-                   handshake(&req).unwrap().finish().unwrap().status());
+        assert_eq!(WsHandshakeError::BadWebsocketKey, handshake(&req).err().unwrap());
+        assert_eq!(StatusCode::BAD_REQUEST,
+                   handshake(&req).err().unwrap().error_response().status());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE,
+                       header::HeaderValue::from_static("websocket"));
+        headers.insert(header::CONNECTION,
+                       header::HeaderValue::from_static("upgrade"));
+        headers.insert(SEC_WEBSOCKET_VERSION,
+                       header::HeaderValue::from_static("13"));
+        headers.insert(SEC_WEBSOCKET_KEY,
+                       header::HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="));
+        let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
+                                   Version::HTTP_11, headers, None);
+        let resp = handshake(&req).unwrap().finish().unwrap();
+        assert_eq!(StatusCode::SWITCHING_PROTOCOLS, resp.status());
+        assert_eq!(resp.headers().get(SEC_WEBSOCKET_ACCEPT).unwrap(),
+                   "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_negotiate_extensions() {
+        let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
+                                   Version::HTTP_11, HeaderMap::new(), None);
+        assert_eq!(negotiate_extensions(&req), NegotiatedExtensions::default());
+
+        let mut headers = HeaderMap::new();
+        headers.append(SEC_WEBSOCKET_EXTENSIONS,
+                       header::HeaderValue::from_static("x-webkit-deflate-frame"));
+        headers.append(SEC_WEBSOCKET_EXTENSIONS,
+                       header::HeaderValue::from_static(
+                           "permessage-deflate; client_max_window_bits=12"));
+        let req = HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
+                                   Version::HTTP_11, headers, None);
+        let deflate = negotiate_extensions(&req).deflate.unwrap();
+        assert_eq!(deflate.client_max_window_bits, 12);
+        assert!(!deflate.server_no_context_takeover);
+    }
+
+    fn protocol_request(offered: Option<&'static str>) -> HttpRequest {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::UPGRADE,
+                       header::HeaderValue::from_static("websocket"));
+        headers.insert(header::CONNECTION,
+                       header::HeaderValue::from_static("upgrade"));
+        headers.insert(SEC_WEBSOCKET_VERSION,
+                       header::HeaderValue::from_static("13"));
+        headers.insert(SEC_WEBSOCKET_KEY,
+                       header::HeaderValue::from_static("dGhlIHNhbXBsZSBub25jZQ=="));
+        if let Some(offered) = offered {
+            headers.insert(SEC_WEBSOCKET_PROTOCOL,
+                           header::HeaderValue::from_static(offered));
+        }
+        HttpRequest::new(Method::GET, Uri::from_str("/").unwrap(),
+                         Version::HTTP_11, headers, None)
+    }
+
+    #[test]
+    fn test_handshake_with_headers() {
+        let req = protocol_request(None);
+        let resp = handshake_with_headers(&req, |headers| {
+            headers.insert(header::SET_COOKIE,
+                           header::HeaderValue::from_static("session=1; Path=/"));
+            headers.insert(header::UPGRADE, header::HeaderValue::from_static("h2c"));
+            headers.insert(SEC_WEBSOCKET_ACCEPT, header::HeaderValue::from_static("key"));
+        }).unwrap().finish().unwrap();
+
+        assert_eq!(StatusCode::SWITCHING_PROTOCOLS, resp.status());
+        assert_eq!(resp.headers().get(header::SET_COOKIE).unwrap(), "session=1; Path=/");
+        let upgrade: Vec<_> = resp.headers().get_all(header::UPGRADE).iter().collect();
+        assert_eq!(upgrade, vec!["websocket"]);
+        let accept: Vec<_> = resp.headers().get_all(SEC_WEBSOCKET_ACCEPT).iter().collect();
+        assert_eq!(accept, vec!["s3pPLMBiTxaQ9kYGzzhZRbK+xOo="]);
+        assert!(resp.upgrade());
+
+        let req = HttpRequest::new(Method::POST, Uri::from_str("/").unwrap(),
+                                   Version::HTTP_11, HeaderMap::new(), None);
+        assert_eq!(handshake_with_headers(&req, |_| ()).err().unwrap(),
+                   WsHandshakeError::GetMethodRequired);
+    }
+
+    #[test]
+    fn test_handshake_protocols() {
+        // matching protocol
+        let req = protocol_request(Some("v2.chat, v1.chat"));
+        assert_eq!(select_protocol(&req, &["v1.chat", "v2.chat"]), Some("v2.chat"));
+        let resp = handshake_with_protocols(&req, &["v1.chat", "v2.chat"], true)
+            .unwrap().finish().unwrap();
+        assert_eq!(resp.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(), "v2.chat");
+
+        // no matching protocol
+        let req = protocol_request(Some("v3.chat"));
+        assert_eq!(select_protocol(&req, &["v1.chat"]), None);
+        let resp = handshake_with_protocols(&req, &["v1.chat"], false)
+            .unwrap().finish().unwrap();
+        assert!(resp.headers().get(SEC_WEBSOCKET_PROTOCOL).is_none());
+        assert_eq!(WsHandshakeError::UnsupportedProtocol,
+                   handshake_with_protocols(&req, &["v1.chat"], true).err().unwrap());
+
+        // client offers none
+        let req = protocol_request(None);
+        let resp = handshake_with_protocols(&req, &["v1.chat"], false)
+            .unwrap().finish().unwrap();
+        assert!(resp.headers().get(SEC_WEBSOCKET_PROTOCOL).is_none());
+        assert!(handshake_with_protocols(&req, &["v1.chat"], true).is_err());
+        assert!(handshake(&req).unwrap().finish().unwrap()
+                .headers().get(SEC_WEBSOCKET_PROTOCOL).is_none());
     }
 }
@@ -1,12 +1,15 @@
-use std::fmt;
+use std::{fmt, io};
 use std::convert::{Into, From};
 use sha1;
 use base64;
+use failure::Fail;
+
+use ws::codec::CodecError;
 
 use self::OpCode::*;
 /// Operation codes as part of rfc6455.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
-pub(crate) enum OpCode {
+pub enum OpCode {
     /// Indicates a continuation frame of a fragmented message.
     Continue,
     /// Indicates a text data frame.
@@ -21,6 +24,8 @@ pub(crate) enum OpCode {
     Pong,
     /// Indicates an invalid opcode was received.
     Bad,
+    /// Reserved data opcode (0x3-0x7) enabled by `WsConfig::reserved_opcodes`.
+    Reserved(u8),
 }
 
 impl fmt::Display for OpCode {
@@ -33,6 +38,7 @@ impl fmt::Display for OpCode {
             Ping       =>   write!(f, "PING"),
             Pong       =>   write!(f, "PONG"),
             Bad        =>   write!(f, "BAD"),
+            Reserved(n) =>  write!(f, "RESERVED({})", n),
         }
     }
 }
@@ -51,6 +57,31 @@ impl Into<u8> for OpCode {
                 debug_assert!(false, "Attempted to convert invalid opcode to u8. This is a bug.");
                 8  // if this somehow happens, a close frame will help us tear down quickly
             }
+            Reserved(n) =>  n,
+        }
+    }
+}
+
+impl OpCode {
+
+    /// Convert byte to opcode, reserved opcodes are reported as error
+    pub fn try_from(byte: u8) -> Result<OpCode, InvalidOpCode> {
+        match byte {
+            0   =>   Ok(Continue),
+            1   =>   Ok(Text),
+            2   =>   Ok(Binary),
+            8   =>   Ok(Close),
+            9   =>   Ok(Ping),
+            10  =>   Ok(Pong),
+            _   =>   Err(InvalidOpCode(byte))
+        }
+    }
+
+    /// Is this a control frame opcode: Close, Ping or Pong
+    pub fn is_control(&self) -> bool {
+        match *self {
+            Close | Ping | Pong => true,
+            _ => false,
         }
     }
 }
@@ -58,18 +89,141 @@ impl Into<u8> for OpCode {
 impl From<u8> for OpCode {
 
     fn from(byte: u8) -> OpCode {
-        match byte {
-            0   =>   Continue,
-            1   =>   Text,
-            2   =>   Binary,
-            8   =>   Close,
-            9   =>   Ping,
-            10  =>   Pong,
-            _   =>   Bad
+        OpCode::try_from(byte).unwrap_or(Bad)
+    }
+}
+
+/// Reserved or unknown opcode
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct InvalidOpCode(pub u8);
+
+impl InvalidOpCode {
+    /// Is opcode from reserved control frames range (0xB-0xF)
+    pub fn is_control(&self) -> bool {
+        self.0 & 0x08 != 0
+    }
+}
+
+impl fmt::Display for InvalidOpCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_control() {
+            write!(f, "reserved control opcode: {}", self.0)
+        } else {
+            write!(f, "reserved data opcode: {}", self.0)
         }
     }
 }
 
+/// Frame level protocol violation
+#[derive(Fail, Debug, Eq, PartialEq, Clone, Copy)]
+pub enum ProtocolError {
+    /// Server received a frame without masking key
+    #[fail(display="Received an unmasked frame from client")]
+    UnmaskedClientFrame,
+    /// Client received a masked frame
+    #[fail(display="Received a masked frame from server")]
+    MaskedServerFrame,
+    /// Reserved bit is set without negotiated extension
+    #[fail(display="Received a frame with unexpected reserved bits set")]
+    ReservedBitSet,
+    /// Reserved or unknown opcode
+    #[fail(display="Encountered {}", _0)]
+    InvalidOpcode(InvalidOpCode),
+    /// Control frame without `fin` bit set
+    #[fail(display="Fragmented control frame")]
+    FragmentedControlFrame,
+    /// Control frame payload is longer than 125 bytes
+    #[fail(display="Received control frame with length: {}", _0)]
+    ControlFrameTooLong(usize),
+    /// Close frame payload of one byte, status code takes two bytes
+    #[fail(display="Received close frame with truncated status code")]
+    InvalidClosePayload,
+    /// Frame payload is larger than configured max frame size
+    #[fail(display="Frame too large")]
+    FrameTooLarge,
+    /// 64-bit frame length does not fit into usize
+    #[fail(display="Frame length exceeds platform usize")]
+    LengthOverflow,
+    /// Payload length uses longer encoding than necessary
+    #[fail(display="Frame length is not minimally encoded")]
+    NonMinimalLength,
+    /// Continuation frame without preceding data frame
+    #[fail(display="Unexpected continuation frame")]
+    UnexpectedContinuation,
+    /// New data frame before the final fragment of previous message
+    #[fail(display="Received new data frame in the middle of fragmented message")]
+    UnfinishedMessage,
+    /// Control frame with `rsv1` bit set
+    #[fail(display="Received compressed control frame")]
+    CompressedControlFrame,
+    /// Continuation frame with `rsv1` bit set
+    #[fail(display="Received continuation frame with reserved bit set")]
+    CompressedContinuation,
+    /// Compressed message without negotiated `permessage-deflate`
+    #[fail(display="Received compressed frame without negotiated extension")]
+    UnnegotiatedCompression,
+    /// Reassembled or inflated message is larger than configured max message size
+    #[fail(display="Message too large")]
+    MessageTooLarge,
+    /// Message consists of more fragments than configured max number of fragments
+    #[fail(display="Too many message fragments")]
+    TooManyFragments,
+    /// Text message is not valid UTF-8
+    #[fail(display="Invalid UTF-8 in text message")]
+    InvalidUtf8,
+    /// Compressed message payload can not be inflated
+    #[fail(display="Can not inflate message payload")]
+    InvalidCompressedData,
+    /// Unexpected failure while processing the message
+    #[fail(display="Internal error while processing message")]
+    Internal,
+}
+
+impl ProtocolError {
+    /// Close code to fail the connection with
+    ///
+    /// Oversized frames and messages map to `1009`, invalid text or
+    /// compressed payload to `1007`, internal failures to `1011`, any
+    /// other violation to `1002`.
+    pub fn close_code(&self) -> CloseCode {
+        match *self {
+            ProtocolError::FrameTooLarge |
+            ProtocolError::LengthOverflow |
+            ProtocolError::MessageTooLarge |
+            ProtocolError::TooManyFragments => CloseCode::Size,
+            ProtocolError::InvalidUtf8 |
+            ProtocolError::InvalidCompressedData => CloseCode::Invalid,
+            ProtocolError::Internal => CloseCode::Error,
+            _ => CloseCode::Protocol,
+        }
+    }
+}
+
+impl From<CodecError> for ProtocolError {
+    fn from(err: CodecError) -> ProtocolError {
+        match err {
+            CodecError::UnmaskedClientFrame => ProtocolError::UnmaskedClientFrame,
+            CodecError::MaskedServerFrame => ProtocolError::MaskedServerFrame,
+            CodecError::ReservedBitSet => ProtocolError::ReservedBitSet,
+            CodecError::FrameTooLarge => ProtocolError::FrameTooLarge,
+            CodecError::LengthOverflow => ProtocolError::LengthOverflow,
+            CodecError::BufferTooSmall => ProtocolError::Internal,
+        }
+    }
+}
+
+impl From<CodecError> for io::Error {
+    fn from(err: CodecError) -> io::Error {
+        ProtocolError::from(err).into()
+    }
+}
+
+impl From<ProtocolError> for io::Error {
+    fn from(err: ProtocolError) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, err.compat())
+    }
+}
+
 use self::CloseCode::*;
 /// Status code used to indicate why an endpoint is closing the `WebSocket` connection.
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
@@ -132,14 +286,67 @@ pub enum CloseCode {
     /// to a different IP (when multiple targets exist), or reconnect to the same IP
     /// when a user has performed an action.
     Again,
+    /// Indicates that the server was acting as a gateway or proxy and received
+    /// an invalid response from the upstream server.
+    BadGateway,
     #[doc(hidden)]
     Tls,
     #[doc(hidden)]
     Empty,
+    /// Reserved code which must not be sent over the wire, such as
+    /// 1005, 1006 or 1015, received from the peer.
+    Reserved(u16),
+    /// Application defined code from private range 4000-4999.
+    Private(u16),
+    /// Code not assigned by RFC 6455 and outside of registered and private
+    /// ranges (1-999, 1004, 1016-2999 and above 4999).
+    InvalidCode(u16),
     #[doc(hidden)]
     Other(u16),
 }
 
+impl CloseCode {
+
+    /// Convert close code received from the peer.
+    ///
+    /// Codes reserved for local use (1005, 1006 and 1015) are
+    /// mapped to `CloseCode::Reserved`.
+    pub fn from_wire(code: u16) -> CloseCode {
+        match code {
+            1005 | 1006 | 1015 => Reserved(code),
+            _ => CloseCode::from(code),
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Normal        =>   "Normal",
+            Away          =>   "Going Away",
+            Protocol      =>   "Protocol Error",
+            Unsupported   =>   "Unsupported Data",
+            Status        =>   "No Status Received",
+            Abnormal      =>   "Abnormal Closure",
+            Invalid       =>   "Invalid Payload Data",
+            Policy        =>   "Policy Violation",
+            Size          =>   "Message Too Big",
+            Extension     =>   "Mandatory Extension",
+            Error         =>   "Internal Error",
+            Restart       =>   "Service Restart",
+            Again         =>   "Try Again Later",
+            BadGateway    =>   "Bad Gateway",
+            Tls           =>   "TLS Handshake",
+            Empty         =>   return write!(f, "Empty"),
+            Reserved(_)   =>   "Reserved",
+            Private(_)    =>   "Private",
+            InvalidCode(_) =>  "Invalid Code",
+            Other(_)      =>   "Other",
+        };
+        write!(f, "{} ({})", name, Into::<u16>::into(*self))
+    }
+}
+
 impl Into<u16> for CloseCode {
 
     fn into(self) -> u16 {
@@ -157,8 +364,12 @@ impl Into<u16> for CloseCode {
            Error         =>   1011,
            Restart       =>   1012,
            Again         =>   1013,
+           BadGateway    =>   1014,
            Tls           =>   1015,
            Empty         =>   0,
+           Reserved(code) =>  code,
+           Private(code) =>   code,
+           InvalidCode(code) => code,
            Other(code)   =>   code,
         }
     }
@@ -181,13 +392,24 @@ impl From<u16> for CloseCode {
             1011 => Error,
             1012 => Restart,
             1013 => Again,
+            1014 => BadGateway,
             1015 => Tls,
             0    => Empty,
-            _ => Other(code),
+            4000...4999 => Private(code),
+            3000...3999 => Other(code),
+            _ => InvalidCode(code),
         }
     }
 }
 
+/// Check if close code is allowed to be sent over the wire
+pub(crate) fn is_valid_close_code(code: u16) -> bool {
+    match code {
+        1000...1003 | 1007...1014 | 3000...4999 => true,
+        _ => false,
+    }
+}
+
 static WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
 // TODO: hash is always same size, we dont need String
@@ -200,6 +422,14 @@ pub(crate) fn hash_key(key: &[u8]) -> String {
     base64::encode(&hasher.digest().bytes())
 }
 
+/// Check that `Sec-WebSocket-Key` is a base64-encoded 16 bytes value
+pub(crate) fn verify_key(key: &[u8]) -> bool {
+    match base64::decode(key) {
+        Ok(nonce) => nonce.len() == 16,
+        Err(_) => false,
+    }
+}
+
 
 #[cfg(test)]
 mod test {
@@ -236,6 +466,35 @@ mod test {
         opcode_into!(99 => OpCode::Bad);
     }
 
+    #[test]
+    fn test_try_from_opcode() {
+        assert_eq!(OpCode::try_from(0), Ok(OpCode::Continue));
+        assert_eq!(OpCode::try_from(1), Ok(OpCode::Text));
+        assert_eq!(OpCode::try_from(2), Ok(OpCode::Binary));
+        assert_eq!(OpCode::try_from(8), Ok(OpCode::Close));
+        assert_eq!(OpCode::try_from(9), Ok(OpCode::Ping));
+        assert_eq!(OpCode::try_from(10), Ok(OpCode::Pong));
+
+        let err = OpCode::try_from(3).err().unwrap();
+        assert_eq!(err, InvalidOpCode(3));
+        assert!(!err.is_control());
+        let err = OpCode::try_from(0xB).err().unwrap();
+        assert_eq!(err, InvalidOpCode(0xB));
+        assert!(err.is_control());
+    }
+
+    #[test]
+    fn test_is_control() {
+        assert!(!OpCode::Continue.is_control());
+        assert!(!OpCode::Text.is_control());
+        assert!(!OpCode::Binary.is_control());
+        assert!(OpCode::Close.is_control());
+        assert!(OpCode::Ping.is_control());
+        assert!(OpCode::Pong.is_control());
+        assert!(!OpCode::Bad.is_control());
+        assert!(!OpCode::Reserved(3).is_control());
+    }
+
     #[test]
     fn test_from_opcode() {
         opcode_from!(OpCode::Continue => 0);
@@ -244,6 +503,7 @@ mod test {
         opcode_from!(OpCode::Close => 8);
         opcode_from!(OpCode::Ping => 9);
         opcode_from!(OpCode::Pong => 10);
+        opcode_from!(OpCode::Reserved(3) => 3);
     }
 
     #[test]
@@ -261,6 +521,7 @@ mod test {
         assert_eq!(format!("{}", OpCode::Ping), "PING");
         assert_eq!(format!("{}", OpCode::Pong), "PONG");
         assert_eq!(format!("{}", OpCode::Bad), "BAD");
+        assert_eq!(format!("{}", OpCode::Reserved(3)), "RESERVED(3)");
     }
 
     #[test]
@@ -278,9 +539,10 @@ mod test {
         assert_eq!(CloseCode::from(1011u16), CloseCode::Error);
         assert_eq!(CloseCode::from(1012u16), CloseCode::Restart);
         assert_eq!(CloseCode::from(1013u16), CloseCode::Again);
+        assert_eq!(CloseCode::from(1014u16), CloseCode::BadGateway);
         assert_eq!(CloseCode::from(1015u16), CloseCode::Tls);
         assert_eq!(CloseCode::from(0u16), CloseCode::Empty);
-        assert_eq!(CloseCode::from(2000u16), CloseCode::Other(2000));
+        assert_eq!(CloseCode::from(2000u16), CloseCode::InvalidCode(2000));
     }
 
     #[test]
@@ -298,8 +560,137 @@ mod test {
         assert_eq!(1011u16, Into::<u16>::into(CloseCode::Error));
         assert_eq!(1012u16, Into::<u16>::into(CloseCode::Restart));
         assert_eq!(1013u16, Into::<u16>::into(CloseCode::Again));
+        assert_eq!(1014u16, Into::<u16>::into(CloseCode::BadGateway));
         assert_eq!(1015u16, Into::<u16>::into(CloseCode::Tls));
         assert_eq!(0u16, Into::<u16>::into(CloseCode::Empty));
         assert_eq!(2000u16, Into::<u16>::into(CloseCode::Other(2000)));
+        assert_eq!(1005u16, Into::<u16>::into(CloseCode::Reserved(1005)));
+    }
+
+    #[test]
+    fn closecode_display() {
+        assert_eq!(format!("{}", CloseCode::Normal), "Normal (1000)");
+        assert_eq!(format!("{}", CloseCode::Away), "Going Away (1001)");
+        assert_eq!(format!("{}", CloseCode::Policy), "Policy Violation (1008)");
+        assert_eq!(format!("{}", CloseCode::Other(4001)), "Other (4001)");
+        assert_eq!(format!("{}", CloseCode::Private(4001)), "Private (4001)");
+        assert_eq!(format!("{}", CloseCode::InvalidCode(5000)), "Invalid Code (5000)");
+        assert_eq!(format!("{}", CloseCode::Empty), "Empty");
+    }
+
+    #[test]
+    fn closecode_round_trip() {
+        for code in (1000u16..1016).chain(3000..5000) {
+            assert_eq!(code, Into::<u16>::into(CloseCode::from(code)));
+            assert_eq!(code, Into::<u16>::into(CloseCode::from_wire(code)));
+        }
+        assert_eq!(CloseCode::from(3000u16), CloseCode::Other(3000));
+        assert_eq!(CloseCode::from(3999u16), CloseCode::Other(3999));
+        assert_eq!(CloseCode::from(4999u16), CloseCode::Private(4999));
+    }
+
+    #[test]
+    fn closecode_range_boundaries() {
+        let cases = [
+            (999u16, CloseCode::InvalidCode(999)),
+            (1000, CloseCode::Normal),
+            (1004, CloseCode::InvalidCode(1004)),
+            (1015, CloseCode::Tls),
+            (1016, CloseCode::InvalidCode(1016)),
+            (2999, CloseCode::InvalidCode(2999)),
+            (3000, CloseCode::Other(3000)),
+            (3999, CloseCode::Other(3999)),
+            (4000, CloseCode::Private(4000)),
+            (4999, CloseCode::Private(4999)),
+            (5000, CloseCode::InvalidCode(5000)),
+            (65_535, CloseCode::InvalidCode(65_535)),
+        ];
+        for &(code, expected) in cases.iter() {
+            assert_eq!(CloseCode::from(code), expected);
+            assert_eq!(code, Into::<u16>::into(CloseCode::from(code)));
+        }
+    }
+
+    #[test]
+    fn closecode_private() {
+        for code in 4000u16..5000 {
+            assert_eq!(CloseCode::from(code), CloseCode::Private(code));
+            assert_eq!(CloseCode::from_wire(code), CloseCode::Private(code));
+            assert_eq!(code, Into::<u16>::into(CloseCode::Private(code)));
+        }
+    }
+
+    #[test]
+    fn closecode_invalid() {
+        for code in [1u16, 999, 1004, 1016, 2999, 5000, 65_535].iter() {
+            assert_eq!(CloseCode::from(*code), CloseCode::InvalidCode(*code));
+            assert_eq!(*code, Into::<u16>::into(CloseCode::from(*code)));
+        }
+    }
+
+    #[test]
+    fn closecode_from_wire() {
+        assert_eq!(CloseCode::from_wire(1000), CloseCode::Normal);
+        assert_eq!(CloseCode::from_wire(1014), CloseCode::BadGateway);
+        assert_eq!(CloseCode::from_wire(1005), CloseCode::Reserved(1005));
+        assert_eq!(CloseCode::from_wire(1006), CloseCode::Reserved(1006));
+        assert_eq!(CloseCode::from_wire(1015), CloseCode::Reserved(1015));
+        assert_eq!(CloseCode::from_wire(4000), CloseCode::Private(4000));
+    }
+
+    #[test]
+    fn test_is_valid_close_code() {
+        assert!(is_valid_close_code(1000));
+        assert!(is_valid_close_code(1003));
+        assert!(is_valid_close_code(1007));
+        assert!(is_valid_close_code(1011));
+        assert!(is_valid_close_code(3000));
+        assert!(is_valid_close_code(4999));
+        assert!(!is_valid_close_code(0));
+        assert!(!is_valid_close_code(1004));
+        assert!(!is_valid_close_code(1005));
+        assert!(!is_valid_close_code(1006));
+        assert!(!is_valid_close_code(1015));
+        assert!(!is_valid_close_code(2000));
+        assert!(!is_valid_close_code(5000));
+    }
+
+    #[test]
+    fn test_protocol_error_close_code() {
+        assert_eq!(ProtocolError::UnmaskedClientFrame.close_code(), CloseCode::Protocol);
+        assert_eq!(ProtocolError::UnexpectedContinuation.close_code(), CloseCode::Protocol);
+        assert_eq!(ProtocolError::CompressedControlFrame.close_code(), CloseCode::Protocol);
+        assert_eq!(ProtocolError::InvalidClosePayload.close_code(), CloseCode::Protocol);
+        assert_eq!(ProtocolError::InvalidUtf8.close_code(), CloseCode::Invalid);
+        assert_eq!(ProtocolError::InvalidCompressedData.close_code(), CloseCode::Invalid);
+        assert_eq!(ProtocolError::FrameTooLarge.close_code(), CloseCode::Size);
+        assert_eq!(ProtocolError::MessageTooLarge.close_code(), CloseCode::Size);
+        assert_eq!(ProtocolError::TooManyFragments.close_code(), CloseCode::Size);
+        assert_eq!(ProtocolError::Internal.close_code(), CloseCode::Error);
+    }
+
+    #[test]
+    fn test_protocol_error_from_codec() {
+        assert_eq!(ProtocolError::from(CodecError::MaskedServerFrame),
+                   ProtocolError::MaskedServerFrame);
+        assert_eq!(ProtocolError::from(CodecError::FrameTooLarge), ProtocolError::FrameTooLarge);
+        assert_eq!(ProtocolError::from(CodecError::BufferTooSmall), ProtocolError::Internal);
+    }
+
+    #[test]
+    fn test_hash_key() {
+        // RFC 6455, section 1.3
+        assert_eq!(hash_key(b"dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_verify_key() {
+        assert!(verify_key(b"dGhlIHNhbXBsZSBub25jZQ=="));
+        assert!(!verify_key(b""));
+        assert!(!verify_key(b"13"));
+        assert!(!verify_key(b"not base64!"));
+        // 15 and 17 bytes
+        assert!(!verify_key(b"dGhlIHNhbXBsZSBub25j"));
+        assert!(!verify_key(b"dGhlIHNhbXBsZSBub25jZSE="));
     }
 }
@@ -0,0 +1,50 @@
+//! Websocket helpers for testing.
+
+use actix::{Actor, ActorContext, Handler};
+
+use error::Error;
+use httprequest::HttpRequest;
+use httpresponse::HttpResponse;
+use ws::{self, CloseCode, Message, WebsocketContext};
+
+/// Echo handler.
+///
+/// Does websocket handshake and echoes reassembled messages back with
+/// the same opcode, replies to pings and to the peer's Close frame.
+///
+/// ```rust
+/// # extern crate actix_web;
+/// # use actix_web::*;
+/// #
+/// # fn main() {
+/// let srv = test::TestServer::new(|app| app.handler(ws::test::echo));
+/// # }
+/// ```
+pub fn echo(req: HttpRequest) -> Result<HttpResponse, Error> {
+    ws::start(req, Echo)
+}
+
+/// Actor started by `echo()`
+pub struct Echo;
+
+impl Actor for Echo {
+    type Context = WebsocketContext<Self>;
+}
+
+impl Handler<Message> for Echo {
+    type Result = ();
+
+    fn handle(&mut self, msg: Message, ctx: &mut Self::Context) {
+        match msg {
+            Message::Ping(msg) => ctx.pong(&msg),
+            Message::Text(text) => ctx.text(text),
+            Message::Binary(bin) => ctx.binary(bin),
+            Message::Close(close) => match close.code {
+                CloseCode::Empty | CloseCode::Abnormal => ctx.close(None),
+                code => ctx.close(Some((code, close.reason.as_str()))),
+            },
+            Message::Closed | Message::Error => ctx.stop(),
+            Message::Pong(_) => (),
+        }
+    }
+}
@@ -4,6 +4,9 @@ extern crate futures;
 extern crate http;
 extern crate bytes;
 
+use std::{net, thread, time};
+use std::io::{Read, Write};
+use std::sync::mpsc;
 use bytes::Bytes;
 use futures::Stream;
 
@@ -47,7 +50,110 @@ fn test_simple() {
     let (item, reader) = srv.execute(reader.into_future()).unwrap();
     assert_eq!(item, Some(ws::Message::Pong("ping".to_owned())));
 
+    // server echoes close frame
     writer.close(ws::CloseCode::Normal, "");
+    let (item, reader) = srv.execute(reader.into_future()).unwrap();
+    assert_eq!(item, Some(ws::Message::Closed));
+    assert_eq!(reader.close_code(), Some(ws::CloseCode::Normal));
+    let (item, _) = srv.execute(reader.into_future()).unwrap();
+    assert!(item.is_none())
+}
+
+#[test]
+fn test_client_protocols() {
+    let mut srv = test::TestServer::new(
+        |app| app.handler(|req| ws::start_with_protocols(req, Ws, &["v1.echo"])));
+    let fut = ws::WsClient::new(srv.url("/"))
+        .protocols(vec!["v2.echo", "v1.echo"])
+        .connect().unwrap();
+    let (reader, mut writer) = srv.execute(fut).unwrap();
+
+    writer.text("text");
+    let (item, reader) = srv.execute(reader.into_future()).unwrap();
+    assert_eq!(item, Some(ws::Message::Text("text".to_owned())));
+
+    writer.close(ws::CloseCode::Away, "");
+    let (item, reader) = srv.execute(reader.into_future()).unwrap();
+    assert_eq!(item, Some(ws::Message::Closed));
+    assert_eq!(reader.close_code(), Some(ws::CloseCode::Away));
     let (item, _) = srv.execute(reader.into_future()).unwrap();
     assert!(item.is_none())
 }
+
+#[test]
+fn test_handshake_timeout() {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let sys = System::new("test");
+        let srv = HttpServer::new(
+            || vec![Application::new().resource("/", |r| r.f(|req| ws::start(req, Ws)))])
+            .client_timeout(200);
+        let srv = srv.bind("127.0.0.1:0").unwrap();
+        let _ = tx.send(srv.addrs()[0]);
+        srv.start();
+        sys.run();
+    });
+    let addr = rx.recv().unwrap();
+
+    // slow client never finishes upgrade request headers
+    let mut stream = net::TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: upgrade\r\n")
+        .unwrap();
+
+    let mut data = Vec::new();
+    let _ = stream.read_to_end(&mut data);
+    assert!(data.starts_with(b"HTTP/1.1 408"));
+}
+
+#[test]
+#[cfg(feature = "test")]
+fn test_echo() {
+    let srv = test::TestServer::new(|app| app.handler(ws::test::echo));
+
+    let mut stream = net::TcpStream::connect(srv.addr()).unwrap();
+    stream.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: upgrade\r\n\
+                       Sec-WebSocket-Version: 13\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+
+    // fragmented text message with ping in between fragments
+    let mut fragments = ws::FrameFragmenter::client("hello world", ws::OpCode::Text, 4);
+    stream.write_all(fragments.next().unwrap().as_ref()).unwrap();
+    stream.write_all(ws::Frame::ping("hi", true).unwrap().as_ref()).unwrap();
+    for fragment in fragments {
+        stream.write_all(fragment.as_ref()).unwrap();
+    }
+    stream.write_all(ws::Frame::close(ws::CloseCode::Away, "bye", true).as_ref()).unwrap();
+
+    // pong, reassembled message and close frame with the same status code
+    let mut data = Vec::new();
+    let _ = stream.read_to_end(&mut data);
+    assert!(data.starts_with(b"HTTP/1.1 101"));
+    assert!(data.ends_with(b"\x8a\x02hi\x81\x0bhello world\x88\x05\x03\xe9bye"));
+}
+
+#[test]
+fn test_close_half_closed_peer() {
+    let srv = test::TestServer::new(|app| app.handler(|req| ws::start(req, Ws)));
+
+    let mut stream = net::TcpStream::connect(srv.addr()).unwrap();
+    stream.set_read_timeout(Some(time::Duration::from_secs(5))).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: upgrade\r\n\
+                       Sec-WebSocket-Version: 13\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n").unwrap();
+
+    // masked text frame and close frame, then peer stops sending
+    stream.write_all(b"\x81\x82\x00\x00\x00\x00hi\x88\x82\x00\x00\x00\x00\x03\xe8").unwrap();
+    stream.shutdown(net::Shutdown::Write).unwrap();
+
+    // echo and close reply are still written
+    let mut data = Vec::new();
+    let _ = stream.read_to_end(&mut data);
+    assert!(data.starts_with(b"HTTP/1.1 101"));
+    assert!(data.ends_with(b"\x81\x02hi\x88\x02\x03\xe8"));
+}